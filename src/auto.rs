@@ -0,0 +1,249 @@
+//! Fan speed from a temperature. Readings map to a speed through bands with
+//! hysteresis, so a temperature sitting on a threshold doesn't flap the fan
+//! between speeds, and a command is only sent when the speed changes.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str;
+
+use fan::FanState21;
+use mqtt::Subscriber;
+
+/// Where a temperature comes from
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TempSource {
+    /// A sysfs file in millidegrees, e.g. `/sys/class/hwmon/hwmon0/temp1_input`
+    Hwmon(PathBuf),
+    /// A 1-wire sensor's `w1_slave` file
+    W1(PathBuf),
+    /// Degrees as the payload of messages on an MQTT topic
+    Mqtt { broker: String, topic: String },
+    /// The radio's on-chip sensor
+    Chip,
+}
+
+/// Temperatures in °C at which each speed starts. A speed is kept until the
+/// temperature falls `hysteresis` below where it starts.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Bands {
+    pub low: f64,
+    pub medium: f64,
+    pub high: f64,
+    #[serde(default = "default_hysteresis")]
+    pub hysteresis: f64,
+}
+
+fn default_hysteresis() -> f64 {
+    1.0
+}
+
+/// Speeds from slowest to fastest
+const SPEEDS: [FanState21; 4] = [FanState21::Off,
+                                 FanState21::Low,
+                                 FanState21::Med,
+                                 FanState21::High];
+
+impl Bands {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.low <= self.medium && self.medium <= self.high) {
+            Err("Bands must start in order of low, medium, high".to_string())
+        } else if self.hysteresis.is_nan() || self.hysteresis < 0.0 {
+            Err("Hysteresis can't be negative".to_string())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Index into `SPEEDS` of the band `temp` is in, ignoring hysteresis
+    fn band(&self, temp: f64) -> usize {
+        [self.low, self.medium, self.high].iter().filter(|&&start| temp >= start).count()
+    }
+
+    /// Speed for `temp` when the fan is running at `current`. An unknown
+    /// speed goes straight to the band.
+    pub fn speed(&self, temp: f64, current: Option<FanState21>) -> FanState21 {
+        let band = self.band(temp);
+        match current.and_then(|speed| SPEEDS.iter().position(|&s| s == speed)) {
+            Some(now) if band < now => SPEEDS[self.band(temp + self.hysteresis).min(now)],
+            _ => SPEEDS[band],
+        }
+    }
+}
+
+/// Drives a fan's speed from a temperature
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Automation {
+    /// Name or address of the fan
+    pub fan: String,
+    /// Send smart commands, leaving the light as it is, instead of dumb ones
+    #[serde(default)]
+    pub smart: bool,
+    pub source: TempSource,
+    pub bands: Bands,
+    /// How often to read the temperature, e.g. `30s`
+    #[serde(default = "default_interval")]
+    pub interval: String,
+}
+
+fn default_interval() -> String {
+    "30s".to_string()
+}
+
+/// Follows the temperature for one automation and decides when the fan
+/// needs a command
+pub struct Controller {
+    bands: Bands,
+    speed: Option<FanState21>,
+}
+
+impl Controller {
+    /// Starts from the fan's known speed, if any. Otherwise the first
+    /// reading always sets the speed.
+    pub fn new(bands: Bands, speed: Option<FanState21>) -> Self {
+        Controller { bands, speed }
+    }
+
+    /// Takes a reading and returns the speed to change to, if it changes
+    pub fn update(&mut self, temp: f64) -> Option<FanState21> {
+        let speed = self.bands.speed(temp, self.speed);
+        if self.speed == Some(speed) {
+            return None;
+        }
+        self.speed = Some(speed);
+        Some(speed)
+    }
+}
+
+/// Parses a hwmon style reading in millidegrees
+pub fn parse_hwmon(text: &str) -> Option<f64> {
+    text.trim().parse::<f64>().ok().map(|millis| millis / 1000.0)
+}
+
+/// Parses a 1-wire `w1_slave` file. The first line ends in `YES` if the
+/// reading passed its CRC and the second ends in `t=` and millidegrees.
+pub fn parse_w1(text: &str) -> Option<f64> {
+    let mut lines = text.lines();
+    if !lines.next()?.trim_end().ends_with("YES") {
+        return None;
+    }
+    let line = lines.next()?;
+    parse_hwmon(&line[line.find("t=")? + 2..])
+}
+
+fn read_file(path: &Path, parse: fn(&str) -> Option<f64>) -> io::Result<f64> {
+    parse(&fs::read_to_string(path)?).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData,
+                       format!("No temperature in {}", path.display()))
+    })
+}
+
+/// An open temperature source
+pub enum Sensor {
+    Hwmon(PathBuf),
+    W1(PathBuf),
+    Mqtt {
+        sub: Subscriber,
+        last: Option<f64>,
+    },
+    Chip,
+}
+
+impl Sensor {
+    /// Opens a source. MQTT connects as `client_id`.
+    pub fn open(source: &TempSource, client_id: &str) -> io::Result<Self> {
+        Ok(match *source {
+            TempSource::Hwmon(ref path) => Sensor::Hwmon(path.clone()),
+            TempSource::W1(ref path) => Sensor::W1(path.clone()),
+            TempSource::Mqtt { ref broker, ref topic } => {
+                Sensor::Mqtt {
+                    sub: Subscriber::connect(broker, client_id, topic)?,
+                    last: None,
+                }
+            }
+            TempSource::Chip => Sensor::Chip,
+        })
+    }
+
+    /// Reads the temperature in °C, using `chip` for the radio's sensor.
+    /// MQTT gives the latest message, or `None` until one arrives. Messages
+    /// that aren't a number are skipped.
+    pub fn read<F>(&mut self, chip: F) -> io::Result<Option<f64>>
+        where F: FnOnce() -> io::Result<f64>
+    {
+        match *self {
+            Sensor::Hwmon(ref path) => read_file(path, parse_hwmon).map(Some),
+            Sensor::W1(ref path) => read_file(path, parse_w1).map(Some),
+            Sensor::Mqtt { ref mut sub, ref mut last } => {
+                for (topic, payload) in sub.poll()? {
+                    match str::from_utf8(&payload).ok().and_then(|text| text.trim().parse().ok()) {
+                        Some(temp) => *last = Some(temp),
+                        None => warn!("No temperature in message on {}", topic),
+                    }
+                }
+                Ok(*last)
+            }
+            Sensor::Chip => chip().map(Some),
+        }
+    }
+}
+
+#[test]
+fn auto_bands() {
+    let bands = Bands {
+        low: 24.0,
+        medium: 27.0,
+        high: 30.0,
+        hysteresis: 1.0,
+    };
+    assert!(bands.validate().is_ok());
+    assert!(Bands { high: 26.0, ..bands.clone() }.validate().is_err());
+
+    let mut control = Controller::new(bands, None);
+    let speeds = [20.0, 23.5, 24.0, 23.5, 27.5, 26.5, 25.9, 31.0, 23.0, 22.9]
+        .iter()
+        .map(|&temp| control.update(temp))
+        .collect::<Vec<_>>();
+    assert_eq!(vec![Some(FanState21::Off),
+                    None,
+                    Some(FanState21::Low),
+                    None,
+                    Some(FanState21::Med),
+                    None,
+                    Some(FanState21::Low),
+                    Some(FanState21::High),
+                    Some(FanState21::Low),
+                    Some(FanState21::Off)],
+               speeds);
+}
+
+#[test]
+fn auto_sensors() {
+    use std::env;
+
+    let dir = env::temp_dir().join(format!("fanrf-sensors-{}", ::std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let hwmon = dir.join("temp1_input");
+    fs::write(&hwmon, "23500\n").unwrap();
+    let w1 = dir.join("w1_slave");
+    fs::write(&w1,
+              "72 01 4b 46 7f ff 0e 10 57 : crc=57 YES\n72 01 4b 46 7f ff 0e 10 57 t=23125\n")
+        .unwrap();
+
+    let no_chip = || -> io::Result<f64> { panic!("Not the chip") };
+    let mut sensor = Sensor::open(&TempSource::Hwmon(hwmon.clone()), "test").unwrap();
+    assert_eq!(Some(23.5), sensor.read(no_chip).unwrap());
+    fs::write(&hwmon, "-1250\n").unwrap();
+    assert_eq!(Some(-1.25), sensor.read(no_chip).unwrap());
+    let mut sensor = Sensor::open(&TempSource::W1(w1.clone()), "test").unwrap();
+    assert_eq!(Some(23.125), sensor.read(no_chip).unwrap());
+    let mut sensor = Sensor::open(&TempSource::Chip, "test").unwrap();
+    assert_eq!(Some(25.0), sensor.read(|| Ok(25.0)).unwrap());
+
+    // Failed CRC
+    fs::write(&w1, "72 01 4b 46 7f ff 0e 10 57 : crc=57 NO\n72 01 t=23125\n").unwrap();
+    let mut sensor = Sensor::open(&TempSource::W1(w1), "test").unwrap();
+    assert_eq!(io::ErrorKind::InvalidData, sensor.read(no_chip).unwrap_err().kind());
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,298 @@
+//! Register bus through a microcontroller bridge on a serial port. The wire
+//! protocol and what the firmware has to do are described in
+//! `serial-bridge-protocol.md`.
+
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+use std::time::Duration;
+
+use serial::{self, SerialPort};
+
+use gpio::{IrqLine, Lines, ShutdownLine};
+use regrw::RegRw;
+use remote::{CAP_IRQ, CAP_SHUTDOWN, OP_BURST_READ, OP_BURST_WRITE, OP_CAPS, OP_IRQ_ASSERTED,
+             OP_READ, OP_SHUTDOWN_GET, OP_SHUTDOWN_SET, OP_WRITE};
+
+/// First byte of every frame
+pub const SYNC: u8 = 0xa5;
+/// Response kinds. A request's kind is its op.
+pub const STATUS_OK: u8 = 0x00;
+pub const STATUS_ERR: u8 = 0x01;
+/// Sent by the bridge, unsolicited, when nIRQ falls
+pub const EVT_IRQ: u8 = 0x80;
+
+const BAUD_RATE: serial::BaudRate = serial::Baud115200;
+/// How long to wait for the bridge to answer a request
+const RESPONSE_TIMEOUT_MS: u64 = 1000;
+
+/// Checksum byte that makes everything after the sync byte sum to zero
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)).wrapping_neg()
+}
+
+pub fn write_frame<W: Write>(w: &mut W, kind: u8, arg: u8, payload: &[u8]) -> io::Result<()> {
+    if payload.len() > 0xffff {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Payload too long"));
+    }
+    let len = payload.len() as u16;
+    let mut frame = Vec::with_capacity(payload.len() + 6);
+    frame.extend_from_slice(&[SYNC, kind, arg, (len >> 8) as u8, len as u8]);
+    frame.extend_from_slice(payload);
+    let check = checksum(&frame[1..]);
+    frame.push(check);
+    w.write_all(&frame)?;
+    w.flush()
+}
+
+/// Reads the next valid frame as `(kind, arg, payload)`. Bytes before a sync
+/// byte and frames with a bad checksum are dropped.
+pub fn read_frame<R: Read>(r: &mut R) -> io::Result<(u8, u8, Vec<u8>)> {
+    loop {
+        let mut byte = [0u8; 1];
+        r.read_exact(&mut byte)?;
+        if byte[0] != SYNC {
+            continue;
+        }
+        let mut header = [0u8; 4];
+        r.read_exact(&mut header)?;
+        let len = (header[2] as usize) << 8 | header[3] as usize;
+        let mut rest = vec![0; len + 1];
+        r.read_exact(&mut rest)?;
+        let check = rest.pop().unwrap();
+        if checksum(&header).wrapping_add(checksum(&rest)) != check {
+            warn!("Dropping serial frame with bad checksum");
+            continue;
+        }
+        return Ok((header[0], header[1], rest));
+    }
+}
+
+struct SerialConn<P: SerialPort> {
+    port: P,
+    /// An IRQ event arrived while waiting for a response
+    irq_pending: bool,
+}
+
+impl<P: SerialPort> SerialConn<P> {
+    /// Next frame that isn't an IRQ event
+    fn response(&mut self, op: u8) -> io::Result<Vec<u8>> {
+        loop {
+            let (kind, arg, payload) = read_frame(&mut self.port)?;
+            match kind {
+                EVT_IRQ => self.irq_pending = true,
+                STATUS_OK if arg == op => return Ok(payload),
+                STATUS_ERR if arg == op => {
+                    return Err(io::Error::other(String::from_utf8_lossy(&payload).into_owned()))
+                }
+                _ => warn!("Unexpected serial frame kind 0x{:02x} for op 0x{:02x}", kind, arg),
+            }
+        }
+    }
+
+    fn request(&mut self, op: u8, reg: u8, payload: &[u8]) -> io::Result<Vec<u8>> {
+        write_frame(&mut self.port, op, reg, payload)?;
+        self.response(op)
+    }
+
+    fn request_flag(&mut self, op: u8, reg: u8) -> io::Result<bool> {
+        self.request(op, reg, &[]).map(|resp| resp.first() == Some(&1))
+    }
+
+    fn wait_irq(&mut self, timeout_ms: isize) -> io::Result<bool> {
+        if self.irq_pending {
+            self.irq_pending = false;
+            return Ok(true);
+        }
+        // Negative means no timeout, like poll()
+        let timeout = if timeout_ms < 0 {
+            Duration::from_secs(3600)
+        } else {
+            Duration::from_millis(timeout_ms as u64)
+        };
+        self.port.set_timeout(timeout)?;
+        let ret = loop {
+            match read_frame(&mut self.port) {
+                Ok((EVT_IRQ, _, _)) => break Ok(true),
+                Ok((kind, arg, _)) => {
+                    warn!("Unexpected serial frame kind 0x{:02x} for op 0x{:02x}", kind, arg)
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut && timeout_ms >= 0 => {
+                    break Ok(false)
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut => (),
+                Err(e) => break Err(e),
+            }
+        };
+        self.port.set_timeout(Duration::from_millis(RESPONSE_TIMEOUT_MS))?;
+        ret
+    }
+}
+
+/// Client side of the serial bridge protocol
+pub struct SerialRegs<P: SerialPort>(Rc<RefCell<SerialConn<P>>>);
+
+impl<P: SerialPort + 'static> SerialRegs<P> {
+    pub fn new(mut port: P) -> io::Result<Self> {
+        port.reconfigure(&|settings| {
+                settings.set_baud_rate(BAUD_RATE)?;
+                settings.set_char_size(serial::Bits8);
+                settings.set_parity(serial::ParityNone);
+                settings.set_stop_bits(serial::Stop1);
+                settings.set_flow_control(serial::FlowNone);
+                Ok(())
+            })?;
+        port.set_timeout(Duration::from_millis(RESPONSE_TIMEOUT_MS))?;
+        Ok(SerialRegs(Rc::new(RefCell::new(SerialConn {
+            port,
+            irq_pending: false,
+        }))))
+    }
+
+    /// Handles for whichever of the IRQ and shutdown lines the bridge has
+    pub fn lines(&self) -> io::Result<Lines> {
+        let caps = self.0.borrow_mut().request(OP_CAPS, 0, &[])?.first().cloned().unwrap_or(0);
+        let irq = if caps & CAP_IRQ != 0 {
+            Some(Box::new(SerialIrq(self.0.clone())) as Box<dyn IrqLine>)
+        } else {
+            None
+        };
+        let shutdown = if caps & CAP_SHUTDOWN != 0 {
+            Some(Box::new(SerialShutdown(self.0.clone())) as Box<dyn ShutdownLine>)
+        } else {
+            None
+        };
+        Ok((irq, shutdown))
+    }
+}
+
+impl SerialRegs<serial::SystemPort> {
+    pub fn open(path: &str) -> io::Result<Self> {
+        Self::new(serial::open(path)?)
+    }
+}
+
+impl<P: SerialPort> RegRw for SerialRegs<P> {
+    fn read(&mut self, reg: u8) -> io::Result<u8> {
+        let resp = self.0.borrow_mut().request(OP_READ, reg, &[])?;
+        resp.first()
+            .cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Empty read response"))
+    }
+
+    fn write(&mut self, reg: u8, val: u8) -> io::Result<()> {
+        self.0.borrow_mut().request(OP_WRITE, reg, &[val]).map(|_| ())
+    }
+
+    fn burst_write(&mut self, reg: u8, val: &[u8]) -> io::Result<()> {
+        self.0.borrow_mut().request(OP_BURST_WRITE, reg, val).map(|_| ())
+    }
+
+    fn burst_read(&mut self, reg: u8, buf: &mut [u8]) -> io::Result<()> {
+        let len = buf.len() as u16;
+        let resp = self.0
+            .borrow_mut()
+            .request(OP_BURST_READ, reg, &[(len >> 8) as u8, len as u8])?;
+        if resp.len() != buf.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Short burst read"));
+        }
+        buf.copy_from_slice(&resp);
+        Ok(())
+    }
+}
+
+struct SerialShutdown<P: SerialPort>(Rc<RefCell<SerialConn<P>>>);
+
+impl<P: SerialPort> ShutdownLine for SerialShutdown<P> {
+    fn in_shutdown(&mut self) -> io::Result<bool> {
+        self.0.borrow_mut().request_flag(OP_SHUTDOWN_GET, 0)
+    }
+
+    fn set_shutdown(&mut self, shutdown: bool) -> io::Result<()> {
+        self.0.borrow_mut().request(OP_SHUTDOWN_SET, shutdown as u8, &[]).map(|_| ())
+    }
+}
+
+struct SerialIrq<P: SerialPort>(Rc<RefCell<SerialConn<P>>>);
+
+impl<P: SerialPort> IrqLine for SerialIrq<P> {
+    fn asserted(&mut self) -> io::Result<bool> {
+        self.0.borrow_mut().request_flag(OP_IRQ_ASSERTED, 0)
+    }
+
+    fn wait(&mut self, timeout_ms: isize) -> io::Result<bool> {
+        self.0.borrow_mut().wait_irq(timeout_ms)
+    }
+}
+
+/// Opens a pseudo terminal and returns the master end and the slave's path
+#[cfg(test)]
+fn open_pty() -> (::std::fs::File, String) {
+    use std::ffi::CStr;
+    use std::os::unix::io::FromRawFd;
+
+    use libc;
+
+    unsafe {
+        let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+        assert!(fd >= 0);
+        assert_eq!(0, libc::grantpt(fd));
+        assert_eq!(0, libc::unlockpt(fd));
+        let mut name = [0 as libc::c_char; 64];
+        assert_eq!(0, libc::ptsname_r(fd, name.as_mut_ptr(), name.len()));
+        let path = CStr::from_ptr(name.as_ptr()).to_string_lossy().into_owned();
+        (::std::fs::File::from_raw_fd(fd), path)
+    }
+}
+
+/// Host-side stand-in for the bridge firmware. Writes `prelude` to the port
+/// first, then serves requests until the port closes.
+#[cfg(test)]
+fn emulate_bridge<S: Read + Write>(mut port: S, prelude: &[u8]) {
+    use regrw::EmuRegs;
+    use remote::RegServer;
+
+    let mut server = RegServer::new(Box::new(EmuRegs::new()), None, None);
+    port.write_all(prelude).unwrap();
+    while let Ok((op, reg, payload)) = read_frame(&mut port) {
+        let ret = match server.handle(op, reg, &payload) {
+            Ok(resp) => write_frame(&mut port, STATUS_OK, op, &resp),
+            Err(e) => write_frame(&mut port, STATUS_ERR, op, e.to_string().as_bytes()),
+        };
+        if ret.is_err() {
+            break;
+        }
+    }
+}
+
+#[test]
+fn serial_transmit() {
+    use std::thread;
+
+    use rfm::Rfm22;
+    use rfmconfig::Rfm22Config;
+
+    let (master, path) = open_pty();
+    // Line noise, a corrupted frame and an IRQ event ahead of the first response
+    let mut prelude = vec![0x00, 0x13, SYNC, OP_READ, 0x00, 0x00, 0x00, 0x00];
+    write_frame(&mut prelude, EVT_IRQ, 0, &[]).unwrap();
+    let bridge = thread::spawn(move || emulate_bridge(master, &prelude));
+
+    let mut regs = SerialRegs::open(&path).unwrap();
+    regs.write(0x10, 0xaa).unwrap();
+    assert_eq!(0xaa, regs.read(0x10).unwrap());
+    {
+        let mut irq = SerialIrq(regs.0.clone());
+        assert!(irq.wait(0).unwrap());
+        assert!(!irq.wait(0).unwrap());
+    }
+
+    let (irq, shutdown) = regs.lines().unwrap();
+    assert!(irq.is_none() && shutdown.is_none());
+    {
+        let mut rf = Rfm22::from_regrw(Box::new(regs), irq, shutdown);
+        rf.configure(Rfm22Config::default()).unwrap();
+        rf.transmit_bitstream(vec![true, false, true]).unwrap();
+    }
+    bridge.join().unwrap();
+}
@@ -0,0 +1,202 @@
+//! Measures OOK PWM captures against what we would send, for working out why
+//! a fan ignores us.
+//!
+//! Both waveforms are reduced to pulses. Each pulse is one bit: short for 0
+//! and long for 1, followed by silence until the end of the bit period.
+//! Silences longer than a bit separate frames.
+
+use std::io::{self, Write};
+
+use fan::FanPkt;
+use rtl433::decode_row;
+
+/// Silences longer than this many bit periods end a frame
+const FRAME_GAP_MIN_PERIODS: f64 = 1.5;
+
+/// Timing of a waveform, averaged over all its frames
+#[derive(Clone, Debug, PartialEq)]
+pub struct Measurement {
+    pub period_us: f64,
+    pub short_us: f64,
+    pub long_us: f64,
+    /// Silence between frames, if there is more than one
+    pub gap_us: Option<f64>,
+    /// Bits of each frame, including the start bit
+    pub frames: Vec<Vec<bool>>,
+}
+
+fn mean(vals: &[i64]) -> Option<f64> {
+    if vals.is_empty() {
+        None
+    } else {
+        Some(vals.iter().sum::<i64>() as f64 / vals.len() as f64)
+    }
+}
+
+impl Measurement {
+    /// Measures durations as returned by `encode::timings`. Fails if there
+    /// aren't both short and long pulses.
+    pub fn new(timings: &[i64]) -> Option<Self> {
+        // (pulse, silence after it)
+        let mut pulses = Vec::new();
+        let mut iter = timings.iter().skip_while(|&&t| t <= 0).peekable();
+        while let Some(&pulse) = iter.next() {
+            let mut silence = 0;
+            while let Some(&&t) = iter.peek() {
+                if t > 0 {
+                    break;
+                }
+                silence -= t;
+                iter.next();
+            }
+            pulses.push((pulse, silence));
+        }
+
+        let min = pulses.iter().map(|p| p.0).min()?;
+        let max = pulses.iter().map(|p| p.0).max()?;
+        // Noise aside, long pulses are at least twice as long as short ones
+        if (max as f64) < min as f64 * 1.5 {
+            return None;
+        }
+        let threshold = (min + max) / 2;
+        let short = pulses.iter().map(|p| p.0).filter(|&p| p < threshold).collect::<Vec<_>>();
+        let long = pulses.iter().map(|p| p.0).filter(|&p| p >= threshold).collect::<Vec<_>>();
+        let short_us = mean(&short)?;
+        let long_us = mean(&long)?;
+        // Silence after a long pulse is usually as long as a short pulse.
+        // Receivers stretch or shrink pulses, so this is only good enough to
+        // find the gaps.
+        let rough_period_us = short_us + long_us;
+
+        let mut frames = vec![Vec::new()];
+        let mut gaps = Vec::new();
+        let mut periods = Vec::new();
+        for (idx, &(pulse, silence)) in pulses.iter().enumerate() {
+            frames.last_mut().unwrap().push(pulse >= threshold);
+            if silence as f64 > rough_period_us * FRAME_GAP_MIN_PERIODS {
+                if idx + 1 < pulses.len() {
+                    gaps.push(silence);
+                    frames.push(Vec::new());
+                }
+            } else if let Some(&(next, _)) = pulses.get(idx + 1) {
+                // Pulses start early for 1 bits but always end at the same
+                // point in the bit, so falling edges are a bit period apart
+                periods.push(silence + next);
+            }
+        }
+        let period_us = mean(&periods).unwrap_or(rough_period_us);
+        Some(Measurement {
+            period_us,
+            short_us,
+            long_us,
+            gap_us: mean(&gaps),
+            frames,
+        })
+    }
+
+    /// The command most frames decode to
+    pub fn decode(&self) -> Option<FanPkt> {
+        let mut counts: Vec<(FanPkt, usize)> = Vec::new();
+        for pkt in self.frames.iter().filter_map(|bits| decode_row(bits)) {
+            match counts.iter().position(|c| c.0 == pkt) {
+                Some(idx) => counts[idx].1 += 1,
+                None => counts.push((pkt, 1)),
+            }
+        }
+        counts.into_iter().max_by_key(|c| c.1).map(|c| c.0)
+    }
+
+    /// Number of bits that differ from `expected` in each frame. Missing or
+    /// extra bits count as errors.
+    pub fn bit_errors(&self, expected: &[bool]) -> Vec<usize> {
+        self.frames
+            .iter()
+            .map(|bits| {
+                let diff = bits.iter().zip(expected).filter(|&(a, b)| a != b).count();
+                diff + bits.len().max(expected.len()) - bits.len().min(expected.len())
+            })
+            .collect()
+    }
+}
+
+fn row<W: Write>(w: &mut W,
+                 name: &str,
+                 capture: Option<f64>,
+                 expected: Option<f64>)
+                 -> io::Result<()> {
+    let fmt = |v: Option<f64>| v.map_or("-".to_string(), |v| format!("{:.0}", v));
+    let diff = match (capture, expected) {
+        (Some(c), Some(e)) if e != 0.0 => format!("{:+.1}%", (c - e) / e * 100.0),
+        _ => String::new(),
+    };
+    writeln!(w, "{:<14}{:>10}{:>10}{:>10}", name, fmt(capture), fmt(expected), diff)
+}
+
+/// Prints the capture's timing next to the expected timing and the bit
+/// errors of each frame. Returns the total bit errors.
+pub fn report<W: Write>(w: &mut W,
+                        capture: &Measurement,
+                        expected: &Measurement,
+                        pkt: &FanPkt)
+                        -> io::Result<usize> {
+    writeln!(w, "Comparing with {}", pkt)?;
+    writeln!(w, "{:<14}{:>10}{:>10}", "", "capture", "expected")?;
+    row(w, "period (us)", Some(capture.period_us), Some(expected.period_us))?;
+    row(w, "short (us)", Some(capture.short_us), Some(expected.short_us))?;
+    row(w, "long (us)", Some(capture.long_us), Some(expected.long_us))?;
+    row(w, "gap (us)", capture.gap_us, expected.gap_us)?;
+    row(w,
+        "frames",
+        Some(capture.frames.len() as f64),
+        Some(expected.frames.len() as f64))?;
+
+    let mut frame = vec![false];
+    frame.extend(pkt.bits());
+    let errors = capture.bit_errors(&frame);
+    let total = errors.iter().sum();
+    let bad = errors.iter().filter(|&&e| e > 0).count();
+    writeln!(w,
+             "bit errors: {} in {} of {} frames",
+             total,
+             bad,
+             capture.frames.len())?;
+    for (idx, &count) in errors.iter().enumerate().filter(|e| *e.1 > 0) {
+        let bits = capture.frames[idx]
+            .iter()
+            .map(|&b| if b { '1' } else { '0' })
+            .collect::<String>();
+        writeln!(w, "  frame {}: {} ({} errors)", idx + 1, bits, count)?;
+    }
+    Ok(total)
+}
+
+#[test]
+fn compare_capture() {
+    use encode::timings;
+    use fan::{FanPkt21, FanState21};
+    use pwm::PwmSymbols;
+
+    let pkt = FanPkt::Smart(FanPkt21::new(5, 0.5, FanState21::Low));
+    let stream = pkt.repeated(&PwmSymbols::FAN, pkt.repeats());
+    let expected = Measurement::new(&timings(&stream, 1000000.0 / 3000.0)).unwrap();
+    assert_eq!(30, expected.frames.len());
+    assert!((expected.period_us - 1000.0).abs() < 1.0);
+    assert!((expected.gap_us.unwrap() - 34.0 * 333.3).abs() < 500.0);
+
+    // A remote that's 5% slow, with pulses stretched by the receiver, that
+    // was heard for 29 frames. The first bit of the first frame is lost.
+    let mut capture = timings(&pkt.frame(&PwmSymbols::FAN).repeat(29), 1000000.0 / 2850.0)
+        .iter()
+        .map(|&t| t + 40)
+        .collect::<Vec<_>>();
+    capture[3] = capture[1];
+    let measured = Measurement::new(&capture).unwrap();
+    assert_eq!(Some(pkt.clone()), measured.decode());
+    assert!((measured.period_us / expected.period_us - 1.05).abs() < 0.02);
+    assert_eq!(29, measured.frames.len());
+
+    let mut out = Vec::new();
+    assert_eq!(1, report(&mut out, &measured, &expected, &pkt).unwrap());
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.contains("bit errors: 1 in 1 of 29 frames"));
+}
@@ -0,0 +1,313 @@
+//! User settings, kept in a JSON file. Settings given on the command line
+//! take precedence.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde_json;
+
+use auto::Automation;
+use fade::parse_duration;
+use schedule::{Action, Schedule, Task, Trigger};
+use fan::{BrightnessCurve, FanPkt, FanPkt12, FanPkt21, FanState21, Repeat};
+
+/// Settings for the fan at one address
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FanConfig {
+    /// Lets the fan be referred to by name, e.g. in automations
+    pub name: Option<String>,
+    /// Overrides the global `repeat` for this fan
+    pub repeat: Repeat,
+    /// Time for a dumb fan's light to dim from full to its lowest level
+    /// while the Light button is held, in ms
+    pub dim_sweep_ms: Option<f64>,
+    /// How a smart fan's brightness maps to levels
+    pub brightness: BrightnessCurve,
+}
+
+/// A command to put a fan in some state, e.g. for a timer to send
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scene {
+    /// A dumb command by its code
+    Dumb(u8),
+    /// A smart command, with brightness from 0.0 to 1.0 on the fan's curve
+    Smart { fan: FanState21, brightness: f64 },
+}
+
+impl Scene {
+    /// Checks the scene can be sent: dumb codes are 7 bits and smart
+    /// brightness is from 0.0 to 1.0
+    pub fn validate(&self) -> Result<(), String> {
+        match *self {
+            Scene::Dumb(code) if code >= 0x80 => {
+                Err(format!("Dumb command code 0x{:02x} is more than 7 bits", code))
+            }
+            Scene::Smart { brightness, .. } if !(0.0..=1.0).contains(&brightness) => {
+                Err(format!("Smart brightness {} is outside 0.0 to 1.0", brightness))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn from_pkt(pkt: &FanPkt, curve: &BrightnessCurve) -> Self {
+        match *pkt {
+            FanPkt::Dumb(ref pkt) => Scene::Dumb(pkt.code()),
+            FanPkt::Smart(ref pkt) => {
+                Scene::Smart {
+                    fan: pkt.fan(),
+                    brightness: curve.brightness(pkt.level()),
+                }
+            }
+        }
+    }
+
+    pub fn pkt(&self, addr: u8, curve: &BrightnessCurve) -> FanPkt {
+        match *self {
+            Scene::Dumb(code) => FanPkt::Dumb(FanPkt12::from_code(addr, code)),
+            Scene::Smart { fan, brightness } => {
+                FanPkt::Smart(FanPkt21::from_level(addr, curve.level(brightness), fan))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub repeat: Repeat,
+    /// Keyed by fan address
+    pub fans: BTreeMap<u8, FanConfig>,
+    /// Names for dumb command codes beyond the ones `FanCmd12` knows, as
+    /// found with `probe`. Receivers differ, so none are built in.
+    pub commands: BTreeMap<String, u8>,
+    /// Named scenes, e.g. for `timer set --scene`
+    pub scenes: BTreeMap<String, Scene>,
+    pub schedule: Schedule,
+    /// Fan speeds driven by temperature
+    pub automations: Vec<Automation>,
+}
+
+impl Config {
+    /// `$XDG_CONFIG_HOME/fanrf/config.json`, falling back to `~/.config`
+    pub fn default_path() -> PathBuf {
+        env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+            .unwrap_or_else(|| PathBuf::from("."))
+            .join("fanrf/config.json")
+    }
+
+    /// Loads a config. Missing fields take their default.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let config: Config = serde_json::from_reader(File::open(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        config.validate().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(config)
+    }
+
+    /// Checks the settings can be used, so mistakes show up on loading
+    /// rather than when a timer fires
+    pub fn validate(&self) -> Result<(), String> {
+        for (addr, fan) in &self.fans {
+            if *addr > 0xf {
+                return Err(format!("Fan {}: addresses go up to 15", addr));
+            }
+            fan.brightness.validate().map_err(|e| format!("Fan {}: {}", addr, e))?;
+        }
+        for (name, scene) in &self.scenes {
+            scene.validate().map_err(|e| format!("Scene {}: {}", name, e))?;
+        }
+        self.check_schedule()?;
+        Ok(())
+    }
+
+    /// Loads the config at the default path. A missing file is the default
+    /// config.
+    pub fn open_default() -> io::Result<Self> {
+        match Self::from_file(Self::default_path()) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+            ret => ret,
+        }
+    }
+
+    /// Repeat settings for the fan at `addr`
+    pub fn repeat(&self, addr: u8) -> Repeat {
+        match self.fans.get(&addr) {
+            Some(fan) => fan.repeat.or(self.repeat),
+            None => self.repeat,
+        }
+    }
+
+    /// Checks the schedule's entries can run and returns their triggers and
+    /// what each does
+    pub fn check_schedule(&self) -> Result<Vec<(Trigger, Task)>, String> {
+        let tasks = self.schedule
+            .entries
+            .iter()
+            .map(|entry| {
+                let error = |e: String| format!("Schedule entry '{}': {}", entry.name, e);
+                if entry.fan > 0xf {
+                    return Err(error(format!("fan address {} is more than 0xf", entry.fan)));
+                }
+                match entry.action {
+                    Action::Scene(ref name) => {
+                        self.scenes
+                            .get(name)
+                            .map(|scene| Task::Send(scene.clone()))
+                            .ok_or_else(|| error(format!("no scene named {}", name)))
+                    }
+                    Action::Command(ref scene) => {
+                        scene.validate().map_err(error)?;
+                        Ok(Task::Send(scene.clone()))
+                    }
+                    Action::Fade { to, ref over } => {
+                        match parse_duration(over) {
+                            Some(over_ms) if (0.0..=1.0).contains(&to) => {
+                                Ok(Task::Fade { to, over_ms })
+                            }
+                            _ => {
+                                Err(error("fade needs to between 0.0 and 1.0 and a duration \
+                                           like 10m"
+                                    .to_string()))
+                            }
+                        }
+                    }
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(self.schedule.triggers()?.into_iter().zip(tasks).collect())
+    }
+
+    /// Address of a fan given by name, or by its address as a number
+    pub fn fan_address(&self, fan: &str) -> Option<u8> {
+        self.fans
+            .iter()
+            .find(|&(_, config)| config.name.as_ref().is_some_and(|name| name == fan))
+            .map(|(&addr, _)| addr)
+            .or_else(|| fan.parse::<u8>().ok().filter(|&addr| addr <= 0xf))
+    }
+
+    /// Checks the automations can run and returns the address of each fan
+    pub fn check_automations(&self) -> Result<Vec<u8>, String> {
+        self.automations
+            .iter()
+            .map(|auto| {
+                let address = self.fan_address(&auto.fan)
+                    .ok_or_else(|| format!("Automation for {}: no such fan", auto.fan))?;
+                auto.bands.validate().map_err(|e| format!("Automation for {}: {}", auto.fan, e))?;
+                if parse_duration(&auto.interval).is_none_or(|ms| ms <= 0.0) {
+                    return Err(format!("Automation for {}: interval needs a duration like 30s",
+                                       auto.fan));
+                }
+                Ok(address)
+            })
+            .collect()
+    }
+
+    /// Brightness curve of the fan at `addr`
+    pub fn brightness(&self, addr: u8) -> BrightnessCurve {
+        self.fans.get(&addr).map_or(BrightnessCurve::DEFAULT, |fan| fan.brightness)
+    }
+}
+
+#[test]
+fn config_json() {
+    use fan::FanCmd12;
+
+    let config: Config = serde_json::from_str(r#"{
+        "repeat": {"count": 10, "gap_ms": 15},
+        "fans": {"5": {"name": "bedroom", "repeat": {"count": 40},
+                       "brightness": {"min": 8, "gamma": 2.2}}},
+        "commands": {"reverse": 4},
+        "scenes": {"night": {"smart": {"fan": "low", "brightness": 0.1}}, "off": {"dumb": 2}}
+    }"#)
+        .unwrap();
+    assert_eq!(Repeat {
+                   count: Some(40),
+                   gap_ms: Some(15.0),
+                   min_duration_ms: None,
+               },
+               config.repeat(5));
+    assert_eq!(Some(10), config.repeat(2).count);
+    assert_eq!(Some(&4), config.commands.get("reverse"));
+    assert_eq!(BrightnessCurve {
+                   min: 8,
+                   max: 62,
+                   gamma: 2.2,
+               },
+               config.brightness(5));
+    assert_eq!(BrightnessCurve::DEFAULT, config.brightness(2));
+
+    let curve = config.brightness(5);
+    let night = &config.scenes["night"];
+    assert_eq!(FanPkt::Smart(FanPkt21::from_level(5, 23, FanState21::Low)),
+               night.pkt(5, &BrightnessCurve::DEFAULT));
+    assert_eq!(FanPkt::Dumb(FanPkt12::new(5, FanCmd12::FanOff)),
+               config.scenes["off"].pkt(5, &curve));
+    assert_eq!(Ok(()), config.validate());
+    for scene in [r#"{"smart": {"fan": "low", "brightness": 50}}"#, r#"{"dumb": 200}"#].iter() {
+        let mut bad = config.clone();
+        bad.scenes.insert("bad".to_string(), serde_json::from_str(scene).unwrap());
+        assert!(bad.validate().is_err());
+    }
+    let mut attic = config.clone();
+    attic.fans.insert(20, serde_json::from_str(r#"{"name": "attic"}"#).unwrap());
+    assert!(attic.validate().is_err());
+    assert_eq!(Ok(Vec::new()), config.check_schedule());
+    let config = Config {
+        schedule: serde_json::from_str(r#"{"entries": [
+            {"fan": 5, "when": "0 22 * * *", "action": {"scene": "bedtime"}}
+        ]}"#)
+            .unwrap(),
+        ..config
+    };
+    assert!(config.check_schedule().is_err());
+    assert!(config.validate().is_err());
+    // Each would panic building the packet when the entry runs
+    for entry in [r#"{"fan": 20, "when": "0 22 * * *", "action": {"scene": "off"}}"#,
+                  r#"{"fan": 5, "when": "0 22 * * *", "action": {"command": {"dumb": 200}}}"#,
+                  r#"{"fan": 5, "when": "0 22 * * *",
+                      "action": {"command": {"smart": {"fan": "low", "brightness": 50}}}}"#]
+        .iter() {
+        let mut bad = config.clone();
+        bad.schedule.entries = vec![serde_json::from_str(entry).unwrap()];
+        assert!(bad.check_schedule().is_err());
+    }
+    let mut fade = config.clone();
+    fade.schedule.entries = vec![serde_json::from_str(r#"{"fan": 5, "when": "0 22 * * *",
+        "action": {"fade": {"to": 0.2, "over": "10m"}}}"#)
+        .unwrap()];
+    assert_eq!(Task::Fade {
+                   to: 0.2,
+                   over_ms: 600000.0,
+               },
+               fade.check_schedule().unwrap()[0].1);
+
+    assert_eq!(Some(5), config.fan_address("bedroom"));
+    assert_eq!(Some(3), config.fan_address("3"));
+    assert_eq!(None, config.fan_address("kitchen"));
+    let mut config = Config {
+        automations: serde_json::from_str(r#"[
+            {"fan": "bedroom", "source": {"hwmon": "/sys/class/hwmon/hwmon0/temp1_input"},
+             "bands": {"low": 24, "medium": 27, "high": 30}},
+            {"fan": "2", "smart": true, "source": "chip", "interval": "1m",
+             "bands": {"low": 20, "medium": 25, "high": 30, "hysteresis": 2}}
+        ]"#)
+            .unwrap(),
+        ..config
+    };
+    assert_eq!(Ok(vec![5, 2]), config.check_automations());
+    config.automations[1].fan = "kitchen".to_string();
+    assert!(config.check_automations().is_err());
+
+    // Packets survive a round trip through a scene on any curve
+    for level in 0..64 {
+        let pkt = FanPkt::Smart(FanPkt21::from_level(5, level.max(curve.min), FanState21::High));
+        assert_eq!(pkt, Scene::from_pkt(&pkt, &curve).pkt(5, &curve));
+    }
+}
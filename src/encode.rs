@@ -0,0 +1,105 @@
+//! Text renderings of symbol streams, for looking at what would go on air
+
+use rfm::BitsToBytes;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BitFormat {
+    /// One `0`/`1` character per symbol
+    Bits,
+    /// Symbols packed MSB first the way they are loaded into the FIFO
+    Hex,
+    /// Run lengths in microseconds, `+` for carrier on and `-` for off
+    Timing,
+}
+
+/// Run lengths in microseconds, positive for carrier on and negative for off.
+/// Edges are rounded from the start of the stream so rounding doesn't
+/// accumulate.
+pub fn timings(bits: &[bool], symbol_us: f64) -> Vec<i64> {
+    let mut ret = Vec::new();
+    let mut start = 0;
+    while start < bits.len() {
+        let level = bits[start];
+        let end = bits[start..].iter().position(|&b| b != level).map_or(bits.len(), |n| start + n);
+        let us = (end as f64 * symbol_us).round() as i64 - (start as f64 * symbol_us).round() as i64;
+        ret.push(if level { us } else { -us });
+        start = end;
+    }
+    ret
+}
+
+/// Unpacks bytes into symbols, MSB first
+pub fn bytes_to_bits(bytes: &[u8]) -> Vec<bool> {
+    bytes.iter().flat_map(|b| (0..8).rev().map(move |i| b & (1 << i) != 0)).collect()
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ")
+}
+
+pub fn format_bits(bits: &[bool], format: BitFormat, symbol_us: f64) -> String {
+    match format {
+        BitFormat::Bits => bits.iter().map(|&b| if b { '1' } else { '0' }).collect(),
+        BitFormat::Hex => hex(&BitsToBytes(bits.iter().cloned()).collect::<Vec<_>>()),
+        BitFormat::Timing => {
+            timings(bits, symbol_us)
+                .iter()
+                .map(|t| format!("{:+}", t))
+                .collect::<Vec<_>>()
+                .join(" ")
+        }
+    }
+}
+
+/// Parses symbols written as a binary string, or as hex with a `0x` prefix.
+/// Whitespace and `_` separators are ignored.
+pub fn parse_bits(text: &str) -> Result<Vec<bool>, String> {
+    let text = text.chars().filter(|c| !c.is_whitespace() && *c != '_').collect::<String>();
+    if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        let mut bits = Vec::with_capacity(hex.len() * 4);
+        for c in hex.chars() {
+            let nibble = c.to_digit(16).ok_or_else(|| format!("Invalid hex digit '{}'", c))?;
+            bits.extend((0..4).rev().map(|i| nibble & (1 << i) != 0));
+        }
+        Ok(bits)
+    } else {
+        text.chars()
+            .map(|c| match c {
+                '0' => Ok(false),
+                '1' => Ok(true),
+                _ => Err(format!("Invalid binary digit '{}'", c)),
+            })
+            .collect()
+    }
+}
+
+/// Parses run lengths as printed in the timing format, e.g. `+333 -667`
+pub fn parse_timings(text: &str) -> Result<Vec<i64>, String> {
+    text.split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|t| !t.is_empty())
+        .map(|t| {
+            t.trim_start_matches('+').parse::<i64>().map_err(|_| format!("Invalid duration '{}'", t))
+        })
+        .collect()
+}
+
+#[test]
+fn bit_formats() {
+    let bits = [true, false, false, true, true, true, false, false, true];
+    assert_eq!("100111001", format_bits(&bits, BitFormat::Bits, 1.0));
+    assert_eq!("9c 80", format_bits(&bits, BitFormat::Hex, 1.0));
+    assert_eq!("+333 -667 +1000 -667 +333",
+               format_bits(&bits, BitFormat::Timing, 1000.0 / 3.0));
+    assert_eq!(&bits[..], &bytes_to_bits(&[0x9c, 0x80])[..9]);
+    assert_eq!(Ok(vec![333, -667, 1000, -667, 333]),
+               parse_timings(&format_bits(&bits, BitFormat::Timing, 1000.0 / 3.0)));
+}
+
+#[test]
+fn parse_bit_strings() {
+    assert_eq!(Ok(vec![true, false, true, true]), parse_bits("10 1_1"));
+    assert_eq!(Ok(vec![true, false, true, false, false, false, false, true]),
+               parse_bits("0xA1"));
+    assert!(parse_bits("102").is_err());
+    assert!(parse_bits("0xag").is_err());
+}
@@ -0,0 +1,101 @@
+//! Smooth light transitions for smart fans. Their packets carry an absolute
+//! brightness, so a fade is a series of commands stepping between levels.
+
+use fan::BrightnessCurve;
+
+/// One command of a fade
+#[derive(Clone, Debug, PartialEq)]
+pub struct Step {
+    /// Time from the start of the fade
+    pub at_ms: f64,
+    pub brightness: f64,
+    pub level: u8,
+}
+
+/// Parses a duration like `500ms`, `30s`, `10m` or `1h`. Plain numbers are
+/// seconds.
+pub fn parse_duration(text: &str) -> Option<f64> {
+    let text = text.trim();
+    let split = text.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(text.len());
+    let scale = match &text[split..] {
+        "ms" => 1.0,
+        "" | "s" => 1000.0,
+        "m" | "min" => 60000.0,
+        "h" => 3600000.0,
+        _ => return None,
+    };
+    text[..split].parse::<f64>().ok().filter(|&val| val >= 0.0).map(|val| val * scale)
+}
+
+/// Steps from `from` to `to` brightness over `over_ms`, at most one every
+/// `min_interval_ms` so each command finishes sending before the next. Steps
+/// are spaced evenly in brightness, so a curve with gamma looks smooth, and
+/// never use a level the curve doesn't, so the light stays above the lowest
+/// level the fan accepts until it's turned off at the end. The last step is
+/// at `over_ms` and reaches `to`.
+pub fn schedule(curve: &BrightnessCurve,
+                from: f64,
+                to: f64,
+                over_ms: f64,
+                min_interval_ms: f64)
+                -> Vec<Step> {
+    let (start, end) = (curve.level(from), curve.level(to));
+    // Off is sent as the top level, but sits below the lowest on the scale
+    let scale = |level: u8| {
+        if level == curve.level(0.0) {
+            curve.min as i32 - 1
+        } else {
+            level as i32
+        }
+    };
+    let levels = (scale(end) - scale(start)).unsigned_abs() as usize;
+    let slots = (over_ms / min_interval_ms.max(1.0)).floor() as usize;
+    let count = levels.min(slots).max(1);
+
+    let mut steps: Vec<Step> = Vec::new();
+    for idx in 1..=count {
+        let frac = idx as f64 / count as f64;
+        let brightness = from + (to - from) * frac;
+        let level = curve.level(brightness);
+        if steps.last().map_or(level == start, |s| s.level == level) {
+            continue;
+        }
+        steps.push(Step {
+            at_ms: over_ms * frac,
+            brightness,
+            level,
+        });
+    }
+    steps
+}
+
+#[test]
+fn fade_schedule() {
+    assert_eq!(Some(600000.0), parse_duration("10m"));
+    assert_eq!(Some(1500.0), parse_duration("1.5"));
+    assert_eq!(Some(250.0), parse_duration("250ms"));
+    assert_eq!(None, parse_duration("10 years"));
+
+    let curve = BrightnessCurve::DEFAULT;
+    // 43 levels from 19 to 62, but only room for one a second
+    let steps = schedule(&curve, 0.0, 1.0, 10000.0, 1000.0);
+    assert_eq!(10, steps.len());
+    assert_eq!(Step {
+                   at_ms: 10000.0,
+                   brightness: 1.0,
+                   level: 62,
+               },
+               steps[9]);
+    assert!(steps.windows(2).all(|w| w[0].level < w[1].level));
+
+    // Down to off, never below the lowest level before it
+    let steps = schedule(&curve, 0.5, 0.0, 60000.0, 100.0);
+    assert_eq!(22, steps.len());
+    assert!(steps[..21].iter().all(|s| s.level >= curve.min));
+    assert_eq!(63, steps[21].level);
+    assert_eq!(60000.0, steps[21].at_ms);
+
+    // Nothing to do, or too short for more than one step
+    assert!(schedule(&curve, 0.5, 0.5, 1000.0, 100.0).is_empty());
+    assert_eq!(1, schedule(&curve, 0.2, 0.8, 10.0, 100.0).len());
+}
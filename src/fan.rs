@@ -0,0 +1,821 @@
+//! Hampton Bay / Harbor Breeze fan remote packets and their OOK encoding
+
+use std::fmt;
+use std::io;
+use std::iter::repeat;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use pwm::PwmSymbols;
+use rfm::Rfm22;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum FanPkt {
+    Dumb(FanPkt12),
+    Smart(FanPkt21),
+}
+
+/// Repeats a frame with `gap_symbols` of silence after each copy
+pub fn frame_stream(frame: &[bool], gap_symbols: usize, repeats: usize) -> Vec<bool> {
+    let mut stream = Vec::with_capacity((frame.len() + gap_symbols) * repeats);
+    for _ in 0..repeats {
+        stream.extend_from_slice(frame);
+        let len = stream.len() + gap_symbols;
+        stream.resize(len, false);
+    }
+    stream
+}
+
+/// Overrides for how a command is repeated on air. Unset fields keep the
+/// packet type's frame count and the PWM timing's gap.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Repeat {
+    /// Frames to send
+    pub count: Option<usize>,
+    /// Silence after each frame in ms
+    pub gap_ms: Option<f64>,
+    /// Send more frames if needed to keep transmitting for this many ms
+    pub min_duration_ms: Option<f64>,
+}
+
+impl Repeat {
+    /// Takes each unset field from `fallback`
+    pub fn or(self, fallback: Repeat) -> Repeat {
+        Repeat {
+            count: self.count.or(fallback.count),
+            gap_ms: self.gap_ms.or(fallback.gap_ms),
+            min_duration_ms: self.min_duration_ms.or(fallback.min_duration_ms),
+        }
+    }
+
+    /// Works out the timing and number of frames to send `pkt` with, at
+    /// `data_rate_hz` symbols/s
+    pub fn resolve(&self, pkt: &FanPkt, pwm: &PwmSymbols, data_rate_hz: f64) -> (PwmSymbols, usize) {
+        let mut pwm = *pwm;
+        if let Some(gap_ms) = self.gap_ms {
+            pwm.gap = (gap_ms * data_rate_hz / 1000.0).round() as usize;
+        }
+        let mut frames = self.count.unwrap_or_else(|| pkt.repeats());
+        if let Some(min_ms) = self.min_duration_ms {
+            let frame_ms = pkt.frame(&pwm).len() as f64 * 1000.0 / data_rate_hz;
+            frames = frames.max((min_ms / frame_ms).ceil() as usize);
+        }
+        (pwm, frames)
+    }
+}
+
+/// Ends a `FanPkt::hold` from another thread, like letting go of the button
+#[derive(Clone, Debug, Default)]
+pub struct Release(Arc<AtomicBool>);
+
+impl Release {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn release(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_released(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Symbols of a frame repeated until a stop condition, checked before each
+/// frame once `min_frames` have been sent
+pub struct HoldStream<F: FnMut() -> bool> {
+    frame: Vec<bool>,
+    pos: usize,
+    min_frames: usize,
+    stop: F,
+    /// Frames started so far
+    pub frames: usize,
+}
+
+impl<F: FnMut() -> bool> Iterator for HoldStream<F> {
+    type Item = bool;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos == self.frame.len() {
+            self.pos = 0;
+        }
+        if self.pos == 0 {
+            if self.frames >= self.min_frames && (self.stop)() {
+                return None;
+            }
+            self.frames += 1;
+        }
+        self.pos += 1;
+        Some(self.frame[self.pos - 1])
+    }
+}
+
+impl FanPkt {
+    /// Packet data bits, before symbol expansion
+    pub fn bits(&self) -> Vec<bool> {
+        match *self {
+            FanPkt::Dumb(ref pkt) => pkt.into_iter().collect(),
+            FanPkt::Smart(ref pkt) => pkt.into_iter().collect(),
+        }
+    }
+
+    /// Address of the fan the command is for
+    pub fn addr(&self) -> u8 {
+        match *self {
+            FanPkt::Dumb(ref pkt) => pkt.addr(),
+            FanPkt::Smart(ref pkt) => pkt.addr(),
+        }
+    }
+
+    /// Named fields of `bits()` as `(label, start, end)` bit ranges
+    pub fn fields(&self) -> Vec<(String, usize, usize)> {
+        match *self {
+            FanPkt::Dumb(ref pkt) => {
+                let cmd = match pkt.cmd() {
+                    Some(cmd) => format!("{:?}", cmd),
+                    None => format!("cmd 0x{:02x}", pkt.cmd),
+                };
+                vec![("1".to_string(), 0, 1), (format!("addr {}", pkt.addr), 1, 5), (cmd, 5, 12)]
+            }
+            FanPkt::Smart(ref pkt) => {
+                let light = if pkt.brightness() == 0.0 {
+                    "light off".to_string()
+                } else {
+                    format!("light {:.0}%", pkt.brightness() * 100.0)
+                };
+                vec![("111".to_string(), 0, 3),
+                     (format!("addr {}", pkt.addr()), 3, 7),
+                     ("1".to_string(), 7, 8),
+                     (light, 8, 14),
+                     (format!("{:?}", pkt.fan()), 14, 16),
+                     ("1".to_string(), 16, 17),
+                     (format!("sum {:x}", pkt.chksum), 17, 21)]
+            }
+        }
+    }
+
+    /// Number of frames sent per command
+    pub fn repeats(&self) -> usize {
+        match *self {
+            FanPkt::Dumb(_) => 20,
+            FanPkt::Smart(_) => 30,
+        }
+    }
+
+    /// Start bit and packet bits, expanded to PWM symbols
+    pub fn expanded(&self, pwm: &PwmSymbols) -> Vec<bool> {
+        FanExpand::new(repeat(false).take(1) // Start bit
+                           .chain(self.bits()),
+                       *pwm)
+            .collect()
+    }
+
+    /// One frame on air: the expanded packet followed by the inter-frame gap
+    pub fn frame(&self, pwm: &PwmSymbols) -> Vec<bool> {
+        frame_stream(&self.expanded(pwm), pwm.gap, 1)
+    }
+
+    /// `frames` frames back to back, e.g. as worked out by `Repeat::resolve`.
+    /// Commands are normally sent `repeats()` times.
+    pub fn repeated(&self, pwm: &PwmSymbols, frames: usize) -> Vec<bool> {
+        frame_stream(&self.expanded(pwm), pwm.gap, frames)
+    }
+
+    pub fn transmit(&self, rf: &mut Rfm22, pwm: &PwmSymbols, frames: usize) -> io::Result<()> {
+        rf.transmit_bitstream(self.repeated(pwm, frames))
+    }
+
+    /// Frames of the command until `stop` returns true, like a held button
+    pub fn hold_stream<F: FnMut() -> bool>(&self,
+                                           pwm: &PwmSymbols,
+                                           min_frames: usize,
+                                           stop: F)
+                                           -> HoldStream<F> {
+        HoldStream {
+            frame: self.frame(pwm),
+            pos: 0,
+            min_frames,
+            stop,
+            frames: 0,
+        }
+    }
+
+    /// Sends at least `min_frames` frames, then keeps sending until `stop`
+    /// returns true. Returns the number of frames sent.
+    pub fn hold<F: FnMut() -> bool>(&self,
+                                    rf: &mut Rfm22,
+                                    pwm: &PwmSymbols,
+                                    min_frames: usize,
+                                    stop: F)
+                                    -> io::Result<usize> {
+        let mut stream = self.hold_stream(pwm, min_frames, stop);
+        rf.transmit_bitstream(stream.by_ref())?;
+        Ok(stream.frames)
+    }
+
+    /// Decodes every valid frame in a symbol stream
+    pub fn decode(symbols: &[bool], pwm: &PwmSymbols) -> Vec<FanPkt> {
+        unexpand(symbols, pwm)
+            .iter()
+            .filter_map(|bits| match bits.len() {
+                12 => FanPkt12::from_bits(bits).map(FanPkt::Dumb),
+                21 => FanPkt21::from_bits(bits).map(FanPkt::Smart),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for FanPkt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FanPkt::Dumb(ref pkt) => {
+                write!(f, "dumb address {} ", pkt.addr)?;
+                match pkt.cmd() {
+                    Some(cmd) => write!(f, "{:?}", cmd),
+                    None => write!(f, "command 0x{:02x}", pkt.cmd),
+                }
+            }
+            FanPkt::Smart(ref pkt) => {
+                write!(f,
+                       "smart address {} fan {:?} brightness {:.0}%",
+                       pkt.addr(),
+                       pkt.fan(),
+                       pkt.brightness() * 100.0)
+            }
+        }
+    }
+}
+
+/// Reverses `FanExpand` on each frame of a symbol stream and returns the
+/// packet bits with the start bit removed. Frames end where the symbols stop
+/// looking like PWM bits, such as at a gap.
+fn unexpand(symbols: &[bool], pwm: &PwmSymbols) -> Vec<Vec<bool>> {
+    // The bit whose waveform starts at `idx`, if any
+    let bit_at = |idx: usize| {
+        let bit = &symbols[idx..idx + pwm.period];
+        [false, true].iter().cloned().find(|&value| {
+            let pulse = pwm.pulse(value);
+            bit.iter().enumerate().all(|(pos, &on)| on == pulse.contains(&pos))
+        })
+    };
+    let mut frames = Vec::new();
+    let mut pos = 0;
+    while let Some(first_one) = symbols[pos..].iter().position(|&b| b).map(|n| pos + n) {
+        // The first 1 is where the start bit's pulse begins
+        let mut idx = first_one.saturating_sub(pwm.pulse(false).start).max(pos);
+        let mut bits = Vec::new();
+        while idx + pwm.period <= symbols.len() {
+            match bit_at(idx) {
+                Some(bit) => bits.push(bit),
+                None => break,
+            }
+            idx += pwm.period;
+        }
+        if bits.len() > 1 && !bits[0] {
+            frames.push(bits.split_off(1));
+        }
+        pos = idx.max(first_one + 1);
+    }
+    frames
+}
+
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FanCmd12 {
+    Light = 0x01,
+    FanHigh = 0x20,
+    FanMed = 0x10,
+    FanLow = 0x08,
+    FanOff = 0x02,
+}
+
+impl FanCmd12 {
+    /// Every command with its command line name
+    pub const NAMES: [(&'static str, FanCmd12); 5] = [("light", FanCmd12::Light),
+                                                     ("off", FanCmd12::FanOff),
+                                                     ("low", FanCmd12::FanLow),
+                                                     ("medium", FanCmd12::FanMed),
+                                                     ("high", FanCmd12::FanHigh)];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::NAMES.iter().find(|n| n.0 == name).map(|n| n.1)
+    }
+
+    pub fn from_code(code: u8) -> Option<Self> {
+        Self::NAMES.iter().find(|n| n.1 as u8 == code).map(|n| n.1)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FanPkt12 {
+    addr: u8,
+    cmd: u8,
+}
+
+impl FanPkt12 {
+    pub fn new(addr: u8, cmd: FanCmd12) -> Self {
+        FanPkt12 {
+            addr: addr,
+            cmd: cmd as u8,
+        }
+    }
+
+    /// A command by its 7-bit code, for functions `FanCmd12` doesn't name
+    pub fn from_code(addr: u8, code: u8) -> Self {
+        assert!(addr <= 0xf && code < 0x80);
+        FanPkt12 {
+            addr,
+            cmd: code,
+        }
+    }
+
+    /// Parses the 12 packet bits
+    pub fn from_bits(bits: &[bool]) -> Option<Self> {
+        if bits.len() != 12 || !bits[0] {
+            return None;
+        }
+        Some(FanPkt12 {
+            addr: bits_to_u8(&bits[1..5]),
+            cmd: bits_to_u8(&bits[5..12]),
+        })
+    }
+
+    pub fn addr(&self) -> u8 {
+        self.addr
+    }
+
+    /// The command, if it is one we know
+    pub fn cmd(&self) -> Option<FanCmd12> {
+        FanCmd12::from_code(self.cmd)
+    }
+
+    /// The 7-bit command code
+    pub fn code(&self) -> u8 {
+        self.cmd
+    }
+}
+
+/// Packs bits MSB first
+fn bits_to_u8(bits: &[bool]) -> u8 {
+    bits.iter().fold(0, |val, &bit| val << 1 | bit as u8)
+}
+
+impl<'a> IntoIterator for &'a FanPkt12 {
+    type Item = bool;
+    type IntoIter = FanPkt12Bits<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        FanPkt12Bits::new(self)
+    }
+}
+
+#[derive(Clone)]
+pub struct FanPkt12Bits<'a> {
+    pkt: &'a FanPkt12,
+    count: u8,
+}
+
+impl<'a> FanPkt12Bits<'a> {
+    fn new(pkt: &'a FanPkt12) -> Self {
+        FanPkt12Bits {
+            pkt: pkt,
+            count: 0,
+        }
+    }
+}
+
+impl<'a> Iterator for FanPkt12Bits<'a> {
+    type Item = bool;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let ret = match self.count {
+            0 => Some(true), // First bit is a 1
+            1...4 => Some((self.pkt.addr & (1 << (3 - (self.count - 1))) != 0)),
+            5...11 => Some((self.pkt.cmd as u8 & (1 << (6 - (self.count - 5))) != 0)),
+            _ => return None,
+        };
+        self.count += 1;
+        ret
+    }
+}
+
+#[test]
+fn fan12_serializer() {
+    fn from_iter<I: Iterator<Item = bool>>(mut iter: I) -> FanPkt12 {
+        assert_eq!(iter.next().unwrap(), true); // First 1 bit
+        let addr = if iter.next().unwrap() { 1 << 3 } else { 0 } |
+                   if iter.next().unwrap() { 1 << 2 } else { 0 } |
+                   if iter.next().unwrap() { 1 << 1 } else { 0 } |
+                   if iter.next().unwrap() { 1 << 0 } else { 0 };
+        let cmd = if iter.next().unwrap() { 1 << 6 } else { 0 } |
+                  if iter.next().unwrap() { 1 << 5 } else { 0 } |
+                  if iter.next().unwrap() { 1 << 4 } else { 0 } |
+                  if iter.next().unwrap() { 1 << 3 } else { 0 } |
+                  if iter.next().unwrap() { 1 << 2 } else { 0 } |
+                  if iter.next().unwrap() { 1 << 1 } else { 0 } |
+                  if iter.next().unwrap() { 1 << 0 } else { 0 };
+        assert!(iter.next().is_none());
+        FanPkt12 {
+            addr: addr,
+            cmd: cmd,
+        }
+    }
+    for addr in 0..16 {
+        for cmd in 0..128 {
+            let pkt = FanPkt12 {
+                addr: addr,
+                cmd: cmd,
+            };
+            assert_eq!(pkt.clone(), from_iter(pkt.into_iter()));
+        }
+    }
+}
+
+fn reverse_nibble(n: u8) -> u8 {
+    (n & (1 << 3)) >> 3 | (n & (1 << 2)) >> 1 | (n & (1 << 1)) << 1 | (n & (1 << 0)) << 3
+}
+
+#[test]
+fn test_reverse_nibble() {
+    assert_eq!(0x8, reverse_nibble(0x1));
+    assert_eq!(0x4, reverse_nibble(0x2));
+    assert_eq!(0x2, reverse_nibble(0x4));
+    assert_eq!(0x1, reverse_nibble(0x8));
+    assert_eq!(0x7, reverse_nibble(0xe));
+}
+
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FanState21 {
+    Off = 0x3,
+    Low = 0x0,
+    #[serde(rename = "medium")]
+    Med = 0x1,
+    High = 0x2,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FanPkt21 {
+    data0: u8,
+    data1: u8,
+    chksum: u8,
+}
+
+const BRIGHTNESS_MAX: u8 = 62;
+// Fan seems to reject commands with brightness < ~30%
+const BRIGHTNESS_MIN: u8 = 19;
+/// Brightness value that turns the light off
+const BRIGHTNESS_OFF: u8 = 63;
+
+/// Maps brightness from 0.0 to 1.0 onto the 6-bit level sent to a fan.
+/// Fans differ in the lowest level they accept, which `calibrate` finds.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BrightnessCurve {
+    /// Level for the lowest brightness above off
+    pub min: u8,
+    /// Level for full brightness
+    pub max: u8,
+    /// Levels go as brightness to this power. Above 1 gives finer steps at
+    /// the dim end, where the eye notices them most.
+    pub gamma: f64,
+}
+
+impl Default for BrightnessCurve {
+    fn default() -> Self {
+        BrightnessCurve::DEFAULT
+    }
+}
+
+impl BrightnessCurve {
+    pub const DEFAULT: BrightnessCurve = BrightnessCurve {
+        min: BRIGHTNESS_MIN,
+        max: BRIGHTNESS_MAX,
+        gamma: 1.0,
+    };
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.min > self.max || self.max > BRIGHTNESS_MAX {
+            return Err(format!("Brightness levels must satisfy min <= max <= {}",
+                               BRIGHTNESS_MAX));
+        }
+        if self.gamma.is_nan() || self.gamma <= 0.0 {
+            return Err("Brightness gamma must be positive".to_string());
+        }
+        Ok(())
+    }
+
+    /// Level to send for `brightness`. 0.0 turns the light off.
+    pub fn level(&self, brightness: f64) -> u8 {
+        assert!((0.0..=1.0).contains(&brightness));
+        if brightness == 0.0 {
+            BRIGHTNESS_OFF
+        } else {
+            // Nudged so levels survive a round trip through `brightness()`
+            ((self.max - self.min) as f64 * brightness.powf(self.gamma) + 1e-9) as u8 + self.min
+        }
+    }
+
+    /// Brightness a level stands for. Levels below `min` count as `min`,
+    /// which is just above 0.0 so it isn't mistaken for off.
+    pub fn brightness(&self, level: u8) -> f64 {
+        match level {
+            BRIGHTNESS_OFF => 0.0,
+            _ if self.max == self.min => 1.0,
+            val => {
+                let linear = (val.clamp(self.min, self.max) - self.min) as f64 /
+                             (self.max - self.min) as f64;
+                linear.powf(1.0 / self.gamma).max(f64::MIN_POSITIVE)
+            }
+        }
+    }
+}
+
+impl FanPkt21 {
+    pub fn new(addr: u8, brightness: f64, fan: FanState21) -> Self {
+        Self::from_level(addr, BrightnessCurve::DEFAULT.level(brightness), fan)
+    }
+
+    /// A command with a raw 6-bit brightness level. 63 turns the light off.
+    pub fn from_level(addr: u8, level: u8, fan: FanState21) -> Self {
+        assert!(level <= BRIGHTNESS_OFF);
+        let data0 = 0x7 << 5 | reverse_nibble(addr) << 1 | 1;
+        let data1 = level << 2 | fan as u8;
+        FanPkt21 {
+            data0: data0,
+            data1: data1,
+            chksum: Self::checksum(data0, data1),
+        }
+    }
+
+    fn checksum(data0: u8, data1: u8) -> u8 {
+        ((data0 >> 4) + (data0 & 0xf) + (data1 >> 4) + (data1 & 0xf) + 3) & 0xf
+    }
+
+    /// Parses the 21 packet bits. Fails on a bad checksum.
+    pub fn from_bits(bits: &[bool]) -> Option<Self> {
+        if bits.len() != 21 || !bits[16] {
+            return None;
+        }
+        let pkt = FanPkt21 {
+            data0: bits_to_u8(&bits[0..8]),
+            data1: bits_to_u8(&bits[8..16]),
+            chksum: bits_to_u8(&bits[17..21]),
+        };
+        if pkt.data0 >> 5 != 0x7 || pkt.data0 & 1 == 0 ||
+           pkt.chksum != Self::checksum(pkt.data0, pkt.data1) {
+            return None;
+        }
+        Some(pkt)
+    }
+
+    pub fn addr(&self) -> u8 {
+        reverse_nibble(self.data0 >> 1 & 0xf)
+    }
+
+    pub fn fan(&self) -> FanState21 {
+        match self.data1 & 0x3 {
+            0x0 => FanState21::Low,
+            0x1 => FanState21::Med,
+            0x2 => FanState21::High,
+            _ => FanState21::Off,
+        }
+    }
+
+    /// Light brightness from 0.0 to 1.0 on the default curve. Only
+    /// approximately what was passed to `new()` since the packet has a
+    /// coarser scale.
+    pub fn brightness(&self) -> f64 {
+        BrightnessCurve::DEFAULT.brightness(self.level())
+    }
+
+    /// The raw 6-bit brightness level
+    pub fn level(&self) -> u8 {
+        self.data1 >> 2
+    }
+}
+
+impl<'a> IntoIterator for &'a FanPkt21 {
+    type Item = bool;
+    type IntoIter = FanPkt21Bits<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        FanPkt21Bits::new(self)
+    }
+}
+
+#[derive(Clone)]
+pub struct FanPkt21Bits<'a> {
+    pkt: &'a FanPkt21,
+    count: u8,
+}
+
+impl<'a> FanPkt21Bits<'a> {
+    fn new(pkt: &'a FanPkt21) -> Self {
+        FanPkt21Bits {
+            pkt: pkt,
+            count: 0,
+        }
+    }
+}
+
+impl<'a> Iterator for FanPkt21Bits<'a> {
+    type Item = bool;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let ret = match self.count {
+            0...7 => Some(self.pkt.data0 & (1 << (7 - (self.count - 0))) != 0),
+            8...15 => Some(self.pkt.data1 & (1 << (7 - (self.count - 8))) != 0),
+            16 => Some(true),
+            17...20 => Some(self.pkt.chksum & (1 << (3 - (self.count - 17))) != 0),
+            _ => return None,
+        };
+        self.count += 1;
+        ret
+    }
+}
+
+#[test]
+fn fan21_serializer() {
+    fn from_iter<I: Iterator<Item = bool>>(mut iter: I) -> u8 {
+        // Three high bits
+        assert_eq!(iter.next().unwrap(), true);
+        assert_eq!(iter.next().unwrap(), true);
+        assert_eq!(iter.next().unwrap(), true);
+        let addr = if iter.next().unwrap() { 1 << 0 } else { 0 } |
+                   if iter.next().unwrap() { 1 << 1 } else { 0 } |
+                   if iter.next().unwrap() { 1 << 2 } else { 0 } |
+                   if iter.next().unwrap() { 1 << 3 } else { 0 };
+        // High bit
+        assert_eq!(iter.next().unwrap(), true);
+        // State
+        for _ in 0..8 {
+            iter.next().unwrap();
+        }
+        // High bit
+        assert_eq!(iter.next().unwrap(), true);
+        // Chksum
+        for _ in 0..4 {
+            iter.next().unwrap();
+        }
+        assert!(iter.next().is_none());
+        addr
+    }
+    for addr in 0..16 {
+        for state in [FanState21::Off].iter() {
+            let pkt = FanPkt21::new(addr, 0.0, *state);
+            assert_eq!(addr, from_iter(pkt.into_iter()));
+        }
+    }
+}
+
+/// Adapts a data bit stream to PWM symbols. Pulses of both lengths end at
+/// the same symbol of the bit, `long` symbols in. With the fan timing that's
+/// 3 symbols per bit: the bit, 1, 0.
+#[derive(Clone)]
+pub struct FanExpand<I: Iterator<Item = bool>> {
+    bits: I,
+    pwm: PwmSymbols,
+    bit: bool,
+    /// Symbol within the current bit
+    pos: usize,
+}
+
+impl<I: Iterator<Item = bool>> FanExpand<I> {
+    pub fn new(bits: I, pwm: PwmSymbols) -> Self {
+        FanExpand {
+            bits,
+            pwm,
+            bit: false,
+            pos: 0,
+        }
+    }
+}
+
+impl<I: Iterator<Item = bool>> Iterator for FanExpand<I> {
+    type Item = bool;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos == 0 {
+            self.bit = self.bits.next()?;
+        }
+        let on = self.pwm.pulse(self.bit).contains(&self.pos);
+        self.pos = (self.pos + 1) % self.pwm.period;
+        Some(on)
+    }
+}
+
+#[test]
+fn fan_repeat() {
+    let pkt = FanPkt::Dumb(FanPkt12::new(5, FanCmd12::Light));
+    let pwm = PwmSymbols::FAN;
+    assert_eq!((pwm, 20), Repeat::default().resolve(&pkt, &pwm, 3000.0));
+    let repeat = Repeat {
+        count: Some(4),
+        gap_ms: Some(20.0),
+        ..Repeat::default()
+    };
+    let (gapped, frames) = repeat.resolve(&pkt, &pwm, 3000.0);
+    assert_eq!((60, 4), (gapped.gap, frames));
+    assert_eq!((39 + 60) * 4, pkt.repeated(&gapped, frames).len());
+    // The fallback's gap is ignored, so frames are 99 symbols, 33ms
+    let repeat = Repeat { min_duration_ms: Some(1000.0), ..repeat }.or(Repeat {
+        count: Some(1),
+        gap_ms: Some(0.0),
+        ..Repeat::default()
+    });
+    assert_eq!(31, repeat.resolve(&pkt, &pwm, 3000.0).1);
+
+    // Released partway through the third frame, which is still finished
+    let release = Release::new();
+    let handle = release.clone();
+    let len = pkt.frame(&pwm).len();
+    let mut stream = pkt.hold_stream(&pwm, 1, move || release.is_released());
+    assert_eq!(len * 2 + 1, stream.by_ref().take(len * 2 + 1).count());
+    handle.release();
+    assert_eq!(len - 1, stream.by_ref().count());
+    assert_eq!(3, stream.frames);
+
+    let mut stops = 0;
+    let stream = pkt.hold_stream(&pwm, 2, || {
+            stops += 1;
+            stops == 3
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(pkt.repeated(&pwm, 4), stream);
+}
+
+#[test]
+fn fan_decode() {
+    let pkts = [FanPkt::Dumb(FanPkt12::new(5, FanCmd12::Light)),
+                FanPkt::Dumb(FanPkt12::new(0xf, FanCmd12::FanOff)),
+                FanPkt::Smart(FanPkt21::new(5, 0.5, FanState21::Low)),
+                FanPkt::Smart(FanPkt21::new(0, 0.0, FanState21::Off))];
+    for pkt in pkts.iter() {
+        let stream = pkt.repeated(&PwmSymbols::FAN, pkt.repeats());
+        let decoded = FanPkt::decode(&stream, &PwmSymbols::FAN);
+        assert_eq!(pkt.repeats(), decoded.len());
+        assert!(decoded.iter().all(|d| d == pkt));
+    }
+    assert_eq!("smart address 5 fan Low brightness 49%", pkts[2].to_string());
+    // A flipped checksum bit is rejected
+    let mut stream = pkts[2].frame(&PwmSymbols::FAN);
+    let len = stream.len();
+    stream[len - PwmSymbols::FAN.gap - 3] ^= true;
+    assert!(FanPkt::decode(&stream, &PwmSymbols::FAN).is_empty());
+
+    let (pwm, _) = ::pwm::PwmTiming::EV1527.oversample().unwrap();
+    let stream = pkts[0].repeated(&pwm, pkts[0].repeats());
+    assert_eq!(vec![pkts[0].clone(); 20], FanPkt::decode(&stream, &pwm));
+}
+
+#[test]
+fn fan_pulse_align() {
+    use pwm::PwmTiming;
+
+    let expand = |bits: &[bool], pwm: &PwmSymbols| {
+        FanExpand::new(bits.iter().cloned(), *pwm).collect::<Vec<_>>()
+    };
+    // EV1527 pulses start each bit: 0 is 1, 0, 0, 0 and 1 is 1, 1, 1, 0
+    let (ev1527, _) = PwmTiming::EV1527.oversample().unwrap();
+    assert_eq!(vec![true, false, false, false], expand(&[false], &ev1527));
+    assert_eq!(vec![true, true, true, false], expand(&[true], &ev1527));
+    // Fan remote pulses end together: 0 is 0, 1, 0 and 1 is 1, 1, 0
+    assert_eq!(vec![false, true, false], expand(&[false], &PwmSymbols::FAN));
+    assert_eq!(vec![true, true, false], expand(&[true], &PwmSymbols::FAN));
+    let (parsed, _) = PwmTiming::parse("300,700,1000").unwrap().oversample().unwrap();
+    assert_eq!(vec![true, true, false, false, false, false, false],
+               expand(&[false], &parsed));
+}
+
+#[test]
+fn brightness_curve() {
+    let default = BrightnessCurve::DEFAULT;
+    assert_eq!(40, default.level(0.5));
+    assert_eq!(63, default.level(0.0));
+    assert_eq!(62, default.level(1.0));
+    assert_eq!(40, FanPkt21::new(5, 0.5, FanState21::Low).level());
+
+    let curve = BrightnessCurve {
+        min: 10,
+        max: 50,
+        gamma: 2.0,
+    };
+    assert_eq!(Ok(()), curve.validate());
+    assert_eq!(20, curve.level(0.5));
+    assert!((curve.brightness(20) - 0.5).abs() < 1e-9);
+    assert!(curve.brightness(10) > 0.0);
+    assert_eq!(10, curve.level(curve.brightness(10)));
+    assert!(BrightnessCurve { min: 30, ..curve }.validate().is_ok());
+    assert!(BrightnessCurve { max: 63, ..curve }.validate().is_err());
+    assert!(BrightnessCurve { gamma: 0.0, ..curve }.validate().is_err());
+
+    let pkt = FanPkt21::from_level(3, 7, FanState21::High);
+    assert_eq!(Some(pkt.clone()),
+               FanPkt21::from_bits(&pkt.into_iter().collect::<Vec<_>>()));
+    assert_eq!(7, pkt.level());
+}
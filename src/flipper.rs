@@ -0,0 +1,141 @@
+//! Flipper Zero Sub-GHz RAW captures (`.sub` files)
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+
+const FILETYPE: &str = "Flipper SubGhz RAW File";
+/// OOK with 650 kHz bandwidth, the Flipper's default for OOK remotes
+pub const PRESET_OOK: &str = "FuriHalSubGhzPresetOok650Async";
+/// Flipper splits RAW_Data over lines of at most this many durations
+const RAW_PER_LINE: usize = 512;
+
+/// A RAW capture. Durations are in microseconds, positive for carrier on and
+/// negative for off, the same as `encode::timings`.
+#[derive(Debug, PartialEq)]
+pub struct SubFile {
+    pub frequency_hz: u64,
+    pub preset: String,
+    pub raw: Vec<i64>,
+}
+
+fn invalid<E: ToString>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+impl SubFile {
+    pub fn new(frequency_hz: u64, raw: Vec<i64>) -> Self {
+        SubFile {
+            frequency_hz,
+            preset: PRESET_OOK.to_string(),
+            raw,
+        }
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::parse(BufReader::new(File::open(path)?))
+    }
+
+    /// Parses a RAW capture. Key-decoded captures (`Protocol` other than
+    /// RAW) are rejected.
+    pub fn parse<R: BufRead>(reader: R) -> io::Result<Self> {
+        let mut frequency_hz = None;
+        let mut preset = None;
+        let mut raw = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            let (key, val) = match line.find(':') {
+                Some(idx) => (line[..idx].trim(), line[idx + 1..].trim()),
+                None => continue,
+            };
+            match key {
+                "Filetype" if val != FILETYPE => {
+                    return Err(invalid(format!("Unsupported file type '{}'", val)))
+                }
+                "Frequency" => frequency_hz = Some(val.parse::<u64>().map_err(invalid)?),
+                "Preset" => preset = Some(val.to_string()),
+                "Protocol" if val != "RAW" => {
+                    return Err(invalid(format!("Only RAW captures are supported, not {}", val)))
+                }
+                "RAW_Data" => {
+                    for dur in val.split_whitespace() {
+                        raw.push(dur.parse::<i64>().map_err(invalid)?);
+                    }
+                }
+                _ => (),
+            }
+        }
+        Ok(SubFile {
+            frequency_hz: frequency_hz.ok_or_else(|| invalid("Missing Frequency"))?,
+            preset: preset.ok_or_else(|| invalid("Missing Preset"))?,
+            raw,
+        })
+    }
+
+    pub fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        writeln!(w, "Filetype: {}", FILETYPE)?;
+        writeln!(w, "Version: 1")?;
+        writeln!(w, "Frequency: {}", self.frequency_hz)?;
+        writeln!(w, "Preset: {}", self.preset)?;
+        writeln!(w, "Protocol: RAW")?;
+        for chunk in self.raw.chunks(RAW_PER_LINE) {
+            let durs = chunk.iter().map(|d| d.to_string()).collect::<Vec<_>>();
+            writeln!(w, "RAW_Data: {}", durs.join(" "))?;
+        }
+        Ok(())
+    }
+
+    pub fn is_ook(&self) -> bool {
+        self.preset.contains("Ook")
+    }
+
+    /// Quantizes the capture to symbols of `symbol_us`. Pulses shorter than
+    /// half a symbol are dropped.
+    pub fn to_bits(&self, symbol_us: f64) -> Vec<bool> {
+        let mut bits = Vec::new();
+        for &dur in self.raw.iter() {
+            let count = (dur.abs() as f64 / symbol_us).round() as usize;
+            let len = bits.len() + count;
+            bits.resize(len, dur > 0);
+        }
+        bits
+    }
+}
+
+#[test]
+fn flipper_round_trip() {
+    use encode::timings;
+    use fan::{FanPkt, FanPkt21, FanState21};
+    use pwm::PwmSymbols;
+
+    let symbol_us = 1000000.0 / 3000.0;
+    let pkt = FanPkt::Smart(FanPkt21::new(5, 0.5, FanState21::Low));
+    let stream = pkt.repeated(&PwmSymbols::FAN, pkt.repeats());
+    let sub = SubFile::new(303800000, timings(&stream, symbol_us));
+    let mut text = Vec::new();
+    sub.write(&mut text).unwrap();
+    let lines = text.split(|&b| b == b'\n').filter(|l| l.starts_with(b"RAW_Data")).count();
+    assert_eq!(sub.raw.len().div_ceil(RAW_PER_LINE), lines);
+
+    let parsed = SubFile::parse(&text[..]).unwrap();
+    assert_eq!(sub, parsed);
+    assert!(parsed.is_ook());
+    assert_eq!(stream, parsed.to_bits(symbol_us));
+    // Captures are noisy. Jitter shouldn't change the symbols.
+    let jittered = SubFile::new(303800000,
+                                parsed.raw
+                                    .iter()
+                                    .enumerate()
+                                    .map(|(i, d)| d + if i % 2 == 0 { 60 } else { -60 })
+                                    .collect());
+    assert_eq!(vec![pkt; 30], FanPkt::decode(&jittered.to_bits(symbol_us), &PwmSymbols::FAN));
+}
+
+#[test]
+fn flipper_rejects_decoded() {
+    let text = "Filetype: Flipper SubGhz Key File\nVersion: 1\nFrequency: 303875000\n";
+    assert!(SubFile::parse(text.as_bytes()).is_err());
+    let text = "Filetype: Flipper SubGhz RAW File\nVersion: 1\nFrequency: 303875000\n\
+                Preset: FuriHalSubGhzPresetOok650Async\nProtocol: Princeton\n";
+    assert!(SubFile::parse(text.as_bytes()).is_err());
+}
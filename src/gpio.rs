@@ -0,0 +1,80 @@
+use std::io;
+
+use sysfs_gpio::{self, Direction, Edge, Pin, PinPoller};
+
+/// The IRQ and shutdown lines a transport provides, if any
+pub type Lines = (Option<Box<dyn IrqLine>>, Option<Box<dyn ShutdownLine>>);
+
+/// The chip's SDN input
+pub trait ShutdownLine {
+    /// Whether the chip is currently held in shutdown
+    fn in_shutdown(&mut self) -> io::Result<bool>;
+    fn set_shutdown(&mut self, shutdown: bool) -> io::Result<()>;
+}
+
+/// The chip's active-low nIRQ output
+pub trait IrqLine {
+    fn asserted(&mut self) -> io::Result<bool>;
+    /// Waits up to `timeout_ms` for nIRQ to be asserted. Returns false on
+    /// timeout.
+    fn wait(&mut self, timeout_ms: isize) -> io::Result<bool>;
+}
+
+fn gpio_err(e: sysfs_gpio::Error) -> io::Error {
+    match e {
+        sysfs_gpio::Error::Io(e) => e,
+        e => io::Error::other(e.to_string()),
+    }
+}
+
+pub struct SysfsShutdown(Pin);
+
+impl SysfsShutdown {
+    pub fn new(pin: Pin) -> Self {
+        pin.export().unwrap();
+        SysfsShutdown(pin)
+    }
+}
+
+impl ShutdownLine for SysfsShutdown {
+    fn in_shutdown(&mut self) -> io::Result<bool> {
+        Ok(match self.0.get_direction().map_err(gpio_err)? {
+            Direction::High => true,
+            Direction::Out => self.0.get_value().map_err(gpio_err)? > 0,
+            _ => false,
+        })
+    }
+
+    fn set_shutdown(&mut self, shutdown: bool) -> io::Result<()> {
+        let dir = if shutdown {
+            Direction::High
+        } else {
+            Direction::Low
+        };
+        self.0.set_direction(dir).map_err(gpio_err)
+    }
+}
+
+pub struct SysfsIrq {
+    pin: Pin,
+    poller: PinPoller,
+}
+
+impl SysfsIrq {
+    pub fn new(pin: Pin) -> Self {
+        pin.export().unwrap();
+        pin.set_edge(Edge::FallingEdge).unwrap();
+        let poller = pin.get_poller().unwrap();
+        SysfsIrq { pin, poller }
+    }
+}
+
+impl IrqLine for SysfsIrq {
+    fn asserted(&mut self) -> io::Result<bool> {
+        self.pin.get_value().map(|val| val == 0).map_err(gpio_err)
+    }
+
+    fn wait(&mut self, timeout_ms: isize) -> io::Result<bool> {
+        self.poller.poll(timeout_ms).map(|val| val.is_some()).map_err(gpio_err)
+    }
+}
@@ -7,12 +7,45 @@ extern crate sysfs_gpio;
 #[macro_use]
 extern crate log;
 extern crate env_logger;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate serial;
+#[cfg(test)]
+extern crate libc;
 
+mod auto;
+mod bridge;
+mod compare;
+mod config;
+mod encode;
+mod fade;
+mod fan;
+mod flipper;
+mod gpio;
+mod mqtt;
+mod plot;
+mod probe;
+mod pwm;
 mod regrw;
+mod remote;
 mod rfm;
+mod rfmconfig;
+mod rtl433;
+mod schedule;
+mod sink;
+mod state;
+mod trace;
 
+use std::collections::VecDeque;
 use std::env;
-use std::iter::repeat;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::io::{self, BufReader, Read, Write};
 
 use clap::{Arg, ArgMatches, App, AppSettings, SubCommand};
 use env_logger::LogBuilder;
@@ -21,300 +54,79 @@ use spidev::{Spidev, SpidevOptions};
 use sysfs_gpio::Pin;
 
 use rfm::*;
+use auto::{Controller, Sensor};
+use bridge::SerialRegs;
+use compare::Measurement;
+use config::{Config, Scene};
+use encode::{BitFormat, bytes_to_bits, format_bits, parse_bits, parse_timings, timings};
+use fan::*;
+use flipper::SubFile;
+use pwm::{PwmSymbols, PwmTiming};
+use gpio::{IrqLine, Lines, ShutdownLine, SysfsIrq, SysfsShutdown};
+use regrw::{EmuRegs, FakeRegs, RegRw, RfmRegs};
+use remote::{RegServer, RemoteRegs};
+use rfmconfig::{GpioFunction, Rfm22Config, Rfm22ConfigBuilder};
+use sink::{SinkFormat, WaveformSink};
+use schedule::Task;
+use state::{FadeStatus, StateStore, Timer};
+use trace::{TraceRecorder, TraceReplay};
 
-enum FanPkt {
-    Dumb(FanPkt12),
-    Smart(FanPkt21),
-}
-
-impl FanPkt {
-    fn transmit(&self, rf: &mut Rfm22) {
-        fn send_pkt<I: IntoIterator<Item = bool>>(rf: &mut Rfm22, iter: I, count: usize)
-            where I::IntoIter: Clone
-        {
-            let bits = repeat(FanExpand::new(repeat(false).take(1) // Start bit
-                                             .chain(iter.into_iter()))
-                              .chain(std::iter::repeat(false).take(11 * 3))) // 11ms pause between commands. 1/3ms symbol period
-                .cycle()
-                .take(count)
-                .flat_map(|i| i);
-            rf.transmit_bitstream(bits).unwrap();
-        }
-
-        match *self {
-            FanPkt::Dumb(ref pkt) => send_pkt(rf, pkt, 20),
-            FanPkt::Smart(ref pkt) => send_pkt(rf, pkt, 30),
-        }
-    }
-}
-
-#[repr(u8)]
-#[derive(Copy, Clone)]
-enum FanCmd12 {
-    Light = 0x01,
-    FanHigh = 0x20,
-    FanMed = 0x10,
-    FanLow = 0x08,
-    FanOff = 0x02,
-}
-
-#[derive(Clone, Debug, PartialEq)]
-struct FanPkt12 {
-    addr: u8,
-    cmd: u8,
-}
-
-impl FanPkt12 {
-    fn new(addr: u8, cmd: FanCmd12) -> Self {
-        FanPkt12 {
-            addr: addr,
-            cmd: cmd as u8,
-        }
-    }
-}
-
-impl<'a> IntoIterator for &'a FanPkt12 {
-    type Item = bool;
-    type IntoIter = FanPkt12Bits<'a>;
-
-    fn into_iter(self) -> Self::IntoIter {
-        FanPkt12Bits::new(self)
-    }
-}
-
-#[derive(Clone)]
-struct FanPkt12Bits<'a> {
-    pkt: &'a FanPkt12,
-    count: u8,
-}
-
-impl<'a> FanPkt12Bits<'a> {
-    fn new(pkt: &'a FanPkt12) -> Self {
-        FanPkt12Bits {
-            pkt: pkt,
-            count: 0,
-        }
-    }
-}
-
-impl<'a> Iterator for FanPkt12Bits<'a> {
-    type Item = bool;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        let ret = match self.count {
-            0 => Some(true), // First bit is a 1
-            1...4 => Some((self.pkt.addr & (1 << (3 - (self.count - 1))) != 0)),
-            5...11 => Some((self.pkt.cmd as u8 & (1 << (6 - (self.count - 5))) != 0)),
-            _ => return None,
-        };
-        self.count += 1;
-        ret
-    }
-}
-
-#[test]
-fn fan12_serializer() {
-    fn from_iter<I: Iterator<Item = bool>>(mut iter: I) -> FanPkt12 {
-        assert_eq!(iter.next().unwrap(), true); // First 1 bit
-        let addr = if iter.next().unwrap() { 1 << 3 } else { 0 } |
-                   if iter.next().unwrap() { 1 << 2 } else { 0 } |
-                   if iter.next().unwrap() { 1 << 1 } else { 0 } |
-                   if iter.next().unwrap() { 1 << 0 } else { 0 };
-        let cmd = if iter.next().unwrap() { 1 << 6 } else { 0 } |
-                  if iter.next().unwrap() { 1 << 5 } else { 0 } |
-                  if iter.next().unwrap() { 1 << 4 } else { 0 } |
-                  if iter.next().unwrap() { 1 << 3 } else { 0 } |
-                  if iter.next().unwrap() { 1 << 2 } else { 0 } |
-                  if iter.next().unwrap() { 1 << 1 } else { 0 } |
-                  if iter.next().unwrap() { 1 << 0 } else { 0 };
-        assert!(iter.next().is_none());
-        FanPkt12 {
-            addr: addr,
-            cmd: cmd,
-        }
-    }
-    for addr in 0..16 {
-        for cmd in 0..128 {
-            let pkt = FanPkt12 {
-                addr: addr,
-                cmd: cmd,
-            };
-            assert_eq!(pkt.clone(), from_iter(pkt.into_iter()));
-        }
-    }
-}
-
-fn reverse_nibble(n: u8) -> u8 {
-    (n & (1 << 3)) >> 3 | (n & (1 << 2)) >> 1 | (n & (1 << 1)) << 1 | (n & (1 << 0)) << 3
-}
-
-#[test]
-fn test_reverse_nibble() {
-    assert_eq!(0x8, reverse_nibble(0x1));
-    assert_eq!(0x4, reverse_nibble(0x2));
-    assert_eq!(0x2, reverse_nibble(0x4));
-    assert_eq!(0x1, reverse_nibble(0x8));
-    assert_eq!(0x7, reverse_nibble(0xe));
-}
-
-#[repr(u8)]
-#[derive(Copy, Clone)]
-enum FanState21 {
-    Off = 0x3,
-    Low = 0x0,
-    Med = 0x1,
-    High = 0x2,
-}
-
-#[derive(Clone, Debug, PartialEq)]
-struct FanPkt21 {
-    data0: u8,
-    data1: u8,
-    chksum: u8,
-}
-
-impl FanPkt21 {
-    fn new(addr: u8, brightness: f64, fan: FanState21) -> Self {
-        const BRIGHTNESS_MAX: u8 = 62;
-        // Fan seems to reject commands with brightness < ~30%
-        const BRIGHTNESS_MIN: u8 = 19;
-        assert!(brightness >= 0.0 && brightness <= 1.0);
-        // Scale brightness.
-        let brightness = if brightness == 0.0 {
-            // Max value indicates off
-            63
-        } else {
-            ((BRIGHTNESS_MAX - BRIGHTNESS_MIN) as f64 * brightness) as u8 + BRIGHTNESS_MIN
-        };
-        let data0 = 0x7 << 5 | reverse_nibble(addr) << 1 | 1;
-        let data1 = brightness << 2 | fan as u8;
-        let chksum = (data0 >> 4) + (data0 & 0xf) + (data1 >> 4) + (data1 & 0xf) + 3;
-        FanPkt21 {
-            data0: data0,
-            data1: data1,
-            chksum: chksum & 0xf,
-        }
-    }
-}
-
-impl<'a> IntoIterator for &'a FanPkt21 {
-    type Item = bool;
-    type IntoIter = FanPkt21Bits<'a>;
-
-    fn into_iter(self) -> Self::IntoIter {
-        FanPkt21Bits::new(self)
-    }
-}
-
-#[derive(Clone)]
-struct FanPkt21Bits<'a> {
-    pkt: &'a FanPkt21,
-    count: u8,
-}
-
-impl<'a> FanPkt21Bits<'a> {
-    fn new(pkt: &'a FanPkt21) -> Self {
-        FanPkt21Bits {
-            pkt: pkt,
-            count: 0,
-        }
-    }
-}
-
-impl<'a> Iterator for FanPkt21Bits<'a> {
-    type Item = bool;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        let ret = match self.count {
-            0...7 => Some(self.pkt.data0 & (1 << (7 - (self.count - 0))) != 0),
-            8...15 => Some(self.pkt.data1 & (1 << (7 - (self.count - 8))) != 0),
-            16 => Some(true),
-            17...20 => Some(self.pkt.chksum & (1 << (3 - (self.count - 17))) != 0),
-            _ => return None,
-        };
-        self.count += 1;
-        ret
-    }
-}
-
-#[test]
-fn fan21_serializer() {
-    fn from_iter<I: Iterator<Item = bool>>(mut iter: I) -> u8 {
-        // Three high bits
-        assert_eq!(iter.next().unwrap(), true);
-        assert_eq!(iter.next().unwrap(), true);
-        assert_eq!(iter.next().unwrap(), true);
-        let addr = if iter.next().unwrap() { 1 << 0 } else { 0 } |
-                   if iter.next().unwrap() { 1 << 1 } else { 0 } |
-                   if iter.next().unwrap() { 1 << 2 } else { 0 } |
-                   if iter.next().unwrap() { 1 << 3 } else { 0 };
-        // High bit
-        assert_eq!(iter.next().unwrap(), true);
-        // State
-        for _ in 0..8 {
-            iter.next().unwrap();
-        }
-        // High bit
-        assert_eq!(iter.next().unwrap(), true);
-        // Chksum
-        for _ in 0..4 {
-            iter.next().unwrap();
-        }
-        assert!(iter.next().is_none());
-        addr
-    }
-    for addr in 0..16 {
-        for state in [FanState21::Off].iter() {
-            let pkt = FanPkt21::new(addr, 0.0, *state);
-            assert_eq!(addr, from_iter(pkt.into_iter()));
-        }
-    }
-}
+macro_rules! SPIDEV_DEFAULT { () => ("/dev/spidev1.0") }
+macro_rules! TX_POWER_DEFAULT { () => (3) }
+macro_rules! LISTEN_DEFAULT { () => ("0.0.0.0:7022") }
 
-#[derive(Clone)]
-enum FanExpandState {
-    Start,
-    Data,
-    End,
+fn dumb_subcommand<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("dumb")
+        .about("Send a 12-bit command. For fans with no LCD in the remote where the fan \
+                keeps the dimmer state.")
+        .arg(Arg::with_name("command")
+            .index(1)
+            .required_unless("raw-cmd")
+            .conflicts_with("raw-cmd")
+            .help("light\tToggle the light\n\
+                   off\tFan off\n\
+                   low\tFan low\n\
+                   medium\tFan medium\n\
+                   high\tFan high\n\
+                   or a name from the config's commands table"))
+        .arg(Arg::with_name("raw-cmd")
+            .long("raw-cmd")
+            .takes_value(true)
+            .help("Send this 7-bit command code instead (0x.., 0b.. or decimal)"))
 }
 
-/// Adapts a data bit stream to 3 symbols per bit
-#[derive(Clone)]
-struct FanExpand<I: Iterator<Item = bool>>(I, FanExpandState);
-
-impl<I: Iterator<Item = bool>> FanExpand<I> {
-    fn new(iter: I) -> Self {
-        FanExpand(iter, FanExpandState::Start)
-    }
+fn smart_subcommand<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("smart")
+        .about("Send a 21-bit command. For fans with an LCD in the remote where the remote \
+                keeps the dimmer state.")
+        .arg(Arg::with_name("fan")
+            .index(1)
+            .required(true)
+            .help("off\tFan off\nlow\tFan low\nmedium\tFan medium\nhigh\tFan high\n"))
+        .arg(Arg::with_name("brightness")
+            .index(2)
+            .required_unless("level")
+            .conflicts_with("level")
+            .help("Light brightness percentage (0-100), mapped to a level by the fan's \
+                   brightness curve"))
+        .arg(Arg::with_name("level")
+            .long("level")
+            .takes_value(true)
+            .help("Send this raw brightness level (0-62, 63 for off) instead"))
 }
 
-impl<I: Iterator<Item = bool>> Iterator for FanExpand<I> {
-    type Item = bool;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        match self.1 {
-            FanExpandState::Start => {
-                let val = self.0.next();
-                if val.is_some() {
-                    self.1 = FanExpandState::Data;
-                }
-                val
-            }
-            FanExpandState::Data => {
-                self.1 = FanExpandState::End;
-                Some(true)
-            }
-            FanExpandState::End => {
-                self.1 = FanExpandState::Start;
-                Some(false)
-            }
-        }
-    }
+fn run_subcommand<'a, 'b>() -> App<'a, 'b> {
+    SubCommand::with_name("run")
+        .about("Send timers, scheduled actions and automatic fan speeds as they come due. \
+                Timers that went off while nothing was running are sent at once.")
+        .arg(Arg::with_name("daemon")
+            .long("daemon")
+            .help("Run in the background"))
+        .arg(Arg::with_name("dry-run")
+            .long("dry-run")
+            .help("Print the encoded commands instead of sending them"))
 }
 
-macro_rules! SPIDEV_DEFAULT { () => ("/dev/spidev1.0") }
-macro_rules! TX_POWER_DEFAULT { () => (3) }
-
 fn arg_app<'a, 'b>() -> App<'a, 'b> {
     App::new(crate_name!())
         .version(crate_version!())
@@ -324,6 +136,11 @@ fn arg_app<'a, 'b>() -> App<'a, 'b> {
             .long("spidev")
             .help(concat!("Linux spidev device. Defaults to ", SPIDEV_DEFAULT!()))
             .takes_value(true))
+        .arg(Arg::with_name("bus")
+            .long("bus")
+            .help("Remote register bus. tcp://host:port for a regserver or serial:/dev/ttyUSB0 \
+                   for a microcontroller bridge. Replaces --spidev, --irq and --shutdown.")
+            .takes_value(true))
         .arg(Arg::with_name("irq")
             .short("i")
             .long("irq")
@@ -334,17 +151,92 @@ fn arg_app<'a, 'b>() -> App<'a, 'b> {
             .long("shutdown")
             .help("Shutdown gpio number")
             .takes_value(true))
+        .arg(Arg::with_name("reg-cache")
+            .long("reg-cache")
+            .help("Serve non-volatile register reads from a local cache"))
+        .arg(Arg::with_name("verify")
+            .long("verify")
+            .help("When to read back register writes. Defaults to always.")
+            .possible_values(&["always", "change", "never"])
+            .takes_value(true))
         .arg(Arg::with_name("txpower")
             .short("p")
             .long("txpower")
             .help(concat!("Transmit power. Range 0-7. Defaults to ",
                           TX_POWER_DEFAULT!()))
             .takes_value(true))
+        .arg(Arg::with_name("radio-gpio")
+            .long("radio-gpio")
+            .help("Set what one of the radio's GPIOs does, as PIN=FUNCTION, e.g. 0=tx_state and \
+                   1=rx_state for boards that switch the antenna with them")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("radio-config")
+            .short("r")
+            .long("radio-config")
+            .help("JSON file with radio settings. Defaults to the fan remote settings.")
+            .takes_value(true))
+        .arg(Arg::with_name("config")
+            .short("c")
+            .long("config")
+            .help("JSON settings file. Defaults to $XDG_CONFIG_HOME/fanrf/config.json if it \
+                   exists.")
+            .takes_value(true))
+        .arg(Arg::with_name("repeat")
+            .long("repeat")
+            .help("Number of frames to send. Defaults to 20 for dumb and 30 for smart commands.")
+            .takes_value(true))
+        .arg(Arg::with_name("gap")
+            .long("gap")
+            .help("Silence after each frame in ms. Defaults to 11.")
+            .takes_value(true))
+        .arg(Arg::with_name("min-duration")
+            .long("min-duration")
+            .help("Send more frames if needed to transmit for at least this many ms")
+            .takes_value(true))
+        .arg(Arg::with_name("hold")
+            .long("hold")
+            .help("Keep sending the command until Enter is pressed or stdin is closed, like \
+                   holding the button"))
+        .arg(Arg::with_name("pwm")
+            .long("pwm")
+            .help("Bit timing for receivers other than the fans: a preset (fan, ev1527) or \
+                   short,long,period[,gap] in us, with pulses starting each bit. Sets the \
+                   data rate.")
+            .takes_value(true))
         .arg(Arg::with_name("address")
             .short("a")
             .long("address")
             .help("Fan address")
             .takes_value(true))
+        .arg(Arg::with_name("trace")
+            .long("trace")
+            .help("Record every register access to a JSON lines file")
+            .takes_value(true))
+        .arg(Arg::with_name("replay")
+            .long("replay")
+            .help("Replay a recorded trace instead of using SPI. Fails if the driver's register \
+                   accesses differ from the trace.")
+            .takes_value(true))
+        .arg(Arg::with_name("sink")
+            .long("sink")
+            .help("Render transmissions to a waveform file instead of using the radio. The \
+                   format is taken from the extension: .cu8, .cf32 or .wav.")
+            .takes_value(true))
+        .arg(Arg::with_name("sink-format")
+            .long("sink-format")
+            .help("Waveform file format if it can't be told from the extension")
+            .possible_values(&["cu8", "cf32", "wav"])
+            .takes_value(true))
+        .arg(Arg::with_name("sample-rate")
+            .long("sample-rate")
+            .help("Waveform sample rate in Hz. Defaults to 250000 for IQ and 48000 for WAV.")
+            .takes_value(true))
+        .arg(Arg::with_name("state")
+            .long("state")
+            .help("Fan state file. Defaults to $XDG_STATE_HOME/fanrf/state.json")
+            .takes_value(true))
         .arg(Arg::with_name("verbose")
             .short("v")
             .long("verbose")
@@ -353,28 +245,300 @@ fn arg_app<'a, 'b>() -> App<'a, 'b> {
             .short("d")
             .long("debug")
             .help("Debug logging (implies debug)"))
-        .subcommand(SubCommand::with_name("dumb")
-            .about("Send a 12-bit command. For fans with no LCD in the remote where the fan \
-                    keeps the dimmer state.")
-            .arg(Arg::with_name("command")
+        .subcommand(dumb_subcommand())
+        .subcommand(smart_subcommand())
+        .subcommand(SubCommand::with_name("dim")
+            .about("Hold the Light button of a dumb fan to dim or brighten its light. Each \
+                    hold goes the opposite way to the last, which is tracked once known.")
+            .arg(Arg::with_name("for")
+                .long("for")
+                .help("Hold for this many ms")
+                .required_unless("to")
+                .conflicts_with("to")
+                .takes_value(true))
+            .arg(Arg::with_name("to")
+                .long("to")
+                .help("Hold long enough to reach this brightness in %. Needs the dim sweep time.")
+                .takes_value(true))
+            .arg(Arg::with_name("from")
+                .long("from")
+                .help("Current brightness in %. Defaults to the tracked state, or 100.")
+                .takes_value(true))
+            .arg(Arg::with_name("sweep")
+                .long("sweep")
+                .help("Time to dim from full to lowest in ms. Defaults to the fan's dim_sweep_ms \
+                       config.")
+                .takes_value(true))
+            .arg(Arg::with_name("direction")
+                .long("direction")
+                .help("Which way this hold goes, if the tracked state doesn't know. Later holds \
+                       are tracked from it.")
+                .possible_values(&["up", "down"])
+                .takes_value(true)))
+        .subcommand(SubCommand::with_name("raw")
+            .about("Transmit an arbitrary OOK bitstream")
+            .arg(Arg::with_name("bits")
                 .index(1)
-                .required(true)
-                .help("light\tToggle the light\n\
-                       off\tFan off\n\
-                       low\tFan low\n\
-                       medum\tFan medium\n\
-                       high\tFan high\n")))
-        .subcommand(SubCommand::with_name("smart")
-            .about("Send a 21-bit command. For fans with an LCD in the remote where the remote \
-                    keeps the dimmer state.")
-            .arg(Arg::with_name("fan")
+                .required_unless("file")
+                .help("Symbols as a binary string (1011...) or hex (0x5a...)"))
+            .arg(Arg::with_name("file")
+                .long("file")
+                .help("Read the symbols from a file in the same format")
+                .conflicts_with("bits")
+                .takes_value(true))
+            .arg(Arg::with_name("expand")
+                .short("e")
+                .long("expand")
+                .help("none\tSend the symbols as given\n\
+                       fan\tSend each bit as 3 symbols like the fan remotes\n")
+                .possible_values(&["none", "fan"])
+                .default_value("none"))
+            .arg(Arg::with_name("copies")
+                .long("copies")
+                .help("Number of times to send the symbols. Unlike --repeat, given for the \
+                       symbols as they are rather than a command.")
+                .default_value("1"))
+            .arg(Arg::with_name("copy-gap")
+                .long("copy-gap")
+                .help("Silence after each copy in ms")
+                .default_value("0"))
+            .arg(Arg::with_name("data-rate")
+                .long("data-rate")
+                .help("Symbol rate in Hz. Defaults to the radio config's.")
+                .takes_value(true))
+            .arg(Arg::with_name("freq")
+                .long("freq")
+                .help("Carrier frequency in MHz. Defaults to the radio config's.")
+                .takes_value(true)))
+        .subcommand(SubCommand::with_name("export")
+            .about("Write a dumb or smart command as a capture file for other tools")
+            .arg(Arg::with_name("format")
+                .short("f")
+                .long("format")
+                .help("flipper\tFlipper Zero Sub-GHz RAW (.sub)\n\
+                       ook\trtl_433 pulse data (.ook)\n")
+                .possible_values(&["flipper", "ook"])
+                .default_value("flipper"))
+            .arg(Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .help("Output file. Defaults to stdout.")
+                .takes_value(true))
+            .subcommand(dumb_subcommand())
+            .subcommand(smart_subcommand())
+            .setting(AppSettings::SubcommandRequired))
+        .subcommand(SubCommand::with_name("import")
+            .about("Send a Flipper Zero Sub-GHz RAW capture at its frequency. Captures of fan \
+                    commands are decoded and sent as clean packets.")
+            .arg(Arg::with_name("file")
                 .index(1)
                 .required(true)
-                .help("off\tFan off\nlow\tFan low\nmedum\tFan medium\nhigh\tFan high\n"))
-            .arg(Arg::with_name("brightness")
-                .index(2)
+                .help(".sub RAW capture"))
+            .arg(Arg::with_name("raw")
+                .long("raw")
+                .help("Replay the capture as recorded even if it decodes"))
+            .arg(Arg::with_name("decode-only")
+                .long("decode-only")
+                .help("Print what the capture decodes to without transmitting")))
+        .subcommand(SubCommand::with_name("ingest")
+            .about("Update the state store from rtl_433 JSON events. Run rtl_433 with -F json \
+                    -X and the flex decoder spec from --print-spec.")
+            .arg(Arg::with_name("file")
+                .index(1)
+                .help("JSON lines file. Defaults to stdin."))
+            .arg(Arg::with_name("print-spec")
+                .long("print-spec")
+                .help("Print the flex decoder spec for the bit timing set by --pwm and exit")))
+        .subcommand(SubCommand::with_name("encode")
+            .about("Print what a dumb or smart command would send, without touching the radio")
+            .arg(Arg::with_name("format")
+                .short("f")
+                .long("format")
+                .help("bits\tOne character per symbol\n\
+                       hex\tSymbols packed into bytes like the FIFO\n\
+                       timing\tRun lengths in us, + for carrier on\n")
+                .possible_values(&["bits", "hex", "timing"])
+                .default_value("bits"))
+            .arg(Arg::with_name("stage")
+                .long("stage")
+                .help("Only print one stage, without its label")
+                .possible_values(&["packet", "expanded", "frame", "stream", "fifo"])
+                .takes_value(true))
+            .subcommand(dumb_subcommand())
+            .subcommand(smart_subcommand())
+            .setting(AppSettings::SubcommandRequired))
+        .subcommand(SubCommand::with_name("plot")
+            .about("Draw the waveform of a command with its fields labelled")
+            .arg(Arg::with_name("svg")
+                .long("svg")
+                .help("Write an SVG timing diagram to this file instead")
+                .takes_value(true))
+            .arg(Arg::with_name("frames")
+                .long("frames")
+                .help("Number of frames of a dumb or smart command to draw")
+                .default_value("1"))
+            .arg(Arg::with_name("width")
+                .long("width")
+                .help("Terminal width to wrap at")
+                .default_value("80"))
+            .subcommand(dumb_subcommand())
+            .subcommand(smart_subcommand())
+            .subcommand(SubCommand::with_name("raw")
+                .about("Draw an arbitrary bitstream")
+                .arg(Arg::with_name("bits")
+                    .index(1)
+                    .required_unless("file")
+                    .help("Symbols as a binary string (1011...) or hex (0x5a...)"))
+                .arg(Arg::with_name("file")
+                    .long("file")
+                    .help("Read the symbols from a file in the same format")
+                    .conflicts_with("bits")
+                    .takes_value(true))
+                .arg(Arg::with_name("expand")
+                    .short("e")
+                    .long("expand")
+                    .help("none\tDraw the symbols as given\n\
+                           fan\tDraw each bit as 3 symbols like the fan remotes\n")
+                    .possible_values(&["none", "fan"])
+                    .default_value("none")))
+            .setting(AppSettings::SubcommandRequired))
+        .subcommand(SubCommand::with_name("compare")
+            .about("Compare a capture of a command with what we would send. The command is \
+                    decoded from the capture unless given.")
+            .arg(Arg::with_name("capture")
+                .index(1)
                 .required(true)
-                .help("Light brightness percentage (0-100)")))
+                .help("rtl_433 pulse data (.ook), Flipper Zero RAW (.sub), or the symbols or \
+                       timings printed by encode --stage stream"))
+            .subcommand(dumb_subcommand())
+            .subcommand(smart_subcommand()))
+        .subcommand(SubCommand::with_name("fade")
+            .about("Fade a smart fan's light to a brightness by sending a series of commands. \
+                    Stop it with Ctrl-C or fade --cancel.")
+            .arg(Arg::with_name("to")
+                .long("to")
+                .help("Brightness to end at in %")
+                .required_unless("cancel")
+                .takes_value(true))
+            .arg(Arg::with_name("over")
+                .long("over")
+                .help("How long to take, e.g. 90s, 10m or 1h")
+                .required_unless("cancel")
+                .conflicts_with("cancel")
+                .takes_value(true))
+            .arg(Arg::with_name("from")
+                .long("from")
+                .help("Brightness to start at in %. Defaults to the tracked state, or off.")
+                .takes_value(true))
+            .arg(Arg::with_name("fan")
+                .long("fan")
+                .help("Fan state to send with each step. Defaults to the tracked state, or off.")
+                .takes_value(true))
+            .arg(Arg::with_name("daemon")
+                .long("daemon")
+                .help("Run the fade in the background"))
+            .arg(Arg::with_name("cancel")
+                .long("cancel")
+                .conflicts_with("to")
+                .help("Stop the fade running on the fan")))
+        .subcommand(SubCommand::with_name("timer")
+            .about("Send a command or scene to a fan later, e.g. off in 45 minutes. Timers are \
+                    kept in the state store and sent by timer run.")
+            .subcommand(SubCommand::with_name("set")
+                .about("Set the fan's timer, replacing any other")
+                .arg(Arg::with_name("in")
+                    .long("in")
+                    .help("Time until it goes off, e.g. 45m or 1h")
+                    .required(true)
+                    .takes_value(true))
+                .arg(Arg::with_name("scene")
+                    .long("scene")
+                    .help("Send this scene from the config")
+                    .takes_value(true))
+                .subcommand(dumb_subcommand())
+                .subcommand(smart_subcommand()))
+            .subcommand(SubCommand::with_name("list").about("List the timers of all fans"))
+            .subcommand(SubCommand::with_name("cancel").about("Cancel the fan's timer"))
+            .subcommand(run_subcommand())
+            .setting(AppSettings::SubcommandRequired))
+        .subcommand(SubCommand::with_name("schedule")
+            .about("Actions the config's schedule runs at set times, by cron expression or \
+                    sunrise and sunset")
+            .subcommand(SubCommand::with_name("list")
+                .about("List the schedule's entries and when each runs next"))
+            .subcommand(SubCommand::with_name("next")
+                .about("List the actions the schedule runs next")
+                .arg(Arg::with_name("count")
+                    .short("n")
+                    .long("count")
+                    .help("How many to list")
+                    .default_value("10")
+                    .takes_value(true)))
+            .subcommand(run_subcommand())
+            .setting(AppSettings::SubcommandRequired))
+        .subcommand(SubCommand::with_name("auto")
+            .about("Set fan speeds from temperatures, as the config's automations say. A \
+                    command is sent only when a fan's speed changes.")
+            .subcommand(SubCommand::with_name("read")
+                .about("Read each automation's temperature and show the speed it maps to"))
+            .subcommand(run_subcommand())
+            .setting(AppSettings::SubcommandRequired))
+        .subcommand(SubCommand::with_name("sensors")
+            .about("Read the radio's temperature and supply voltage, and how many times its \
+                    configuration has been restored after a reset. Exits with an error if the \
+                    temperature or voltage is out of bounds.")
+            .arg(Arg::with_name("low-battery")
+                .long("low-battery")
+                .help("Raise the low battery interrupt below this many volts (1.7-3.25)")
+                .takes_value(true))
+            .arg(Arg::with_name("max-temp")
+                .long("max-temp")
+                .help("Warn above this temperature in °C")
+                .takes_value(true))
+            .arg(Arg::with_name("watch")
+                .long("watch")
+                .help("Keep reading at this interval, e.g. 1m")
+                .takes_value(true)))
+        .subcommand(SubCommand::with_name("calibrate")
+            .about("Step a smart fan's light down through brightness levels, asking after each \
+                    whether it changed, to find the lowest level the fan accepts")
+            .arg(Arg::with_name("fan")
+                .long("fan")
+                .help("Fan state to send with each level. Defaults to the tracked state, or off.")
+                .takes_value(true))
+            .arg(Arg::with_name("start")
+                .long("start")
+                .help("First level to send")
+                .default_value("62")
+                .takes_value(true))
+            .arg(Arg::with_name("end")
+                .long("end")
+                .help("Last level to send")
+                .default_value("0")
+                .takes_value(true)))
+        .subcommand(SubCommand::with_name("probe")
+            .about("Send each dumb command code in turn, asking after each whether the fan \
+                    reacted, to map out a receiver's functions")
+            .arg(Arg::with_name("start")
+                .long("start")
+                .help("First code to send")
+                .default_value("0x00")
+                .takes_value(true))
+            .arg(Arg::with_name("end")
+                .long("end")
+                .help("Last code to send")
+                .default_value("0x7f")
+                .takes_value(true))
+            .arg(Arg::with_name("skip-known")
+                .long("skip-known")
+                .help("Skip the codes of the built in commands and the config's commands table")))
+        .subcommand(SubCommand::with_name("regserver")
+            .about("Serve the local register bus and GPIOs over TCP for --bus")
+            .arg(Arg::with_name("listen")
+                .short("l")
+                .long("listen")
+                .help(concat!("Address to listen on. Defaults to ", LISTEN_DEFAULT!()))
+                .takes_value(true)))
         .setting(AppSettings::SubcommandRequired)
 }
 
@@ -392,52 +556,177 @@ fn log_init(matches: &ArgMatches) {
     log_builder.init().unwrap();
 }
 
-fn main() {
-    let app = arg_app();
-    let matches = app.get_matches();
-    log_init(&matches);
-    let txpower = matches.value_of("txpower")
-        .map(|p| p.parse::<u8>().expect("Invalid argument for txpower"))
-        .unwrap_or(TX_POWER_DEFAULT!());
-    if txpower > 7 {
-        panic!("Requested TX power out of range.");
+/// Opens the IRQ and shutdown GPIOs given on the command line
+fn open_gpios(matches: &ArgMatches) -> Lines {
+    let shutdown = matches.value_of("shutdown")
+        .map(|p| Pin::new(p.parse::<u64>().expect("Invalid argument for shutdown")))
+        .map(|pin| Box::new(SysfsShutdown::new(pin)) as Box<dyn ShutdownLine>);
+    let irq = matches.value_of("irq")
+        .map(|p| Pin::new(p.parse::<u64>().expect("Invalid argument for irq")))
+        .map(|pin| Box::new(SysfsIrq::new(pin)) as Box<dyn IrqLine>);
+    (irq, shutdown)
+}
+
+/// Opens the register backend selected on the command line
+fn open_rfm(matches: &ArgMatches) -> Rfm22 {
+    let trace = matches.value_of("trace")
+        .map(|path| File::create(path).expect("Unable to create trace file"));
+    let wrap = |regs: Box<dyn RegRw>| -> Box<dyn RegRw> {
+        match trace {
+            Some(file) => Box::new(TraceRecorder::new(regs, file)),
+            None => regs,
+        }
+    };
+
+    if let Some(path) = matches.value_of("replay") {
+        let replay = TraceReplay::from_file(path).expect("Unable to load trace");
+        return Rfm22::dummy_regrw(wrap(Box::new(replay)));
+    }
+
+    if let Some(path) = matches.value_of("sink") {
+        let format = matches.value_of("sink-format")
+            .and_then(SinkFormat::from_name)
+            .or_else(|| SinkFormat::from_path(path))
+            .unwrap_or_else(|| {
+                clap::Error::with_description("Unknown waveform format. Use --sink-format.",
+                                              clap::ErrorKind::InvalidValue)
+                    .exit()
+            });
+        let sample_rate = matches.value_of("sample-rate")
+            .map(|rate| rate.parse::<u32>().expect("Invalid argument for sample-rate"))
+            .unwrap_or_else(|| format.default_sample_rate());
+        let file = File::create(path).expect("Unable to create waveform file");
+        let sink = WaveformSink::new(file, format, sample_rate).expect("Unable to write waveform");
+        return Rfm22::from_regrw(wrap(Box::new(sink)), None, None);
+    }
+
+    if let Some(bus) = matches.value_of("bus") {
+        if let Some(addr) = bus.strip_prefix("tcp://") {
+            let regs = RemoteRegs::connect(addr).expect("Unable to connect to regserver");
+            let (irq, shutdown) = regs.lines().expect("Unable to query regserver");
+            return Rfm22::from_regrw(wrap(Box::new(regs)), irq, shutdown);
+        } else if let Some(path) = bus.strip_prefix("serial:") {
+            let regs = SerialRegs::open(path).expect("Unable to open serial bridge");
+            let (irq, shutdown) = regs.lines().expect("Unable to query serial bridge");
+            return Rfm22::from_regrw(wrap(Box::new(regs)), irq, shutdown);
+        } else {
+            clap::Error::with_description("Unsupported bus. Expected tcp://host:port or \
+                                           serial:/dev/tty...",
+                                          clap::ErrorKind::InvalidValue)
+                .exit();
+        }
+    }
+
+    if let Some(spi) = open_spi(matches) {
+        let (irq, shutdown) = open_gpios(matches);
+        Rfm22::from_regrw(wrap(Box::new(spi)), irq, shutdown)
+    } else {
+        warn!("Using dummy backend.");
+        // Set FIFO to almost empty to we don't get stuck waiting on it
+        Rfm22::dummy_regrw(wrap(Box::new(FakeRegs::new())))
     }
+}
+
+fn open_spi(matches: &ArgMatches) -> Option<RfmRegs> {
+    let spidev_path = matches.value_of("spidev").unwrap_or(SPIDEV_DEFAULT!());
+    Spidev::open(spidev_path).ok().map(|mut spi| {
+        let options = SpidevOptions::new()
+            .max_speed_hz(10 * 1000 * 1000)
+            .build();
+        spi.configure(&options).unwrap();
+        RfmRegs::new(spi)
+    })
+}
 
+fn regserver(matches: &ArgMatches, sub: &ArgMatches) {
+    let mut server = if let Some(spi) = open_spi(matches) {
+        let (irq, shutdown) = open_gpios(matches);
+        RegServer::new(Box::new(spi), irq, shutdown)
+    } else {
+        warn!("Using emulated chip.");
+        RegServer::new(Box::new(EmuRegs::new()), None, None)
+    };
+    server.listen(sub.value_of("listen").unwrap_or(LISTEN_DEFAULT!()))
+        .expect("Register server failed");
+}
+
+fn parse_address(matches: &ArgMatches) -> u8 {
     let address = matches.value_of("address")
         .map(|p| p.parse::<u8>().expect("Invalid argument for address"))
         .unwrap();
     if address > 0xf {
         panic!("Address out of range. Must be < 0xf");
     }
+    address
+}
 
-    let pkt = if let Some(matches) = matches.subcommand_matches("dumb") {
-        let cmd = match matches.value_of("command").unwrap() {
-            "light" => FanCmd12::Light,
-            "off" => FanCmd12::FanOff,
-            "low" => FanCmd12::FanLow,
-            "medium" => FanCmd12::FanMed,
-            "high" => FanCmd12::FanHigh,
-            _ => {
-                clap::Error::with_description("Invalid fan command. Possible values: \
-                                               light|off|low|medium|high",
-                                              clap::ErrorKind::UnknownArgument)
-                    .exit();
+fn parse_fan_state(name: &str) -> FanState21 {
+    match name {
+        "off" => FanState21::Off,
+        "low" => FanState21::Low,
+        "medium" => FanState21::Med,
+        "high" => FanState21::High,
+        _ => {
+            clap::Error::with_description("Invalid fan state. Possible values: \
+                                           off|low|medium|high",
+                                          clap::ErrorKind::UnknownArgument)
+                .exit();
+        }
+    }
+}
+
+/// Parses a command code as hex with `0x`, binary with `0b` or decimal
+fn parse_code(text: &str) -> Option<u8> {
+    let code = if let Some(hex) = text.strip_prefix("0x") {
+        u8::from_str_radix(hex, 16)
+    } else if let Some(bin) = text.strip_prefix("0b") {
+        u8::from_str_radix(bin, 2)
+    } else {
+        text.parse::<u8>()
+    };
+    code.ok().filter(|&code| code < 0x80)
+}
+
+/// Builds the packet for a `dumb` or `smart` subcommand of `matches`. Dumb
+/// commands may also be named in `config`.
+fn parse_pkt(address: u8, config: &Config, matches: &ArgMatches) -> Option<FanPkt> {
+    if let Some(matches) = matches.subcommand_matches("dumb") {
+        let code = match matches.value_of("raw-cmd") {
+            Some(text) => {
+                parse_code(text).unwrap_or_else(|| {
+                    clap::Error::with_description("Invalid command code. Must be < 0x80",
+                                                  clap::ErrorKind::InvalidValue)
+                        .exit()
+                })
             }
-        };
-        FanPkt::Dumb(FanPkt12::new(address, cmd))
-    } else if let Some(matches) = matches.subcommand_matches("smart") {
-        let fan = match matches.value_of("fan").unwrap() {
-            "off" => FanState21::Off,
-            "low" => FanState21::Low,
-            "medium" => FanState21::Med,
-            "high" => FanState21::High,
-            _ => {
-                clap::Error::with_description("Invalid fan state. Possible values: \
-                                               off|low|medium|high",
-                                              clap::ErrorKind::UnknownArgument)
-                    .exit();
+            None => {
+                let name = matches.value_of("command").unwrap();
+                FanCmd12::from_name(name)
+                    .map(|cmd| cmd as u8)
+                    .or_else(|| config.commands.get(name).cloned())
+                    .filter(|&code| code < 0x80)
+                    .unwrap_or_else(|| {
+                        let mut names = FanCmd12::NAMES.iter().map(|n| n.0).collect::<Vec<_>>();
+                        names.extend(config.commands.keys().map(|name| name.as_str()));
+                        clap::Error::with_description(&format!("Invalid fan command. Possible \
+                                                                values: {}",
+                                                               names.join("|")),
+                                                      clap::ErrorKind::UnknownArgument)
+                            .exit()
+                    })
             }
         };
+        Some(FanPkt::Dumb(FanPkt12::from_code(address, code)))
+    } else if let Some(matches) = matches.subcommand_matches("smart") {
+        let fan = parse_fan_state(matches.value_of("fan").unwrap());
+        if let Some(level) = matches.value_of("level") {
+            let level = level.parse::<u8>().ok().filter(|&level| level < 64).unwrap_or_else(|| {
+                clap::Error::with_description("Brightness level out of range 0-63",
+                                              clap::ErrorKind::ValueValidation)
+                    .exit()
+            });
+            return Some(FanPkt::Smart(FanPkt21::from_level(address, level, fan)));
+        }
         let brightness = matches.value_of("brightness")
             .unwrap()
             .parse::<u8>()
@@ -454,36 +743,1324 @@ fn main() {
                                               clap::ErrorKind::InvalidValue)
                     .exit();
             });
-        FanPkt::Smart(FanPkt21::new(address, brightness, fan))
+        let level = config.brightness(address).level(brightness);
+        Some(FanPkt::Smart(FanPkt21::from_level(address, level, fan)))
     } else {
-        // Arg parser enforces subcommand requirement
-        unreachable!()
+        None
+    }
+
+}
+
+/// Prints the symbol stream of each encoding stage
+fn encode(pkt: &FanPkt,
+          radio: &Rfm22Config,
+          pwm: &PwmSymbols,
+          frames: usize,
+          matches: &ArgMatches) {
+    let format = match matches.value_of("format") {
+        Some("hex") => BitFormat::Hex,
+        Some("timing") => BitFormat::Timing,
+        _ => BitFormat::Bits,
     };
+    let symbol_us = 1000000.0 / radio.data_rate_hz;
+    let stream = pkt.repeated(pwm, frames);
+    let fifo = BitsToBytes(stream.iter().cloned()).collect::<Vec<_>>();
+    let bits = pkt.bits();
+    let stages = [("packet",
+                   format!("{} bits, {} symbols each", bits.len(), pwm.period),
+                   bits,
+                   symbol_us * pwm.period as f64),
+                  ("expanded", "start bit + packet".to_string(), pkt.expanded(pwm), symbol_us),
+                  ("frame", format!("{} symbol gap", pwm.gap), pkt.frame(pwm), symbol_us),
+                  ("stream", format!("{} frames", frames), stream, symbol_us),
+                  ("fifo", format!("{} bytes", fifo.len()), bytes_to_bits(&fifo), symbol_us)];
+    for &(name, ref desc, ref bits, symbol_us) in stages.iter() {
+        let text = format_bits(bits, format, symbol_us);
+        match matches.value_of("stage") {
+            Some(stage) if stage == name => println!("{}", text),
+            Some(_) => (),
+            None => println!("{} ({}):\n{}\n", name, desc, text),
+        }
+    }
+}
 
-    let spidev_path = matches.value_of("spidev").unwrap_or(SPIDEV_DEFAULT!());
-    let mut rf = if let Ok(mut spi) = Spidev::open(spidev_path) {
-        let shutdown = matches.value_of("shutdown")
-            .map(|p| Pin::new(p.parse::<u64>().expect("Invalid argument for shutdown")));
-        let irq = matches.value_of("irq")
-            .map(|p| Pin::new(p.parse::<u64>().expect("Invalid argument for irq")));
-        let options = SpidevOptions::new()
-            .max_speed_hz(10 * 1000 * 1000)
-            .build();
-        spi.configure(&options).unwrap();
-        Rfm22::new(spi, irq, shutdown)
+fn export(pkt: &FanPkt,
+          radio: &Rfm22Config,
+          pwm: &PwmSymbols,
+          frames: usize,
+          matches: &ArgMatches) {
+    let timings = timings(&pkt.repeated(pwm, frames), 1000000.0 / radio.data_rate_hz);
+    let freq_hz = radio.freq_mhz * 1000000.0;
+    let mut out: Box<dyn Write> = match matches.value_of("output") {
+        Some(path) => Box::new(File::create(path).expect("Unable to create output file")),
+        None => Box::new(io::stdout()),
+    };
+    match matches.value_of("format") {
+        Some("ook") => rtl433::write_ook(&mut out, freq_hz, &timings),
+        _ => SubFile::new(freq_hz.round() as u64, timings).write(&mut out),
+    }
+    .expect("Unable to write capture");
+}
+
+/// Draws a command, or the frame of a `raw` subcommand, as text or SVG
+fn plot(global: &ArgMatches,
+        config: &Config,
+        radio: &Rfm22Config,
+        pwm: &PwmSymbols,
+        matches: &ArgMatches) {
+    let frames = matches.value_of("frames")
+        .unwrap()
+        .parse::<usize>()
+        .expect("Invalid argument for frames");
+    let (symbols, annotations) = match matches.subcommand_matches("raw") {
+        Some(sub) => {
+            let bits = raw_bits(sub);
+            match sub.value_of("expand") {
+                Some("fan") => {
+                    (FanExpand::new(bits.iter().cloned(), *pwm).collect(),
+                     plot::annotate_bits(&bits, pwm))
+                }
+                _ => (bits, Vec::new()),
+            }
+        }
+        None => {
+            let pkt = parse_pkt(parse_address(global), config, matches)
+                .expect("Arg parser enforces subcommand requirement");
+            let (pwm, _) = plan(global, config, &pkt, pwm, radio);
+            let pwm = &pwm;
+            let frames = frames.min(pkt.repeats());
+            let mut symbols = pkt.frame(pwm);
+            symbols = symbols.iter().cloned().cycle().take(symbols.len() * frames).collect();
+            (symbols, plot::annotate(&pkt, pwm, frames))
+        }
+    };
+
+    match matches.value_of("svg") {
+        Some(path) => {
+            File::create(path)
+                .and_then(|mut f| {
+                    f.write_all(plot::svg(&symbols, &annotations, 1000000.0 / radio.data_rate_hz)
+                        .as_bytes())
+                })
+                .expect("Unable to write SVG");
+        }
+        None => {
+            let width = matches.value_of("width")
+                .unwrap()
+                .parse::<usize>()
+                .expect("Invalid argument for width");
+            print!("{}", plot::ascii(&symbols, &annotations, 2, width));
+        }
+    }
+}
+
+/// Loads a capture for `compare` as durations
+fn read_capture(path: &str, radio: &Rfm22Config) -> io::Result<Vec<i64>> {
+    match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+        Some("sub") => SubFile::from_file(path).map(|sub| sub.raw),
+        Some("ook") => rtl433::read_ook(BufReader::new(File::open(path)?)),
+        _ => {
+            let mut text = String::new();
+            File::open(path)?.read_to_string(&mut text)?;
+            if text.contains('+') || text.contains('-') {
+                parse_timings(&text)
+            } else {
+                parse_bits(&text).map(|bits| timings(&bits, 1000000.0 / radio.data_rate_hz))
+            }
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        }
+    }
+}
+
+fn compare(global: &ArgMatches,
+           config: &Config,
+           radio: &Rfm22Config,
+           pwm: &PwmSymbols,
+           matches: &ArgMatches) {
+    let path = matches.value_of("capture").unwrap();
+    let capture = read_capture(path, radio).expect("Unable to load capture");
+    let measured = Measurement::new(&capture).unwrap_or_else(|| {
+        clap::Error::with_description("Capture doesn't look like OOK PWM",
+                                      clap::ErrorKind::InvalidValue)
+            .exit()
+    });
+    let pkt = if matches.subcommand_name().is_some() {
+        parse_pkt(parse_address(global), config, matches).unwrap()
     } else {
-        warn!("Using dummy backend.");
-        // Set FIFO to almost empty to we don't get stuck waiting on it
-        Rfm22::dummy()
+        measured.decode().unwrap_or_else(|| {
+            clap::Error::with_description("No frame of the capture decodes. Give the command \
+                                           it should be.",
+                                          clap::ErrorKind::InvalidValue)
+                .exit()
+        })
+    };
+    let (pwm, frames) = plan(global, config, &pkt, pwm, radio);
+    let expected = Measurement::new(&timings(&pkt.repeated(&pwm, frames),
+                                             1000000.0 / radio.data_rate_hz))
+        .expect("Fan commands have short and long pulses");
+    compare::report(&mut io::stdout(), &measured, &expected, &pkt).expect("Unable to print report");
+}
+
+fn import(matches: &ArgMatches,
+          sub: &ArgMatches,
+          config: &Config,
+          capture: SubFile,
+          radio: Rfm22Config,
+          pwm: &PwmSymbols) {
+    if !capture.is_ook() {
+        clap::Error::with_description(&format!("Only OOK captures can be sent, not {}",
+                                               capture.preset),
+                                      clap::ErrorKind::InvalidValue)
+            .exit();
+    }
+    let bits = capture.to_bits(1000000.0 / radio.data_rate_hz);
+    let decoded = FanPkt::decode(&bits, pwm);
+    if let Some(pkt) = decoded.first() {
+        println!("Decoded {} ({} frames)", pkt, decoded.iter().filter(|d| *d == pkt).count());
+    } else {
+        println!("Capture is not a fan command");
+    }
+    if sub.is_present("decode-only") {
+        return;
+    }
+
+    match decoded.first() {
+        Some(pkt) if !sub.is_present("raw") => send(matches, config, pkt, pwm, radio),
+        _ => {
+            open_configured(matches, radio).transmit_bitstream(bits).unwrap();
+            if let Some(pkt) = decoded.first() {
+                record_sent(matches, config, pkt);
+            }
+        }
+    }
+}
+
+fn open_config(matches: &ArgMatches) -> Config {
+    match matches.value_of("config") {
+        Some(path) => Config::from_file(path),
+        None => Config::open_default(),
+    }
+    .expect("Unable to load config")
+}
+
+/// Timing and number of frames to send `pkt` with. Command line options
+/// override the fan's config, which overrides the global config.
+fn plan(matches: &ArgMatches,
+        config: &Config,
+        pkt: &FanPkt,
+        pwm: &PwmSymbols,
+        radio: &Rfm22Config)
+        -> (PwmSymbols, usize) {
+    let parse = |name| {
+        matches.value_of(name)
+            .map(|val: &str| {
+                val.parse::<f64>().unwrap_or_else(|_| panic!("Invalid argument for {}", name))
+            })
     };
+    let repeat = Repeat {
+        count: matches.value_of("repeat")
+            .map(|val| val.parse::<usize>().expect("Invalid argument for repeat")),
+        gap_ms: parse("gap"),
+        min_duration_ms: parse("min-duration"),
+    };
+    let repeat = repeat.or(config.repeat(pkt.addr()));
+    repeat.resolve(pkt, pwm, radio.data_rate_hz)
+}
+
+/// Releases the button for `--hold` on a line or EOF on stdin
+fn stdin_release() -> Release {
+    let release = Release::new();
+    let handle = release.clone();
+    thread::spawn(move || {
+        let mut line = String::new();
+        let _ = io::stdin().read_line(&mut line);
+        handle.release();
+    });
+    release
+}
+
+/// Sends a command as planned, or held with `--hold`, and records it
+fn send(matches: &ArgMatches,
+        config: &Config,
+        pkt: &FanPkt,
+        pwm: &PwmSymbols,
+        radio: Rfm22Config) {
+    let (pwm, frames) = plan(matches, config, pkt, pwm, &radio);
+    let mut rf = open_configured(matches, radio);
+    if matches.is_present("hold") {
+        info!("Holding {}. Press Enter to release.", pkt);
+        let release = stdin_release();
+        let sent = pkt.hold(&mut rf, &pwm, frames, || release.is_released()).unwrap();
+        info!("Sent {} frames", sent);
+    } else {
+        pkt.transmit(&mut rf, &pwm, frames).unwrap();
+    }
+    record_sent(matches, config, pkt);
+}
+
+/// How often a running fade checks whether it was cancelled
+const FADE_POLL_MS: u64 = 500;
+
+/// Sends commands to the radio, or for a dry run prints how they'd be
+/// encoded instead
+enum Sender {
+    Radio(Box<Rfm22>),
+    DryRun,
+}
+
+impl Sender {
+    fn open(matches: &ArgMatches, radio: &Rfm22Config, dry_run: bool) -> Self {
+        if dry_run {
+            Sender::DryRun
+        } else {
+            Sender::Radio(Box::new(open_configured(matches, radio.clone())))
+        }
+    }
 
-    rf.init();
-    rf.set_modulation_type_and_source(ModulationType::OOK, DataSource::FIFO).unwrap();
-    rf.regs.write_validate(DataAccessControl::empty()).unwrap();
-    // HeaderControl2
-    rf.regs.write_validate(SKIPSYN).unwrap();
-    rf.set_freq_mhz(303.8).unwrap();
-    rf.set_data_rate_hz(3000.0).unwrap();
-    rf.set_tx_power(txpower).unwrap();
-    pkt.transmit(&mut rf);
+    /// The radio's temperature. A dry run has no radio to read.
+    fn read_temperature(&mut self) -> io::Result<f64> {
+        match *self {
+            Sender::Radio(ref mut rf) => rf.read_temperature(),
+            Sender::DryRun => {
+                Err(io::Error::new(io::ErrorKind::NotConnected,
+                                   "No radio to read the temperature of in a dry run"))
+            }
+        }
+    }
+
+    fn send(&mut self, pkt: &FanPkt, pwm: &PwmSymbols, frames: usize) {
+        match *self {
+            Sender::Radio(ref mut rf) => pkt.transmit(rf, pwm, frames).unwrap(),
+            Sender::DryRun => {
+                println!("{}: {} frames of {}",
+                         pkt,
+                         frames,
+                         format_bits(&pkt.frame(pwm), BitFormat::Hex, 0.0))
+            }
+        }
+    }
+
+    /// Sends a command as planned and records it, unless it's a dry run
+    fn send_planned(&mut self,
+                    matches: &ArgMatches,
+                    config: &Config,
+                    pkt: &FanPkt,
+                    pwm: &PwmSymbols,
+                    radio: &Rfm22Config) {
+        let (pwm, frames) = plan(matches, config, pkt, pwm, radio);
+        self.send(pkt, &pwm, frames);
+        if let Sender::Radio(_) = *self {
+            record_sent(matches, config, pkt);
+        }
+    }
+}
+
+/// A fade being sent a step at a time. Tracked fades are noted in the state
+/// store, and end if another command or `fade --cancel` removes them.
+struct ActiveFade {
+    address: u8,
+    id: u32,
+    fan: FanState21,
+    pwm: PwmSymbols,
+    frames: usize,
+    steps: VecDeque<fade::Step>,
+    start: Instant,
+    /// Time to send a step. Steps start this early to land on time.
+    airtime: Duration,
+    tracked: bool,
+}
+
+/// Where a fade goes. `from` and `fan` default to the tracked state.
+struct FadeTarget {
+    from: Option<f64>,
+    to: f64,
+    over_ms: f64,
+    fan: Option<FanState21>,
+}
+
+impl ActiveFade {
+    fn new(matches: &ArgMatches,
+           address: u8,
+           config: &Config,
+           pwm: &PwmSymbols,
+           radio: &Rfm22Config,
+           target: FadeTarget,
+           tracked: bool)
+           -> Self {
+        let FadeTarget { from, to, over_ms, fan } = target;
+        let status = open_state(matches).fans.get(&address).cloned().unwrap_or_default();
+        let from = from.or(status.brightness).unwrap_or(0.0);
+        let fan = fan.or(status.fan).unwrap_or(FanState21::Off);
+        let (pwm, frames) = plan(matches,
+                                 config,
+                                 &FanPkt::Smart(FanPkt21::from_level(address, 0, fan)),
+                                 pwm,
+                                 radio);
+        let airtime_ms = FanPkt::Smart(FanPkt21::from_level(address, 0, fan))
+            .repeated(&pwm, frames)
+            .len() as f64 * 1000.0 / radio.data_rate_hz;
+        let steps = fade::schedule(&config.brightness(address), from, to, over_ms, airtime_ms);
+        info!("Fading fan {} from {:.0}% to {:.0}% in {} steps",
+              address,
+              from * 100.0,
+              to * 100.0,
+              steps.len());
+
+        let id = process::id();
+        if tracked {
+            let mut store = open_state(matches);
+            store.start_fade(address,
+                             FadeStatus {
+                                 id,
+                                 to,
+                                 until: state::now() + (over_ms / 1000.0).ceil() as u64,
+                             });
+            store.save().expect("Unable to save state");
+        }
+        ActiveFade {
+            address,
+            id,
+            fan,
+            pwm,
+            frames,
+            steps: steps.into_iter().collect(),
+            start: Instant::now(),
+            airtime: Duration::from_millis(airtime_ms as u64),
+            tracked,
+        }
+    }
+
+    /// When to send the next step, or `None` once they're all sent
+    fn next_at(&self) -> Option<Instant> {
+        self.steps
+            .front()
+            .map(|step| {
+                self.start + Duration::from_millis(step.at_ms as u64).saturating_sub(self.airtime)
+            })
+    }
+
+    fn running(&self, matches: &ArgMatches) -> bool {
+        !self.tracked || open_state(matches).fading(self.address, self.id)
+    }
+
+    fn send_next(&mut self, matches: &ArgMatches, sender: &mut Sender) {
+        let step = match self.steps.pop_front() {
+            Some(step) => step,
+            None => return,
+        };
+        let pkt = FanPkt21::from_level(self.address, step.level, self.fan);
+        sender.send(&FanPkt::Smart(pkt.clone()), &self.pwm, self.frames);
+        if self.tracked {
+            let mut store = open_state(matches);
+            if store.record_fade(self.id, &pkt, step.brightness, "fanrf") {
+                save_state(&store);
+            }
+        }
+    }
+
+    fn finish(&self, matches: &ArgMatches) {
+        if self.tracked {
+            let mut store = open_state(matches);
+            store.end_fade(self.address, Some(self.id));
+            save_state(&store);
+        }
+    }
+}
+
+/// Sends the steps of a fade, stopping if another command or `--cancel` ends
+/// it in the state store. Tracks the brightness as it goes.
+fn fade(matches: &ArgMatches,
+        sub: &ArgMatches,
+        address: u8,
+        config: &Config,
+        pwm: &PwmSymbols,
+        radio: Rfm22Config) {
+    if sub.is_present("cancel") {
+        let mut store = open_state(matches);
+        match store.end_fade(address, None) {
+            Some(fade) => {
+                store.save().expect("Unable to save state");
+                info!("Cancelled fade to {:.0}% by process {}", fade.to * 100.0, fade.id);
+            }
+            None => warn!("No fade running on fan {}", address),
+        }
+        return;
+    }
+    let percent = |name| {
+        sub.value_of(name).map(|val: &str| {
+            val.parse::<f64>()
+                .ok()
+                .filter(|pct| (0.0..=100.0).contains(pct))
+                .map(|pct| pct / 100.0)
+                .unwrap_or_else(|| {
+                    clap::Error::with_description(&format!("Invalid {} brightness. Must be \
+                                                            0-100",
+                                                           name),
+                                                  clap::ErrorKind::ValueValidation)
+                        .exit()
+                })
+        })
+    };
+    let to = percent("to").unwrap();
+    let over_ms = fade::parse_duration(sub.value_of("over").unwrap()).unwrap_or_else(|| {
+        clap::Error::with_description("Invalid duration for over", clap::ErrorKind::InvalidValue)
+            .exit()
+    });
+    if sub.is_present("daemon") {
+        return daemonize();
+    }
+
+    let mut fade = ActiveFade::new(matches,
+                                   address,
+                                   config,
+                                   pwm,
+                                   &radio,
+                                   FadeTarget {
+                                       from: percent("from"),
+                                       to,
+                                       over_ms,
+                                       fan: sub.value_of("fan").map(parse_fan_state),
+                                   },
+                                   true);
+    let mut sender = Sender::open(matches, &radio, false);
+    while let Some(at) = fade.next_at() {
+        if !fade.running(matches) {
+            info!("Fade cancelled");
+            return;
+        }
+        let now = Instant::now();
+        if now < at {
+            thread::sleep((at - now).min(Duration::from_millis(FADE_POLL_MS)));
+        } else {
+            fade.send_next(matches, &mut sender);
+        }
+    }
+    fade.finish(matches);
+}
+
+/// Runs the rest of the command line again as a background process
+fn daemonize() {
+    let args = env::args_os().skip(1).filter(|arg| arg != "--daemon");
+    // Left running when we exit
+    #[allow(clippy::zombie_processes)]
+    let child = Command::new(env::current_exe().expect("Unable to find fanrf"))
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .spawn()
+        .expect("Unable to start background process");
+    println!("Running in process {}", child.id());
+}
+
+/// How often `timer run` checks the state store for new or expired timers
+const TIMER_POLL_MS: u64 = 1000;
+
+fn format_remaining(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m {}s", secs / 60, secs % 60),
+        _ => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+fn timer(matches: &ArgMatches,
+         sub: &ArgMatches,
+         config: &Config,
+         pwm: &PwmSymbols,
+         radio: Rfm22Config) {
+    match sub.subcommand() {
+        ("set", Some(set)) => {
+            let address = parse_address(matches);
+            let curve = config.brightness(address);
+            let scene = match set.value_of("scene") {
+                Some(name) => {
+                    config.scenes.get(name).cloned().unwrap_or_else(|| {
+                        clap::Error::with_description(&format!("No scene named {} in the config",
+                                                               name),
+                                                      clap::ErrorKind::InvalidValue)
+                            .exit()
+                    })
+                }
+                None => {
+                    let pkt = parse_pkt(address, config, set).unwrap_or_else(|| {
+                        clap::Error::with_description("Give a scene or the command to send",
+                                                      clap::ErrorKind::MissingRequiredArgument)
+                            .exit()
+                    });
+                    Scene::from_pkt(&pkt, &curve)
+                }
+            };
+            let secs = fade::parse_duration(set.value_of("in").unwrap()).unwrap_or_else(|| {
+                clap::Error::with_description("Invalid duration for in",
+                                              clap::ErrorKind::InvalidValue)
+                    .exit()
+            }) / 1000.0;
+            info!("Fan {} will be sent {} in {}",
+                  address,
+                  scene.pkt(address, &curve),
+                  format_remaining(secs.round() as u64));
+            let mut store = open_state(matches);
+            store.set_timer(address,
+                            Timer {
+                                at: state::now() + secs.round() as u64,
+                                scene,
+                            });
+            store.save().expect("Unable to save state");
+        }
+        ("list", _) => {
+            let now = state::now();
+            let store = open_state(matches);
+            for (address, timer) in store.timers() {
+                println!("{}\t{}\t{}",
+                         address,
+                         format_remaining(timer.at.saturating_sub(now)),
+                         timer.scene.pkt(address, &config.brightness(address)));
+            }
+        }
+        ("cancel", _) => {
+            let address = parse_address(matches);
+            let mut store = open_state(matches);
+            match store.cancel_timer(address) {
+                Some(_) => store.save().expect("Unable to save state"),
+                None => warn!("No timer set on fan {}", address),
+            }
+        }
+        ("run", Some(run)) => {
+            if run.is_present("daemon") {
+                return daemonize();
+            }
+            run_daemon(matches, config, pwm, &radio, run.is_present("dry-run"));
+        }
+        _ => unreachable!("Arg parser enforces subcommand requirement"),
+    }
+}
+
+fn schedule(matches: &ArgMatches,
+            sub: &ArgMatches,
+            config: &Config,
+            pwm: &PwmSymbols,
+            radio: Rfm22Config) {
+    let schedule = &config.schedule;
+    let invalid = |e: String| -> ! {
+        clap::Error::with_description(&e, clap::ErrorKind::InvalidValue).exit()
+    };
+    match sub.subcommand() {
+        ("list", _) => {
+            let checked = config.check_schedule().unwrap_or_else(|e| invalid(e));
+            for (entry, (trigger, _)) in schedule.entries.iter().zip(&checked) {
+                let next = trigger.next_after(state::now(), schedule)
+                    .map_or("never".to_string(), |at| {
+                        schedule::format_local(at, schedule.utc_offset_min)
+                    });
+                println!("{}\t{}\t{}\t{}\t{}",
+                         entry.name,
+                         entry.fan,
+                         entry.when,
+                         next,
+                         entry.action);
+            }
+        }
+        ("next", Some(next)) => {
+            let count = next.value_of("count")
+                .unwrap()
+                .parse::<usize>()
+                .expect("Invalid argument for count");
+            config.check_schedule().unwrap_or_else(|e| invalid(e));
+            for (at, idx) in schedule.upcoming(state::now(), count).unwrap_or_else(|e| invalid(e)) {
+                let entry = &schedule.entries[idx];
+                println!("{}\t{}\t{}\t{}",
+                         schedule::format_local(at, schedule.utc_offset_min),
+                         entry.name,
+                         entry.fan,
+                         entry.action);
+            }
+        }
+        ("run", Some(run)) => {
+            if run.is_present("daemon") {
+                return daemonize();
+            }
+            run_daemon(matches, config, pwm, &radio, run.is_present("dry-run"));
+        }
+        _ => unreachable!("Arg parser enforces subcommand requirement"),
+    }
+}
+
+/// How long `auto read` waits for a first MQTT message
+const MQTT_WAIT_MS: u64 = 5000;
+
+/// An automation being run. The sensor is reopened after an error, e.g. to
+/// reconnect to an MQTT broker.
+struct ActiveAutomation {
+    address: u8,
+    auto: auto::Automation,
+    sensor: Option<Sensor>,
+    control: Controller,
+    interval: Duration,
+    next_at: Instant,
+}
+
+impl ActiveAutomation {
+    fn new(matches: &ArgMatches, address: u8, auto: &auto::Automation) -> Self {
+        let speed = open_state(matches).fans.get(&address).and_then(|status| status.fan);
+        ActiveAutomation {
+            address,
+            auto: auto.clone(),
+            sensor: None,
+            control: Controller::new(auto.bands.clone(), speed),
+            interval: Duration::from_millis(fade::parse_duration(&auto.interval).unwrap() as u64),
+            next_at: Instant::now(),
+        }
+    }
+
+    /// Reads the temperature, or `None` if there's no reading yet
+    fn read(&mut self, sender: &mut Sender) -> io::Result<Option<f64>> {
+        if self.sensor.is_none() {
+            let client_id = format!("fanrf-{}-{}", process::id(), self.address);
+            self.sensor = Some(Sensor::open(&self.auto.source, &client_id)?);
+        }
+        let temp = self.sensor.as_mut().unwrap().read(|| sender.read_temperature());
+        if temp.is_err() {
+            self.sensor = None;
+        }
+        temp
+    }
+
+    /// The command setting the fan to `speed`. Smart commands keep the
+    /// light's tracked brightness.
+    fn pkt(&self, matches: &ArgMatches, config: &Config, speed: FanState21) -> FanPkt {
+        if self.auto.smart {
+            let brightness = open_state(matches)
+                .fans
+                .get(&self.address)
+                .and_then(|status| status.brightness)
+                .unwrap_or(0.0);
+            let level = config.brightness(self.address).level(brightness);
+            FanPkt::Smart(FanPkt21::from_level(self.address, level, speed))
+        } else {
+            let cmd = match speed {
+                FanState21::Off => FanCmd12::FanOff,
+                FanState21::Low => FanCmd12::FanLow,
+                FanState21::Med => FanCmd12::FanMed,
+                FanState21::High => FanCmd12::FanHigh,
+            };
+            FanPkt::Dumb(FanPkt12::new(self.address, cmd))
+        }
+    }
+
+    /// Reads the temperature if it's time to and sends a command if the
+    /// speed changes
+    fn poll(&mut self,
+            matches: &ArgMatches,
+            config: &Config,
+            pwm: &PwmSymbols,
+            radio: &Rfm22Config,
+            sender: &mut Sender) {
+        if self.next_at > Instant::now() {
+            return;
+        }
+        self.next_at += self.interval;
+        let temp = match self.read(sender) {
+            Ok(Some(temp)) => temp,
+            Ok(None) => return,
+            Err(e) => return warn!("Unable to read temperature for {}: {}", self.auto.fan, e),
+        };
+        debug!("Fan {} at {:.1}°C", self.auto.fan, temp);
+        if let Some(speed) = self.control.update(temp) {
+            info!("{:.1}°C sets fan {} to {:?}", temp, self.auto.fan, speed);
+            sender.send_planned(matches, config, &self.pkt(matches, config, speed), pwm, radio);
+        }
+    }
+}
+
+fn auto(matches: &ArgMatches,
+        sub: &ArgMatches,
+        config: &Config,
+        pwm: &PwmSymbols,
+        radio: Rfm22Config) {
+    let addresses = config.check_automations().unwrap_or_else(|e| {
+        clap::Error::with_description(&e, clap::ErrorKind::InvalidValue).exit()
+    });
+    match sub.subcommand() {
+        ("read", _) => {
+            // Only opened if an automation reads the chip
+            let mut rf: Option<Rfm22> = None;
+            for (auto, &address) in config.automations.iter().zip(&addresses) {
+                let client_id = format!("fanrf-{}", process::id());
+                let start = Instant::now();
+                let temp = Sensor::open(&auto.source, &client_id).and_then(|mut sensor| {
+                    loop {
+                        let temp = sensor.read(|| {
+                            rf.get_or_insert_with(|| open_configured(matches, radio.clone()))
+                                .read_temperature()
+                        })?;
+                        if temp.is_some() ||
+                           start.elapsed() > Duration::from_millis(MQTT_WAIT_MS) {
+                            return Ok(temp);
+                        }
+                        thread::sleep(Duration::from_millis(100));
+                    }
+                });
+                let speed = open_state(matches).fans.get(&address).and_then(|status| status.fan);
+                match temp {
+                    Ok(Some(temp)) => {
+                        println!("{}\t{:.1}°C\t{:?}",
+                                 auto.fan,
+                                 temp,
+                                 auto.bands.speed(temp, speed))
+                    }
+                    Ok(None) => println!("{}\tno reading", auto.fan),
+                    Err(e) => println!("{}\t{}", auto.fan, e),
+                }
+            }
+        }
+        ("run", Some(run)) => {
+            if run.is_present("daemon") {
+                return daemonize();
+            }
+            run_daemon(matches, config, pwm, &radio, run.is_present("dry-run"));
+        }
+        _ => unreachable!("Arg parser enforces subcommand requirement"),
+    }
+}
+
+/// Sends timers, scheduled actions and the fades they start as they come
+/// due, and fan speeds from the automations. A dry run prints what would be
+/// sent and leaves timers and the state store alone. Scheduled actions missed
+/// while not running are skipped.
+fn run_daemon(matches: &ArgMatches,
+              config: &Config,
+              pwm: &PwmSymbols,
+              radio: &Rfm22Config,
+              dry_run: bool) {
+    let (triggers, tasks): (Vec<_>, Vec<_>) = config.check_schedule()
+        .unwrap_or_else(|e| {
+            clap::Error::with_description(&e, clap::ErrorKind::InvalidValue).exit()
+        })
+        .into_iter()
+        .unzip();
+    let addresses = config.check_automations().unwrap_or_else(|e| {
+        clap::Error::with_description(&e, clap::ErrorKind::InvalidValue).exit()
+    });
+    let mut automations = config.automations
+        .iter()
+        .zip(addresses)
+        .map(|(auto, address)| ActiveAutomation::new(matches, address, auto))
+        .collect::<Vec<_>>();
+    let schedule = &config.schedule;
+    let mut next = triggers.iter()
+        .map(|trigger| trigger.next_after(state::now(), schedule))
+        .collect::<Vec<_>>();
+    let mut sender = Sender::open(matches, radio, dry_run);
+    let mut fades: Vec<ActiveFade> = Vec::new();
+    // Timers a dry run has shown
+    let mut shown = Vec::new();
+    loop {
+        let now = state::now();
+        // Dropped before sending, which records what was sent
+        let mut store = open_state(matches);
+        let due = if dry_run {
+            let due = store.timers()
+                .into_iter()
+                .filter(|&(_, timer)| timer.at <= now)
+                .map(|(address, timer)| (address, timer.clone()))
+                .filter(|due| !shown.contains(due))
+                .collect::<Vec<_>>();
+            shown.extend(due.iter().cloned());
+            due
+        } else {
+            let due = store.take_due(now);
+            if !due.is_empty() {
+                // Saved before sending so a restart doesn't send them twice
+                store.save().expect("Unable to save state");
+            }
+            due
+        };
+        drop(store);
+        for (address, timer) in due {
+            let pkt = timer.scene.pkt(address, &config.brightness(address));
+            info!("Timer sending {}", pkt);
+            sender.send_planned(matches, config, &pkt, pwm, radio);
+        }
+
+        for (idx, entry) in schedule.entries.iter().enumerate() {
+            let at = match next[idx] {
+                Some(at) if at <= now => at,
+                _ => continue,
+            };
+            next[idx] = triggers[idx].next_after(at, schedule);
+            info!("Running '{}' on fan {}: {}", entry.name, entry.fan, entry.action);
+            match tasks[idx] {
+                Task::Send(ref scene) => {
+                    let pkt = scene.pkt(entry.fan, &config.brightness(entry.fan));
+                    sender.send_planned(matches, config, &pkt, pwm, radio)
+                }
+                Task::Fade { to, over_ms } => {
+                    fades.retain(|fade| fade.address != entry.fan);
+                    fades.push(ActiveFade::new(matches,
+                                               entry.fan,
+                                               config,
+                                               pwm,
+                                               radio,
+                                               FadeTarget {
+                                                   from: None,
+                                                   to,
+                                                   over_ms,
+                                                   fan: None,
+                                               },
+                                               !dry_run));
+                }
+            }
+        }
+
+        for auto in &mut automations {
+            auto.poll(matches, config, pwm, radio, &mut sender);
+        }
+
+        fades.retain(|fade| fade.running(matches));
+        for fade in &mut fades {
+            while fade.next_at().is_some_and(|at| at <= Instant::now()) {
+                fade.send_next(matches, &mut sender);
+            }
+            if fade.next_at().is_none() {
+                fade.finish(matches);
+            }
+        }
+        fades.retain(|fade| fade.next_at().is_some());
+
+        let wake = Instant::now() + Duration::from_millis(TIMER_POLL_MS);
+        let wake = fades.iter().filter_map(|fade| fade.next_at()).fold(wake, |a, b| a.min(b));
+        let wake = automations.iter().map(|auto| auto.next_at).fold(wake, |a, b| a.min(b));
+        thread::sleep(wake.saturating_duration_since(Instant::now()));
+    }
+}
+
+/// Prints the radio's temperature and supply voltage, flagging a low battery
+/// or overheating board, and how often the radio has needed its config
+/// restoring
+fn sensors(matches: &ArgMatches, sub: &ArgMatches, radio: Rfm22Config) {
+    let parse = |name| {
+        sub.value_of(name).map(|text| {
+            text.parse::<f64>().unwrap_or_else(|_| {
+                clap::Error::with_description(&format!("Invalid number for {}", name),
+                                              clap::ErrorKind::InvalidValue)
+                    .exit()
+            })
+        })
+    };
+    let max_temp = parse("max-temp");
+    let watch = sub.value_of("watch").map(|text| {
+        fade::parse_duration(text).filter(|&ms| ms > 0.0).unwrap_or_else(|| {
+            clap::Error::with_description("Invalid duration for watch",
+                                          clap::ErrorKind::InvalidValue)
+                .exit()
+        })
+    });
+    let mut rf = open_configured(matches, radio);
+    rf.set_low_battery(parse("low-battery")).unwrap();
+    loop {
+        // Readings from a chip that has reset are of the wrong settings
+        rf.check_config().unwrap();
+        let temp = rf.read_temperature().unwrap();
+        let volts = rf.read_supply_voltage().unwrap();
+        let low_battery = rf.low_battery().unwrap();
+        let overheating = max_temp.is_some_and(|max| temp > max);
+        print!("{:.1}°C\t{:.2}V\t{} recoveries", temp, volts, rf.recoveries());
+        if low_battery {
+            print!("\tlow battery");
+            warn!("Supply below {:.2}V", rf.low_battery_threshold().unwrap());
+        }
+        if overheating {
+            print!("\toverheating");
+            warn!("Board above {:.1}°C", max_temp.unwrap());
+        }
+        println!();
+        match watch {
+            Some(ms) => thread::sleep(Duration::from_millis(ms as u64)),
+            None if low_battery || overheating => process::exit(1),
+            None => return,
+        }
+    }
+}
+
+/// Finds the lowest brightness level a smart fan accepts and prints it as a
+/// brightness curve for the config
+fn calibrate(matches: &ArgMatches,
+             sub: &ArgMatches,
+             address: u8,
+             config: &Config,
+             pwm: &PwmSymbols,
+             radio: Rfm22Config) {
+    let parse = |name| {
+        sub.value_of(name)
+            .unwrap()
+            .parse::<u8>()
+            .ok()
+            .filter(|&level| level < 63)
+            .unwrap_or_else(|| {
+                clap::Error::with_description(&format!("Invalid level for {}. Must be < 63",
+                                                       name),
+                                              clap::ErrorKind::InvalidValue)
+                    .exit()
+            })
+    };
+    let fan = match sub.value_of("fan") {
+        Some(name) => parse_fan_state(name),
+        None => {
+            open_state(matches).fans.get(&address).and_then(|s| s.fan).unwrap_or(FanState21::Off)
+        }
+    };
+    let levels = (parse("end")..=parse("start")).rev().collect::<Vec<_>>();
+    let pkt = |level| FanPkt::Smart(FanPkt21::from_level(address, level, fan));
+    let (pwm, frames) = plan(matches, config, &pkt(0), pwm, &radio);
+    let mut rf = open_configured(matches, radio);
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let lowest = probe::lowest_level(&levels, stdin.lock(), &mut stdout.lock(), |level| {
+            pkt(level).transmit(&mut rf, &pwm, frames)
+        })
+        .expect("Calibration failed");
+    match lowest {
+        Some(level) => {
+            record_sent(matches, config, &pkt(level));
+            println!("Lowest level accepted: {}. Add to the config:\n\
+                      \"fans\": {{\"{}\": {{\"brightness\": {{\"min\": {}}}}}}}",
+                     level,
+                     address,
+                     level);
+        }
+        None => println!("The light didn't change for any level"),
+    }
+}
+
+/// Steps through dumb command codes and prints the ones the fan reacted to as
+/// a `commands` table for the config
+fn probe(matches: &ArgMatches,
+         sub: &ArgMatches,
+         address: u8,
+         config: &Config,
+         pwm: &PwmSymbols,
+         radio: Rfm22Config) {
+    let parse = |name| {
+        parse_code(sub.value_of(name).unwrap()).unwrap_or_else(|| {
+            clap::Error::with_description(&format!("Invalid code for {}. Must be < 0x80", name),
+                                          clap::ErrorKind::InvalidValue)
+                .exit()
+        })
+    };
+    let known = |code: u8| {
+        FanCmd12::from_code(code).is_some() || config.commands.values().any(|&c| c == code)
+    };
+    let codes = (parse("start")..=parse("end"))
+        .filter(|&code| !(sub.is_present("skip-known") && known(code)))
+        .collect::<Vec<_>>();
+    // Every code has the same frame length so this plan fits them all
+    let (pwm, frames) = plan(matches,
+                             config,
+                             &FanPkt::Dumb(FanPkt12::from_code(address, 0)),
+                             pwm,
+                             &radio);
+    let mut rf = open_configured(matches, radio);
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let found = probe::probe(&codes, stdin.lock(), &mut stdout.lock(), |code| {
+            FanPkt::Dumb(FanPkt12::from_code(address, code)).transmit(&mut rf, &pwm, frames)
+        })
+        .expect("Probe failed");
+    if found.is_empty() {
+        println!("The fan didn't react to any code");
+    } else {
+        println!("Add to the config:\n\"commands\": {}",
+                 serde_json::to_string_pretty(&found).unwrap());
+    }
+}
+
+/// Holds Light on a dumb fan for a time, or long enough to reach a level
+fn dim(matches: &ArgMatches,
+       sub: &ArgMatches,
+       config: &Config,
+       pwm: &PwmSymbols,
+       radio: Rfm22Config) {
+    let address = parse_address(matches);
+    let pkt = FanPkt::Dumb(FanPkt12::new(address, FanCmd12::Light));
+    let parse = |name| {
+        sub.value_of(name).map(|val: &str| {
+            val.parse::<f64>().unwrap_or_else(|_| {
+                clap::Error::with_description(&format!("Invalid number for --{}", name),
+                                              clap::ErrorKind::InvalidValue)
+                    .exit()
+            })
+        })
+    };
+    let status = open_state(matches).fans.get(&address).cloned().unwrap_or_default();
+    let up = sub.value_of("direction").map(|dir| dir == "up").or(status.next_dim_up());
+    let (duration_ms, level) = match parse("to") {
+        Some(to) => {
+            let sweep_ms = parse("sweep")
+                .or_else(|| config.fans.get(&address).and_then(|f| f.dim_sweep_ms))
+                .unwrap_or_else(|| {
+                    clap::Error::with_description(&format!("Set dim_sweep_ms for fan {} in the \
+                                                            config, or --sweep",
+                                                           address),
+                                                  clap::ErrorKind::MissingRequiredArgument)
+                        .exit()
+                });
+            let from = parse("from")
+                .map(|pct| pct / 100.0)
+                .or(status.brightness)
+                .unwrap_or(1.0);
+            let hold = state::dim_hold(from, to / 100.0, sweep_ms, up).unwrap_or_else(|e| {
+                clap::Error::with_description(&e, clap::ErrorKind::InvalidValue).exit()
+            });
+            if up.is_none() {
+                warn!("Not known which way fan {} will go, so its brightness won't be tracked. \
+                       Give --direction to start tracking it.",
+                      address);
+            }
+            (hold.duration_ms, hold.level)
+        }
+        None => (parse("for").unwrap(), None),
+    };
+
+    let (pwm, _) = plan(matches, config, &pkt, pwm, &radio);
+    let frame_ms = pkt.frame(&pwm).len() as f64 * 1000.0 / radio.data_rate_hz;
+    let frames = ((duration_ms / frame_ms).ceil() as usize).max(1);
+    info!("Holding Light on fan {} for {} frames ({:.0} ms)", address, frames, duration_ms);
+    let mut rf = open_configured(matches, radio);
+    pkt.transmit(&mut rf, &pwm, frames).unwrap();
+
+    let mut store = open_state(matches);
+    store.record_dim(address, level, up, "fanrf");
+    if let Err(e) = store.save() {
+        warn!("Unable to save state: {}", e);
+    }
+}
+
+fn state_path(matches: &ArgMatches) -> PathBuf {
+    matches.value_of("state").map(PathBuf::from).unwrap_or_else(StateStore::default_path)
+}
+
+/// Opens the state store. Drop it before anything else opens it, such as
+/// `record_sent`, or that will wait forever.
+fn open_state(matches: &ArgMatches) -> StateStore {
+    StateStore::open(state_path(matches)).expect("Unable to load state store")
+}
+
+/// Saves the state store. Failing to is not fatal.
+fn save_state(store: &StateStore) {
+    if let Err(e) = store.save() {
+        warn!("Unable to save state: {}", e);
+    }
+}
+
+/// Notes a command we sent in the state store
+fn record_sent(matches: &ArgMatches, config: &Config, pkt: &FanPkt) {
+    let mut store = open_state(matches);
+    store.record(pkt, &config.brightness(pkt.addr()), "fanrf");
+    save_state(&store);
+}
+
+fn ingest(matches: &ArgMatches, sub: &ArgMatches, config: &Config) {
+    let state = state_path(matches);
+    let count = match sub.value_of("file") {
+        Some(path) => {
+            rtl433::ingest(BufReader::new(File::open(path).expect("Unable to open events")),
+                           &state,
+                           config)
+        }
+        None => {
+            let stdin = io::stdin();
+            let lock = stdin.lock();
+            rtl433::ingest(lock, &state, config)
+        }
+    }
+    .expect("Unable to ingest events");
+    info!("Recorded {} commands", count);
+}
+
+/// Reads the bits of a `raw` style subcommand, before any expansion
+fn raw_bits(matches: &ArgMatches) -> Vec<bool> {
+    let text = match matches.value_of("file") {
+        Some(path) => {
+            let mut text = String::new();
+            File::open(path)
+                .and_then(|mut f| f.read_to_string(&mut text))
+                .expect("Unable to read bitstream file");
+            text
+        }
+        None => matches.value_of("bits").unwrap().to_string(),
+    };
+    parse_bits(&text).unwrap_or_else(|e| {
+        clap::Error::with_description(&e, clap::ErrorKind::InvalidValue).exit()
+    })
+}
+
+/// Builds the symbol stream for the `raw` subcommand
+fn raw_bitstream(matches: &ArgMatches, radio: &Rfm22Config, pwm: &PwmSymbols) -> Vec<bool> {
+    let bits = raw_bits(matches);
+    let frame = match matches.value_of("expand") {
+        Some("fan") => FanExpand::new(bits.into_iter(), *pwm).collect(),
+        _ => bits,
+    };
+    let copies = matches.value_of("copies")
+        .unwrap()
+        .parse::<usize>()
+        .expect("Invalid argument for copies");
+    let gap_ms = matches.value_of("copy-gap")
+        .unwrap()
+        .parse::<f64>()
+        .expect("Invalid argument for copy-gap");
+    let gap_symbols = (gap_ms * radio.data_rate_hz / 1000.0).round() as usize;
+    frame_stream(&frame, gap_symbols, copies)
+}
+
+/// Opens the radio and applies the register options and radio config
+fn open_configured(matches: &ArgMatches, radio: Rfm22Config) -> Rfm22 {
+    let mut rf = open_rfm(matches);
+    rf.regs.set_cache(matches.is_present("reg-cache"));
+    rf.regs.set_verify_policy(match matches.value_of("verify") {
+        Some("change") => VerifyPolicy::OnChange,
+        Some("never") => VerifyPolicy::Never,
+        _ => VerifyPolicy::Always,
+    });
+    rf.configure(radio).unwrap();
+    rf
+}
+
+/// Parses `PIN=FUNCTION`, with the function named as in radio configs
+fn parse_radio_gpio(text: &str) -> Option<(usize, GpioFunction)> {
+    let mut parts = text.splitn(2, '=');
+    let pin = parts.next()?.trim().parse().ok()?;
+    let function = serde_json::from_value(serde_json::Value::from(parts.next()?.trim())).ok()?;
+    Some((pin, function))
+}
+
+fn main() {
+    let app = arg_app();
+    let matches = app.get_matches();
+    log_init(&matches);
+    if let Some(sub) = matches.subcommand_matches("regserver") {
+        return regserver(&matches, sub);
+    }
+    let mut radio = Rfm22ConfigBuilder::new(matches.value_of("radio-config")
+        .map(|path| Rfm22Config::from_file(path).expect("Unable to load radio config"))
+        .unwrap_or_default());
+    if let Some(txpower) = matches.value_of("txpower") {
+        radio = radio.tx_power(txpower.parse::<u8>().expect("Invalid argument for txpower"));
+    }
+    for text in matches.values_of("radio-gpio").into_iter().flatten() {
+        let (pin, function) = parse_radio_gpio(text).unwrap_or_else(|| {
+            clap::Error::with_description(&format!("Invalid radio GPIO '{}'. Expected \
+                                                    PIN=FUNCTION",
+                                                   text),
+                                          clap::ErrorKind::InvalidValue)
+                .exit()
+        });
+        radio = radio.gpio(pin, function);
+    }
+    let pwm = match matches.value_of("pwm") {
+        Some(text) => {
+            let (pwm, rate) = PwmTiming::parse(text)
+                .and_then(|timing| timing.oversample())
+                .unwrap_or_else(|e| {
+                    clap::Error::with_description(&e, clap::ErrorKind::InvalidValue).exit()
+                });
+            radio = radio.data_rate_hz(rate);
+            pwm
+        }
+        None => PwmSymbols::FAN,
+    };
+    if let Some(sub) = matches.subcommand_matches("raw") {
+        if let Some(rate) = sub.value_of("data-rate") {
+            radio = radio.data_rate_hz(rate.parse::<f64>().expect("Invalid argument for data-rate"));
+        }
+        if let Some(freq) = sub.value_of("freq") {
+            radio = radio.freq_mhz(freq.parse::<f64>().expect("Invalid argument for freq"));
+        }
+    }
+    let capture = matches.subcommand_matches("import")
+        .map(|sub| SubFile::from_file(sub.value_of("file").unwrap()).expect("Unable to load capture"));
+    if let Some(ref capture) = capture {
+        radio = radio.freq_mhz(capture.frequency_hz as f64 / 1000000.0);
+    }
+    let radio = radio.build().unwrap_or_else(|e| {
+        clap::Error::with_description(&e.to_string(), clap::ErrorKind::ValueValidation).exit()
+    });
+
+    let config = open_config(&matches);
+    if let Some(sub) = matches.subcommand_matches("ingest") {
+        if sub.is_present("print-spec") {
+            return println!("{}", rtl433::flex_spec(&pwm, radio.data_rate_hz));
+        }
+        return ingest(&matches, sub, &config);
+    }
+
+    if let Some(sub) = matches.subcommand_matches("raw") {
+        if matches.is_present("repeat") || matches.is_present("gap") {
+            clap::Error::with_description("raw sends symbols rather than a command. Use raw \
+                                           --copies and --copy-gap to repeat them.",
+                                          clap::ErrorKind::ArgumentConflict)
+                .exit()
+        }
+        let bits = raw_bitstream(sub, &radio, &pwm);
+        let mut rf = open_configured(&matches, radio);
+        rf.transmit_bitstream(bits).unwrap();
+        return;
+    }
+
+    if let Some(capture) = capture {
+        return import(&matches,
+                      matches.subcommand_matches("import").unwrap(),
+                      &config,
+                      capture,
+                      radio,
+                      &pwm);
+    }
+
+    if let Some(sub) = matches.subcommand_matches("dim") {
+        return dim(&matches, sub, &config, &pwm, radio);
+    }
+    if let Some(sub) = matches.subcommand_matches("compare") {
+        return compare(&matches, &config, &radio, &pwm, sub);
+    }
+    if let Some(sub) = matches.subcommand_matches("plot") {
+        return plot(&matches, &config, &radio, &pwm, sub);
+    }
+    if let Some(sub) = matches.subcommand_matches("sensors") {
+        return sensors(&matches, sub, radio);
+    }
+
+    if let Some(sub) = matches.subcommand_matches("timer") {
+        return timer(&matches, sub, &config, &pwm, radio);
+    }
+    if let Some(sub) = matches.subcommand_matches("schedule") {
+        return schedule(&matches, sub, &config, &pwm, radio);
+    }
+    if let Some(sub) = matches.subcommand_matches("auto") {
+        return auto(&matches, sub, &config, &pwm, radio);
+    }
+    let address = parse_address(&matches);
+    if let Some(sub) = matches.subcommand_matches("fade") {
+        return fade(&matches, sub, address, &config, &pwm, radio);
+    }
+    if let Some(sub) = matches.subcommand_matches("calibrate") {
+        return calibrate(&matches, sub, address, &config, &pwm, radio);
+    }
+    if let Some(sub) = matches.subcommand_matches("probe") {
+        return probe(&matches, sub, address, &config, &pwm, radio);
+    }
+    if let Some(sub) = matches.subcommand_matches("encode") {
+        let pkt = parse_pkt(address, &config, sub)
+            .expect("Arg parser enforces subcommand requirement");
+        let (pwm, frames) = plan(&matches, &config, &pkt, &pwm, &radio);
+        return encode(&pkt, &radio, &pwm, frames, sub);
+    }
+    if let Some(sub) = matches.subcommand_matches("export") {
+        let pkt = parse_pkt(address, &config, sub)
+            .expect("Arg parser enforces subcommand requirement");
+        let (pwm, frames) = plan(&matches, &config, &pkt, &pwm, &radio);
+        return export(&pkt, &radio, &pwm, frames, sub);
+    }
+    // Arg parser enforces subcommand requirement
+    let pkt = parse_pkt(address, &config, &matches).unwrap();
+    send(&matches, &config, &pkt, &pwm, radio);
 }
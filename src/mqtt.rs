@@ -0,0 +1,233 @@
+//! Just enough of an MQTT 3.1.1 client to follow a topic at QoS 0, e.g. a
+//! temperature published by a sensor elsewhere on the network.
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::thread;
+use std::time::Duration;
+
+const DEFAULT_PORT: u16 = 1883;
+/// The broker drops us if it hears nothing for 1.5 times this
+const KEEP_ALIVE_S: u16 = 60;
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+/// Has reserved bits that must be set
+const SUBSCRIBE: u8 = 0x82;
+const SUBACK: u8 = 0x90;
+const PINGREQ: u8 = 0xc0;
+const DISCONNECT: u8 = 0xe0;
+
+/// A message on a subscribed topic
+pub type Message = (String, Vec<u8>);
+
+/// A connection subscribed to one topic. Messages are read in the
+/// background and collected by `poll`, and the connection is kept alive in
+/// the background however rarely that's called.
+pub struct Subscriber {
+    stream: TcpStream,
+    messages: Receiver<io::Result<Message>>,
+    /// Dropping it stops the pings
+    _pinging: Sender<()>,
+}
+
+fn invalid<T>(msg: &str) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+fn encode_str(buf: &mut Vec<u8>, text: &str) {
+    buf.extend_from_slice(&(text.len() as u16).to_be_bytes());
+    buf.extend_from_slice(text.as_bytes());
+}
+
+/// Sends a packet with its length encoded in front of the body
+fn write_packet<W: Write>(out: &mut W, kind: u8, body: &[u8]) -> io::Result<()> {
+    let mut pkt = vec![kind];
+    let mut len = body.len();
+    loop {
+        let byte = (len % 128) as u8;
+        len /= 128;
+        pkt.push(if len > 0 { byte | 0x80 } else { byte });
+        if len == 0 {
+            break;
+        }
+    }
+    pkt.extend_from_slice(body);
+    out.write_all(&pkt)
+}
+
+/// Reads a packet, returning its type byte and body
+fn read_packet<R: Read>(input: &mut R) -> io::Result<(u8, Vec<u8>)> {
+    let mut byte = [0; 1];
+    input.read_exact(&mut byte)?;
+    let kind = byte[0];
+    let mut len = 0;
+    for shift in 0..4 {
+        input.read_exact(&mut byte)?;
+        len |= ((byte[0] & 0x7f) as usize) << (7 * shift);
+        if byte[0] & 0x80 == 0 {
+            let mut body = vec![0; len];
+            input.read_exact(&mut body)?;
+            return Ok((kind, body));
+        }
+    }
+    invalid("MQTT packet length too long")
+}
+
+/// Splits a PUBLISH body into its topic and payload
+fn parse_publish(kind: u8, body: &[u8]) -> io::Result<Message> {
+    if body.len() < 2 {
+        return invalid("MQTT publish too short");
+    }
+    let len = u16::from_be_bytes([body[0], body[1]]) as usize;
+    // A packet ID follows the topic above QoS 0
+    let start = 2 + len + if kind & 0x06 != 0 { 2 } else { 0 };
+    if body.len() < start {
+        return invalid("MQTT publish too short");
+    }
+    let topic = String::from_utf8_lossy(&body[2..2 + len]).into_owned();
+    Ok((topic, body[start..].to_vec()))
+}
+
+impl Subscriber {
+    /// Connects to `broker`, given as `host` or `host:port`, and subscribes
+    /// to `topic`. Wildcards are allowed.
+    pub fn connect(broker: &str, client_id: &str, topic: &str) -> io::Result<Self> {
+        let mut stream = if broker.contains(':') {
+            TcpStream::connect(broker)?
+        } else {
+            TcpStream::connect((broker, DEFAULT_PORT))?
+        };
+
+        let mut body = Vec::new();
+        encode_str(&mut body, "MQTT");
+        // Protocol level 4, clean session
+        body.extend_from_slice(&[4, 0x02]);
+        body.extend_from_slice(&KEEP_ALIVE_S.to_be_bytes());
+        encode_str(&mut body, client_id);
+        write_packet(&mut stream, CONNECT, &body)?;
+        match read_packet(&mut stream)? {
+            (CONNACK, ref body) if body.len() == 2 && body[1] == 0 => (),
+            (CONNACK, body) => {
+                return Err(io::Error::new(io::ErrorKind::ConnectionRefused,
+                                          format!("MQTT broker refused connection ({})",
+                                                  body.get(1).cloned().unwrap_or(0))))
+            }
+            _ => return invalid("Expected MQTT CONNACK"),
+        }
+
+        let mut body = vec![0, 1];
+        encode_str(&mut body, topic);
+        // Maximum QoS 0
+        body.push(0);
+        write_packet(&mut stream, SUBSCRIBE, &body)?;
+
+        let (send, messages) = mpsc::channel();
+        let mut input = stream.try_clone()?;
+        thread::spawn(move || {
+            loop {
+                let msg = match read_packet(&mut input) {
+                    Ok((kind, ref body)) if kind & 0xf0 == PUBLISH => parse_publish(kind, body),
+                    Ok((SUBACK, ref body)) if body.get(2) == Some(&0x80) => {
+                        invalid("MQTT broker refused subscription")
+                    }
+                    // SUBACK and PINGRESP
+                    Ok(_) => continue,
+                    Err(e) => Err(e),
+                };
+                let failed = msg.is_err();
+                if send.send(msg).is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        let (pinging, stop) = mpsc::channel::<()>();
+        let mut output = stream.try_clone()?;
+        thread::spawn(move || {
+            let every = Duration::from_secs(KEEP_ALIVE_S as u64 / 2);
+            while let Err(RecvTimeoutError::Timeout) = stop.recv_timeout(every) {
+                if write_packet(&mut output, PINGREQ, &[]).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Subscriber {
+            stream,
+            messages,
+            _pinging: pinging,
+        })
+    }
+
+    /// Returns the messages received since the last call, oldest first. An
+    /// error is returned once the messages received before it have been.
+    pub fn poll(&mut self) -> io::Result<Vec<Message>> {
+        let mut received = Vec::new();
+        loop {
+            match self.messages.try_recv() {
+                Ok(Ok(msg)) => received.push(msg),
+                Ok(Err(e)) if received.is_empty() => return Err(e),
+                Err(TryRecvError::Disconnected) if received.is_empty() => {
+                    return Err(io::Error::new(io::ErrorKind::ConnectionAborted,
+                                              "MQTT connection closed"))
+                }
+                // An error ends the reader, so the next call finds the
+                // connection closed
+                _ => return Ok(received),
+            }
+        }
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        // Also ends the reader thread
+        let _ = write_packet(&mut self.stream, DISCONNECT, &[]);
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+#[test]
+fn mqtt_subscribe() {
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let broker = listener.local_addr().unwrap().to_string();
+    let server = thread::spawn(move || {
+        let (mut conn, _) = listener.accept().unwrap();
+        let (kind, body) = read_packet(&mut conn).unwrap();
+        assert_eq!(CONNECT, kind);
+        assert_eq!(b"\0\x04MQTT\x04\x02\0\x3c\0\x04test", &body[..]);
+        write_packet(&mut conn, CONNACK, &[0, 0]).unwrap();
+        let (kind, body) = read_packet(&mut conn).unwrap();
+        assert_eq!(SUBSCRIBE, kind);
+        assert_eq!(b"\0\x01\0\x09home/temp\0", &body[..]);
+        write_packet(&mut conn, SUBACK, &[0, 1, 0]).unwrap();
+        for temp in ["21.5", "22"].iter() {
+            let mut body = Vec::new();
+            encode_str(&mut body, "home/temp");
+            body.extend_from_slice(temp.as_bytes());
+            write_packet(&mut conn, PUBLISH, &body).unwrap();
+        }
+    });
+
+    let mut sub = Subscriber::connect(&broker, "test", "home/temp").unwrap();
+    // The broker has hung up by the time we look
+    server.join().unwrap();
+    thread::sleep(Duration::from_millis(50));
+    // Messages that arrived before it did are still returned
+    let mut received = Vec::new();
+    for _ in 0..100 {
+        match sub.poll() {
+            Ok(msgs) => received.extend(msgs),
+            Err(_) => break,
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(vec![("home/temp".to_string(), b"21.5".to_vec()),
+                    ("home/temp".to_string(), b"22".to_vec())],
+               received);
+}
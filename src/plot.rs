@@ -0,0 +1,214 @@
+//! Timing diagrams of symbol streams, as terminal text or SVG
+
+use encode::timings;
+use fan::FanPkt;
+use pwm::PwmSymbols;
+
+/// A labelled range of symbols, `start..end`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Annotation {
+    pub label: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Annotation {
+    pub fn new<S: Into<String>>(label: S, start: usize, end: usize) -> Self {
+        Annotation {
+            label: label.into(),
+            start,
+            end,
+        }
+    }
+}
+
+/// Annotates `frames` frames of `pkt` as sent: the start bit, the packet
+/// fields and the inter-frame gap
+pub fn annotate(pkt: &FanPkt, pwm: &PwmSymbols, frames: usize) -> Vec<Annotation> {
+    let expanded = pkt.expanded(pwm).len();
+    let frame = pkt.frame(pwm).len();
+    let period = pwm.period;
+    let mut anns = Vec::new();
+    for offset in (0..frames).map(|n| n * frame) {
+        anns.push(Annotation::new("start", offset, offset + period));
+        for (label, start, end) in pkt.fields() {
+            // Packet bits follow the start bit
+            anns.push(Annotation::new(label,
+                                      offset + (start + 1) * period,
+                                      offset + (end + 1) * period));
+        }
+        anns.push(Annotation::new("gap", offset + expanded, offset + frame));
+    }
+    anns
+}
+
+/// Annotates each fan-expanded bit of a raw stream with its value
+pub fn annotate_bits(bits: &[bool], pwm: &PwmSymbols) -> Vec<Annotation> {
+    bits.iter()
+        .enumerate()
+        .map(|(idx, &bit)| {
+            Annotation::new(if bit { "1" } else { "0" },
+                            idx * pwm.period,
+                            (idx + 1) * pwm.period)
+        })
+        .collect()
+}
+
+/// Draws the waveform with `cols` characters per symbol and the annotations
+/// under it, wrapped at `width` characters
+pub fn ascii(symbols: &[bool], annotations: &[Annotation], cols: usize, width: usize) -> String {
+    let len = symbols.len() * cols;
+    let mut high = vec![' '; len];
+    let mut low = vec![' '; len];
+    let mut labels = vec![' '; len];
+    for (idx, &on) in symbols.iter().enumerate() {
+        for col in idx * cols..(idx + 1) * cols {
+            if on {
+                high[col] = '_';
+            } else {
+                low[col] = '_';
+            }
+        }
+        if idx > 0 && symbols[idx - 1] != on {
+            low[idx * cols] = '|';
+        }
+    }
+    for ann in annotations {
+        let start = ann.start * cols;
+        let end = (ann.end * cols).min(len);
+        if start >= end {
+            continue;
+        }
+        labels[start] = '|';
+        for c in labels[start + 1..end].iter_mut() {
+            *c = '-';
+        }
+        // Label centered in the range if it fits, otherwise cut short
+        let room = end - start - 1;
+        let text = ann.label.chars().take(room).collect::<Vec<_>>();
+        let offset = start + 1 + (room - text.len()) / 2;
+        labels[offset..offset + text.len()].copy_from_slice(&text);
+    }
+
+    // Wrap on symbol boundaries
+    let width = (width / cols.max(1) * cols).max(cols.max(1));
+    let mut out = String::new();
+    for chunk in 0..len.div_ceil(width) {
+        let range = chunk * width..((chunk + 1) * width).min(len);
+        for row in [&high, &low, &labels].iter() {
+            let line = row[range.clone()].iter().collect::<String>();
+            out.push_str(line.trim_end());
+            out.push('\n');
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// Pixels per microsecond in the SVG
+const SVG_SCALE: f64 = 0.06;
+const SVG_MARGIN: f64 = 20.0;
+
+/// Renders a timing diagram with a time axis in milliseconds
+pub fn svg(symbols: &[bool], annotations: &[Annotation], symbol_us: f64) -> String {
+    let x = |symbol: usize| SVG_MARGIN + symbol as f64 * symbol_us * SVG_SCALE;
+    let (top, bottom) = (SVG_MARGIN, SVG_MARGIN + 40.0);
+    let width = x(symbols.len()) + SVG_MARGIN;
+    let height = bottom + 80.0;
+
+    let mut points = Vec::new();
+    let mut pos = 0;
+    for t in timings(symbols, symbol_us) {
+        let run = symbols[pos..].iter().take_while(|&&b| b == (t > 0)).count();
+        let y = if t > 0 { top } else { bottom };
+        points.push(format!("{:.1},{:.1}", x(pos), y));
+        points.push(format!("{:.1},{:.1}", x(pos + run), y));
+        pos += run;
+    }
+
+    let mut out = String::new();
+    out.push_str(&format!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{:.0}\" \
+                           height=\"{:.0}\" font-family=\"monospace\" font-size=\"10\">\n",
+                          width,
+                          height));
+    out.push_str(&format!("<polyline fill=\"none\" stroke=\"black\" points=\"{}\"/>\n",
+                          points.join(" ")));
+    for (idx, ann) in annotations.iter().enumerate() {
+        let (x0, x1) = (x(ann.start), x(ann.end.min(symbols.len())));
+        let y = bottom + 10.0 + (idx % 2) as f64 * 18.0;
+        let fill = if idx % 2 == 0 { "#dde8f4" } else { "#f4e8dd" };
+        out.push_str(&format!("<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"14\" \
+                               fill=\"{}\" stroke=\"gray\"/>\n",
+                              x0,
+                              y,
+                              x1 - x0,
+                              fill));
+        out.push_str(&format!("<text x=\"{:.1}\" y=\"{:.1}\" text-anchor=\"middle\">{}</text>\n",
+                              (x0 + x1) / 2.0,
+                              y + 11.0,
+                              ann.label));
+    }
+    // Time axis
+    let axis_y = height - 15.0;
+    let total_ms = symbols.len() as f64 * symbol_us / 1000.0;
+    out.push_str(&format!("<line x1=\"{:.1}\" y1=\"{:.1}\" x2=\"{:.1}\" y2=\"{:.1}\" \
+                           stroke=\"gray\"/>\n",
+                          x(0),
+                          axis_y,
+                          x(symbols.len()),
+                          axis_y));
+    let mut ms = 0;
+    while ms as f64 <= total_ms {
+        let tx = SVG_MARGIN + ms as f64 * 1000.0 * SVG_SCALE;
+        out.push_str(&format!("<line x1=\"{:.1}\" y1=\"{:.1}\" x2=\"{:.1}\" y2=\"{:.1}\" \
+                               stroke=\"gray\"/>\n",
+                              tx,
+                              axis_y - 3.0,
+                              tx,
+                              axis_y + 3.0));
+        out.push_str(&format!("<text x=\"{:.1}\" y=\"{:.1}\" text-anchor=\"middle\">{}ms</text>\n",
+                              tx,
+                              axis_y + 13.0,
+                              ms));
+        ms += 1;
+    }
+    out.push_str("</svg>\n");
+    out
+}
+
+#[test]
+fn ascii_plot() {
+    let symbols = [false, true, false, true, true, false];
+    let anns = [Annotation::new("start", 0, 3), Annotation::new("1", 3, 6)];
+    assert_eq!("  __  ____\n\
+                __| |_|   |_\n\
+                |start|--1--\n\n",
+               ascii(&symbols, &anns, 2, 80));
+    // Wrapped
+    assert_eq!(3, ascii(&symbols, &anns, 2, 5).split("\n\n").filter(|s| !s.is_empty()).count());
+}
+
+#[test]
+fn svg_plot() {
+    let symbols = [false, true, false, true, true, false];
+    let svg = svg(&symbols, &[Annotation::new("start", 0, 3)], 1000.0);
+    assert!(svg.starts_with("<svg"));
+    assert!(svg.contains(">start</text>"));
+    assert!(svg.contains(">6ms</text>"));
+    // 5 runs, each a horizontal segment
+    let points = svg.split("points=\"").nth(1).unwrap().split('"').next().unwrap();
+    assert_eq!(10, points.split(' ').count());
+}
+
+#[test]
+fn fan_annotations() {
+    use fan::{FanCmd12, FanPkt12};
+
+    let pkt = FanPkt::Dumb(FanPkt12::new(5, FanCmd12::Light));
+    let anns = annotate(&pkt, &PwmSymbols::FAN, 2);
+    assert_eq!(10, anns.len());
+    assert_eq!(Annotation::new("addr 5", 6, 18), anns[2]);
+    assert_eq!(Annotation::new("Light", 18, 39), anns[3]);
+    assert_eq!(Annotation::new("gap", 39, 72), anns[4]);
+    assert_eq!(Annotation::new("start", 72, 75), anns[5]);
+}
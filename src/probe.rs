@@ -0,0 +1,134 @@
+//! Steps through command codes or brightness levels, asking after each
+//! whether the fan did anything. Maps out receivers with functions we don't
+//! know the codes for, and finds the lowest brightness a fan accepts.
+
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+
+const PROMPT: &str = "Did the fan react? [y NAME / N / r(epeat) / q(uit)] ";
+const LEVEL_PROMPT: &str = "Did the light change? [Y / n / r(epeat) / q(uit)] ";
+
+/// Prompts and reads a line of answer, lowercasing the first word. `None` at
+/// the end of input.
+fn ask<R: BufRead, W: Write>(input: &mut R,
+                             out: &mut W,
+                             prompt: &str)
+                             -> io::Result<Option<(String, Vec<String>)>> {
+    write!(out, "{}", prompt)?;
+    out.flush()?;
+    let mut line = String::new();
+    if input.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    let mut words = line.split_whitespace().map(String::from);
+    let first = words.next().unwrap_or_default().to_lowercase();
+    Ok(Some((first, words.collect())))
+}
+
+/// Sends each code with `send` and reads the answer from `input`. Answering
+/// `y` with a name records what the code does. Returns the codes the fan
+/// reacted to, named `code_0xNN` where no name was given.
+pub fn probe<R, W, F>(codes: &[u8],
+                      mut input: R,
+                      out: &mut W,
+                      mut send: F)
+                      -> io::Result<BTreeMap<String, u8>>
+    where R: BufRead,
+          W: Write,
+          F: FnMut(u8) -> io::Result<()>
+{
+    let mut found = BTreeMap::new();
+    'codes: for &code in codes {
+        loop {
+            writeln!(out, "Sending code 0x{:02x} ({:07b})", code, code)?;
+            send(code)?;
+            let (answer, rest) = match ask(&mut input, out, PROMPT)? {
+                Some(answer) => answer,
+                None => break 'codes,
+            };
+            match answer.as_str() {
+                "y" | "yes" => {
+                    let name = if rest.is_empty() {
+                        format!("code_0x{:02x}", code)
+                    } else {
+                        rest.join("_")
+                    };
+                    found.insert(name, code);
+                    break;
+                }
+                "r" | "repeat" => continue,
+                "q" | "quit" => break 'codes,
+                _ => break,
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// Sends each level in turn, from bright to dim, until the light stops
+/// changing. Returns the lowest level it changed for.
+pub fn lowest_level<R, W, F>(levels: &[u8],
+                             mut input: R,
+                             out: &mut W,
+                             mut send: F)
+                             -> io::Result<Option<u8>>
+    where R: BufRead,
+          W: Write,
+          F: FnMut(u8) -> io::Result<()>
+{
+    let mut lowest = None;
+    'levels: for &level in levels {
+        loop {
+            writeln!(out, "Sending level {}", level)?;
+            send(level)?;
+            let (answer, _) = match ask(&mut input, out, LEVEL_PROMPT)? {
+                Some(answer) => answer,
+                None => break 'levels,
+            };
+            match answer.as_str() {
+                "" | "y" | "yes" => {
+                    lowest = Some(level);
+                    break;
+                }
+                "r" | "repeat" => continue,
+                _ => break 'levels,
+            }
+        }
+    }
+    Ok(lowest)
+}
+
+#[test]
+fn probe_codes() {
+    let answers = "n\ny reverse\n\nr\ny\nq\n";
+    let mut sent = Vec::new();
+    let mut out = Vec::new();
+    let found = probe(&[0x03, 0x04, 0x05, 0x06, 0x07],
+                      answers.as_bytes(),
+                      &mut out,
+                      |code| {
+                          sent.push(code);
+                          Ok(())
+                      })
+        .unwrap();
+    assert_eq!(vec![0x03, 0x04, 0x05, 0x06, 0x06, 0x07], sent);
+    assert_eq!(Some(&0x04), found.get("reverse"));
+    assert_eq!(Some(&0x06), found.get("code_0x06"));
+    assert_eq!(2, found.len());
+    assert!(String::from_utf8(out).unwrap().starts_with("Sending code 0x03 (0000011)\n"));
+}
+
+#[test]
+fn calibrate_levels() {
+    let mut sent = Vec::new();
+    let lowest = lowest_level(&[30, 29, 28, 27, 26],
+                              "\ny\nr\n\nn\n".as_bytes(),
+                              &mut Vec::new(),
+                              |level| {
+                                  sent.push(level);
+                                  Ok(())
+                              })
+        .unwrap();
+    assert_eq!(Some(28), lowest);
+    assert_eq!(vec![30, 29, 28, 28, 27], sent);
+}
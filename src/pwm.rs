@@ -0,0 +1,178 @@
+//! Pulse width modulated bit timing, and the symbol rate needed to send it
+//! with the radio's OOK modulator
+
+use std::ops::Range;
+
+/// Where in the bit period a bit's pulse sits
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PulseAlign {
+    /// The pulse starts the bit and silence fills the rest of the period
+    Start,
+    /// Silence starts the bit and the pulse ends at the long pulse's end, so
+    /// the bit's value is decided before the pulse rises
+    End,
+}
+
+/// Bit timing of a remote in microseconds. Each bit has a pulse, short for 0
+/// and long for 1, placed in the bit period by `align`. Frames are followed
+/// by a gap.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PwmTiming {
+    pub short_us: f64,
+    pub long_us: f64,
+    pub period_us: f64,
+    pub gap_us: f64,
+    pub align: PulseAlign,
+}
+
+/// `PwmTiming` in whole symbols
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PwmSymbols {
+    pub short: usize,
+    pub long: usize,
+    pub period: usize,
+    pub gap: usize,
+    pub align: PulseAlign,
+}
+
+impl PwmSymbols {
+    /// Hampton Bay / Harbor Breeze remotes at 3000 symbols/s: 1/3 and 2/3 duty
+    /// cycle, 11ms between frames
+    pub const FAN: PwmSymbols = PwmSymbols {
+        short: 1,
+        long: 2,
+        period: 3,
+        gap: 33,
+        align: PulseAlign::End,
+    };
+
+    /// Symbols of the bit period the pulse for `bit` is on during
+    pub fn pulse(&self, bit: bool) -> Range<usize> {
+        let len = if bit { self.long } else { self.short };
+        match self.align {
+            PulseAlign::Start => 0..len,
+            PulseAlign::End => self.long - len..self.long,
+        }
+    }
+}
+
+/// Most symbols per bit we'll oversample to. Keeps the FIFO from being
+/// drained faster than it can be refilled.
+const MAX_SYMBOLS_PER_BIT: usize = 32;
+/// Largest error allowed in any duration, as a fraction of the short pulse
+const MAX_ERROR: f64 = 0.05;
+/// TX data rate range of the RFM22
+const DATA_RATE_HZ: (f64, f64) = (123.0, 256000.0);
+
+impl PwmTiming {
+    pub const FAN: PwmTiming = PwmTiming {
+        short_us: 1000.0 / 3.0,
+        long_us: 2000.0 / 3.0,
+        period_us: 1000.0,
+        gap_us: 11000.0,
+        align: PulseAlign::End,
+    };
+
+    /// 1:3 duty cycle at 400us, as used by EV1527 style receivers
+    pub const EV1527: PwmTiming = PwmTiming {
+        short_us: 400.0,
+        long_us: 1200.0,
+        period_us: 1600.0,
+        gap_us: 11000.0,
+        align: PulseAlign::Start,
+    };
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "fan" => Some(Self::FAN),
+            "ev1527" => Some(Self::EV1527),
+            _ => None,
+        }
+    }
+
+    /// Parses a preset name or `short,long,period[,gap]` in microseconds.
+    /// Pulses of the latter start each bit, as most remotes send them.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        if let Some(timing) = Self::from_name(text) {
+            return Ok(timing);
+        }
+        let durs = text.split(',')
+            .map(|d| d.trim().parse::<f64>().map_err(|_| format!("Invalid duration '{}'", d)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let timing = match durs.len() {
+            3 | 4 => {
+                PwmTiming {
+                    short_us: durs[0],
+                    long_us: durs[1],
+                    period_us: durs[2],
+                    gap_us: durs.get(3).cloned().unwrap_or(Self::FAN.gap_us),
+                    align: PulseAlign::Start,
+                }
+            }
+            _ => {
+                return Err(format!("Expected a preset or short,long,period[,gap], not '{}'",
+                                   text))
+            }
+        };
+        if !(0.0 < timing.short_us && timing.short_us < timing.long_us &&
+             timing.long_us < timing.period_us && timing.gap_us >= 0.0) {
+            return Err("Durations must satisfy 0 < short < long < period".to_string());
+        }
+        Ok(timing)
+    }
+
+    /// Picks the fewest symbols per bit that represent every duration to
+    /// within 5% of the short pulse. Returns the symbol counts and the data
+    /// rate to send them at.
+    pub fn oversample(&self) -> Result<(PwmSymbols, f64), String> {
+        for period in 2..=MAX_SYMBOLS_PER_BIT {
+            let symbol_us = self.period_us / period as f64;
+            let data_rate_hz = 1000000.0 / symbol_us;
+            if data_rate_hz < DATA_RATE_HZ.0 || data_rate_hz > DATA_RATE_HZ.1 {
+                continue;
+            }
+            let short = (self.short_us / symbol_us).round();
+            let long = (self.long_us / symbol_us).round();
+            let error = (self.short_us - short * symbol_us)
+                .abs()
+                .max((self.long_us - long * symbol_us).abs());
+            if short < 1.0 || long <= short || long >= period as f64 ||
+               error > self.short_us * MAX_ERROR {
+                continue;
+            }
+            let symbols = PwmSymbols {
+                short: short as usize,
+                long: long as usize,
+                period,
+                gap: (self.gap_us / symbol_us).round() as usize,
+                align: self.align,
+            };
+            return Ok((symbols, data_rate_hz));
+        }
+        Err(format!("No symbol rate represents {:?} with at most {} symbols per bit",
+                    self,
+                    MAX_SYMBOLS_PER_BIT))
+    }
+}
+
+#[test]
+fn pwm_oversample() {
+    assert_eq!(Ok((PwmSymbols::FAN, 3000.0)), PwmTiming::FAN.oversample());
+    let (symbols, rate) = PwmTiming::EV1527.oversample().unwrap();
+    assert_eq!(PwmSymbols {
+                   short: 1,
+                   long: 3,
+                   period: 4,
+                   gap: 28,
+                   align: PulseAlign::Start,
+               },
+               symbols);
+    assert_eq!(2500.0, rate);
+    // 30% duty is close enough to 2/7
+    let (symbols, rate) = PwmTiming::parse("300,700,1000").unwrap().oversample().unwrap();
+    assert_eq!((2, 5, 7), (symbols.short, symbols.long, symbols.period));
+    assert_eq!(7000.0, rate);
+    assert_eq!(Ok(PwmTiming::EV1527), PwmTiming::parse("ev1527"));
+    assert!(PwmTiming::parse("700,300,1000").is_err());
+    assert!(PwmTiming::parse("100,201,10000").unwrap().oversample().is_err());
+}
@@ -7,6 +7,24 @@ pub trait RegRw {
     fn read(&mut self, reg: u8) -> io::Result<u8>;
     fn write(&mut self, reg: u8, val: u8) -> io::Result<()>;
     fn burst_write(&mut self, reg: u8, val: &[u8]) -> io::Result<()>;
+    /// Reads consecutive registers, or the FIFO repeatedly
+    fn burst_read(&mut self, mut reg: u8, buf: &mut [u8]) -> io::Result<()> {
+        for byte in buf {
+            *byte = self.read(reg)?;
+            if reg < 0x7f {
+                reg += 1;
+            }
+        }
+        Ok(())
+    }
+    /// Writes several bursts, each starting at its own register. Transports
+    /// that can queue transfers should submit them together.
+    fn write_batch(&mut self, writes: &[(u8, &[u8])]) -> io::Result<()> {
+        for &(reg, val) in writes {
+            self.burst_write(reg, val)?;
+        }
+        Ok(())
+    }
 }
 
 // Not sure why this is required
@@ -20,6 +38,12 @@ impl<T: RegRw + ?Sized> RegRw for Box<T> {
     fn burst_write(&mut self, reg: u8, val: &[u8]) -> io::Result<()> {
         self.deref_mut().burst_write(reg, val)
     }
+    fn burst_read(&mut self, reg: u8, buf: &mut [u8]) -> io::Result<()> {
+        self.deref_mut().burst_read(reg, buf)
+    }
+    fn write_batch(&mut self, writes: &[(u8, &[u8])]) -> io::Result<()> {
+        self.deref_mut().write_batch(writes)
+    }
 }
 
 pub struct RfmRegs {
@@ -48,6 +72,30 @@ impl RegRw for RfmRegs {
         let mut tx = [SpidevTransfer::write(&addr), SpidevTransfer::write(val)];
         self.spi.transfer_multiple(&mut tx)
     }
+
+    fn burst_read(&mut self, reg: u8, buf: &mut [u8]) -> io::Result<()> {
+        let addr = [reg];
+        let mut tx = [SpidevTransfer::write(&addr), SpidevTransfer::read(buf)];
+        self.spi.transfer_multiple(&mut tx)
+    }
+
+    fn write_batch(&mut self, writes: &[(u8, &[u8])]) -> io::Result<()> {
+        let frames: Vec<Vec<u8>> = writes.iter()
+            .map(|&(reg, val)| {
+                let mut frame = Vec::with_capacity(val.len() + 1);
+                frame.push(reg | 0x80);
+                frame.extend_from_slice(val);
+                frame
+            })
+            .collect();
+        let mut tx: Vec<SpidevTransfer> = frames.iter().map(|f| SpidevTransfer::write(f)).collect();
+        // Release chip select between bursts so each gets its own address
+        let last = tx.len().saturating_sub(1);
+        for transfer in &mut tx[..last] {
+            transfer.cs_change = 1;
+        }
+        self.spi.transfer_multiple(&mut tx)
+    }
 }
 
 pub struct FakeRegs([u8; 0x80]);
@@ -60,7 +108,12 @@ impl FakeRegs {
 
 impl RegRw for FakeRegs {
     fn read(&mut self, reg: u8) -> io::Result<u8> {
-        Ok(self.0[reg as usize])
+        let val = self.0[reg as usize];
+        // Interrupt status registers clear on read
+        if reg == 0x03 || reg == 0x04 {
+            self.0[reg as usize] = 0;
+        }
+        Ok(val)
     }
 
     fn write(&mut self, reg: u8, val: u8) -> io::Result<()> {
@@ -80,6 +133,75 @@ impl RegRw for FakeRegs {
     }
 }
 
+/// FakeRegs with just enough chip behaviour to run the real driver against.
+/// A software reset reports chip ready, packets are sent as soon as the
+/// transmitter is enabled, the temperature sensor reads 25°C and the supply
+/// is 3V.
+pub struct EmuRegs(FakeRegs);
+
+impl EmuRegs {
+    pub fn new() -> Self {
+        EmuRegs(FakeRegs::new())
+    }
+
+    fn tx_done(&mut self) {
+        // ITXFFAEM | IPKSENT
+        (self.0).0[0x03] |= 0x24;
+    }
+
+    /// Measures the supply as the low battery detector would
+    fn detect_low_battery(&mut self) {
+        // 3V
+        (self.0).0[0x1b] = 26;
+        // ILBDET below the threshold
+        if (self.0).0[0x1a] > 26 {
+            (self.0).0[0x04] |= 0x04;
+        }
+    }
+}
+
+impl RegRw for EmuRegs {
+    fn read(&mut self, reg: u8) -> io::Result<u8> {
+        self.0.read(reg)
+    }
+
+    fn write(&mut self, reg: u8, val: u8) -> io::Result<()> {
+        match reg {
+            // SWRES: back to defaults with IPOR | ICHIPRDY pending and XTON set
+            0x07 if val & 0x80 != 0 => {
+                self.0 = FakeRegs::new();
+                (self.0).0[0x04] = 0x03;
+                (self.0).0[0x07] = 0x01;
+                Ok(())
+            }
+            // TXON: the whole FIFO goes out at once and TXON clears
+            0x07 if val & 0x08 != 0 => {
+                self.tx_done();
+                self.0.write(reg, val & !0x08)
+            }
+            // ENLBD: measured once when turned on
+            0x07 if val & 0x40 != 0 => {
+                self.detect_low_battery();
+                self.0.write(reg, val)
+            }
+            // ADCSTART: the conversion is done at once and ADCDONE reads set
+            0x0f if val & 0x80 != 0 => {
+                // 25°C on the -64 to 64°C range, the only input emulated
+                (self.0).0[0x11] = 0xb2;
+                self.0.write(reg, val)
+            }
+            _ => self.0.write(reg, val),
+        }
+    }
+
+    fn burst_write(&mut self, reg: u8, val: &[u8]) -> io::Result<()> {
+        if reg == 0x7f {
+            self.tx_done();
+        }
+        self.0.burst_write(reg, val)
+    }
+}
+
 pub struct RegLogger<R: RegRw>(pub R);
 
 impl<R: RegRw> RegRw for RegLogger<R> {
@@ -99,6 +221,19 @@ impl<R: RegRw> RegRw for RegLogger<R> {
         debug!("Burst({:2}) 0x{:02x} = {:?}", val.len(), reg, val);
         self.0.burst_write(reg, val)
     }
+
+    fn burst_read(&mut self, reg: u8, buf: &mut [u8]) -> io::Result<()> {
+        self.0.burst_read(reg, buf).map(|_| {
+            debug!("Burst read({:2}) 0x{:02x} = {:?}", buf.len(), reg, buf);
+        })
+    }
+
+    fn write_batch(&mut self, writes: &[(u8, &[u8])]) -> io::Result<()> {
+        for &(reg, val) in writes {
+            debug!("Batch({:2}) 0x{:02x} = {:?}", val.len(), reg, val);
+        }
+        self.0.write_batch(writes)
+    }
 }
 
 pub trait RfmReg {
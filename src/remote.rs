@@ -0,0 +1,333 @@
+//! Register bus over a byte stream, used to drive a chip on another machine.
+//!
+//! Every request gets exactly one response, in order. Requests are
+//!
+//! ```text
+//! op: u8, reg: u8, len: u16 (big endian), payload: [u8; len]
+//! ```
+//!
+//! and responses are
+//!
+//! ```text
+//! status: u8 (0 = ok, 1 = error), len: u16 (big endian), payload: [u8; len]
+//! ```
+//!
+//! An error payload is a UTF-8 message. The ops are listed below with their
+//! request and response payloads. IRQ "events" are delivered by `IRQ_WAIT`,
+//! which the server answers once nIRQ is asserted or the timeout expires.
+
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::rc::Rc;
+
+use gpio::{IrqLine, Lines, ShutdownLine};
+use regrw::RegRw;
+
+/// Request: empty. Response: the register value.
+pub const OP_READ: u8 = 0x01;
+/// Request: the value. Response: empty.
+pub const OP_WRITE: u8 = 0x02;
+/// Request: the values. Response: empty.
+pub const OP_BURST_WRITE: u8 = 0x03;
+/// Request: byte count as u16. Response: the values.
+pub const OP_BURST_READ: u8 = 0x04;
+/// Request: empty. Response: bit 0 set if there is a shutdown line, bit 1 if
+/// there is an IRQ line.
+pub const OP_CAPS: u8 = 0x05;
+/// Request: empty. Response: 1 if the chip is held in shutdown.
+pub const OP_SHUTDOWN_GET: u8 = 0x06;
+/// Request: 1 to enter shutdown, 0 to leave. Response: empty.
+pub const OP_SHUTDOWN_SET: u8 = 0x07;
+/// Request: empty. Response: 1 if nIRQ is asserted.
+pub const OP_IRQ_ASSERTED: u8 = 0x08;
+/// Request: timeout in ms as u32. Response: 1 if nIRQ was asserted, 0 on
+/// timeout.
+pub const OP_IRQ_WAIT: u8 = 0x09;
+
+pub const CAP_SHUTDOWN: u8 = 1 << 0;
+pub const CAP_IRQ: u8 = 1 << 1;
+
+fn read_frame<S: Read>(stream: &mut S) -> io::Result<(u8, u8, Vec<u8>)> {
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header)?;
+    let len = (header[2] as usize) << 8 | header[3] as usize;
+    let mut payload = vec![0; len];
+    stream.read_exact(&mut payload)?;
+    Ok((header[0], header[1], payload))
+}
+
+fn write_frame<S: Write>(stream: &mut S, a: u8, b: u8, payload: &[u8]) -> io::Result<()> {
+    if payload.len() > 0xffff {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Payload too long"));
+    }
+    let len = payload.len() as u16;
+    let mut frame = Vec::with_capacity(payload.len() + 4);
+    frame.extend_from_slice(&[a, b, (len >> 8) as u8, len as u8]);
+    frame.extend_from_slice(payload);
+    stream.write_all(&frame)?;
+    stream.flush()
+}
+
+/// Connection shared between the register, shutdown and IRQ handles
+type Conn<S> = Rc<RefCell<S>>;
+
+fn send_request<S: Read + Write>(conn: &Conn<S>, op: u8, reg: u8, payload: &[u8]) -> io::Result<()> {
+    write_frame(&mut *conn.borrow_mut(), op, reg, payload)
+}
+
+fn read_response<S: Read + Write>(conn: &Conn<S>) -> io::Result<Vec<u8>> {
+    let (status, _, payload) = read_frame(&mut *conn.borrow_mut())?;
+    if status != 0 {
+        return Err(io::Error::other(String::from_utf8_lossy(&payload).into_owned()));
+    }
+    Ok(payload)
+}
+
+fn request<S: Read + Write>(conn: &Conn<S>, op: u8, reg: u8, payload: &[u8]) -> io::Result<Vec<u8>> {
+    send_request(conn, op, reg, payload)?;
+    read_response(conn)
+}
+
+fn request_flag<S: Read + Write>(conn: &Conn<S>, op: u8, reg: u8, payload: &[u8]) -> io::Result<bool> {
+    request(conn, op, reg, payload).map(|resp| resp.first() == Some(&1))
+}
+
+/// Client side of the protocol
+pub struct RemoteRegs<S: Read + Write>(Conn<S>);
+
+impl<S: Read + Write + 'static> RemoteRegs<S> {
+    pub fn new(stream: S) -> Self {
+        RemoteRegs(Rc::new(RefCell::new(stream)))
+    }
+
+    /// Handles for whichever of the IRQ and shutdown lines the server has
+    pub fn lines(&self) -> io::Result<Lines> {
+        let caps = request(&self.0, OP_CAPS, 0, &[])?.first().cloned().unwrap_or(0);
+        let irq = if caps & CAP_IRQ != 0 {
+            Some(Box::new(RemoteIrq(self.0.clone())) as Box<dyn IrqLine>)
+        } else {
+            None
+        };
+        let shutdown = if caps & CAP_SHUTDOWN != 0 {
+            Some(Box::new(RemoteShutdown(self.0.clone())) as Box<dyn ShutdownLine>)
+        } else {
+            None
+        };
+        Ok((irq, shutdown))
+    }
+}
+
+impl RemoteRegs<TcpStream> {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        Ok(Self::new(stream))
+    }
+}
+
+impl<S: Read + Write> RegRw for RemoteRegs<S> {
+    fn read(&mut self, reg: u8) -> io::Result<u8> {
+        let resp = request(&self.0, OP_READ, reg, &[])?;
+        resp.first()
+            .cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Empty read response"))
+    }
+
+    fn write(&mut self, reg: u8, val: u8) -> io::Result<()> {
+        request(&self.0, OP_WRITE, reg, &[val]).map(|_| ())
+    }
+
+    fn burst_write(&mut self, reg: u8, val: &[u8]) -> io::Result<()> {
+        request(&self.0, OP_BURST_WRITE, reg, val).map(|_| ())
+    }
+
+    fn burst_read(&mut self, reg: u8, buf: &mut [u8]) -> io::Result<()> {
+        let len = buf.len() as u16;
+        let resp = request(&self.0, OP_BURST_READ, reg, &[(len >> 8) as u8, len as u8])?;
+        if resp.len() != buf.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Short burst read"));
+        }
+        buf.copy_from_slice(&resp);
+        Ok(())
+    }
+
+    /// Sends every burst before waiting for any response so the batch costs
+    /// one round trip
+    fn write_batch(&mut self, writes: &[(u8, &[u8])]) -> io::Result<()> {
+        for &(reg, val) in writes {
+            send_request(&self.0, OP_BURST_WRITE, reg, val)?;
+        }
+        let mut ret = Ok(());
+        for _ in writes {
+            if let Err(e) = read_response(&self.0) {
+                ret = Err(e);
+            }
+        }
+        ret
+    }
+}
+
+struct RemoteShutdown<S: Read + Write>(Conn<S>);
+
+impl<S: Read + Write> ShutdownLine for RemoteShutdown<S> {
+    fn in_shutdown(&mut self) -> io::Result<bool> {
+        request_flag(&self.0, OP_SHUTDOWN_GET, 0, &[])
+    }
+
+    fn set_shutdown(&mut self, shutdown: bool) -> io::Result<()> {
+        request(&self.0, OP_SHUTDOWN_SET, shutdown as u8, &[]).map(|_| ())
+    }
+}
+
+struct RemoteIrq<S: Read + Write>(Conn<S>);
+
+impl<S: Read + Write> IrqLine for RemoteIrq<S> {
+    fn asserted(&mut self) -> io::Result<bool> {
+        request_flag(&self.0, OP_IRQ_ASSERTED, 0, &[])
+    }
+
+    fn wait(&mut self, timeout_ms: isize) -> io::Result<bool> {
+        let timeout = timeout_ms.max(0) as u32;
+        request_flag(&self.0,
+                     OP_IRQ_WAIT,
+                     0,
+                     &[(timeout >> 24) as u8, (timeout >> 16) as u8, (timeout >> 8) as u8, timeout as u8])
+    }
+}
+
+/// Server side of the protocol. Owns the local register transport and lines.
+pub struct RegServer {
+    regs: Box<dyn RegRw>,
+    irq: Option<Box<dyn IrqLine>>,
+    shutdown: Option<Box<dyn ShutdownLine>>,
+}
+
+impl RegServer {
+    pub fn new(regs: Box<dyn RegRw>,
+               irq: Option<Box<dyn IrqLine>>,
+               shutdown: Option<Box<dyn ShutdownLine>>)
+               -> Self {
+        RegServer {
+            regs,
+            irq,
+            shutdown,
+        }
+    }
+
+    /// Accepts clients one at a time, forever
+    pub fn listen<A: ToSocketAddrs>(&mut self, addr: A) -> io::Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        info!("Listening on {}", listener.local_addr()?);
+        for stream in listener.incoming() {
+            let stream = stream?;
+            stream.set_nodelay(true)?;
+            let peer = stream.peer_addr()?;
+            info!("Client {} connected", peer);
+            match self.serve(stream) {
+                Ok(()) => info!("Client {} disconnected", peer),
+                Err(e) => warn!("Client {}: {}", peer, e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles requests until the client closes the stream
+    pub fn serve<S: Read + Write>(&mut self, mut stream: S) -> io::Result<()> {
+        loop {
+            let (op, reg, payload) = match read_frame(&mut stream) {
+                Ok(frame) => frame,
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            match self.handle(op, reg, &payload) {
+                Ok(resp) => write_frame(&mut stream, 0, 0, &resp)?,
+                Err(e) => write_frame(&mut stream, 1, 0, e.to_string().as_bytes())?,
+            }
+        }
+    }
+
+    /// Executes one request and returns the response payload
+    pub fn handle(&mut self, op: u8, reg: u8, payload: &[u8]) -> io::Result<Vec<u8>> {
+        let short = || io::Error::new(io::ErrorKind::InvalidInput, "Short request");
+        match op {
+            OP_READ => self.regs.read(reg).map(|val| vec![val]),
+            OP_WRITE => {
+                let val = *payload.first().ok_or_else(short)?;
+                self.regs.write(reg, val).map(|_| Vec::new())
+            }
+            OP_BURST_WRITE => self.regs.burst_write(reg, payload).map(|_| Vec::new()),
+            OP_BURST_READ => {
+                if payload.len() < 2 {
+                    return Err(short());
+                }
+                let mut buf = vec![0; (payload[0] as usize) << 8 | payload[1] as usize];
+                self.regs.burst_read(reg, &mut buf).map(|_| buf)
+            }
+            OP_CAPS => {
+                let mut caps = 0;
+                if self.shutdown.is_some() {
+                    caps |= CAP_SHUTDOWN;
+                }
+                if self.irq.is_some() {
+                    caps |= CAP_IRQ;
+                }
+                Ok(vec![caps])
+            }
+            OP_SHUTDOWN_GET => self.shutdown()?.in_shutdown().map(|s| vec![s as u8]),
+            OP_SHUTDOWN_SET => self.shutdown()?.set_shutdown(reg != 0).map(|_| Vec::new()),
+            OP_IRQ_ASSERTED => self.irq()?.asserted().map(|a| vec![a as u8]),
+            OP_IRQ_WAIT => {
+                if payload.len() < 4 {
+                    return Err(short());
+                }
+                let timeout = (payload[0] as u32) << 24 | (payload[1] as u32) << 16 |
+                              (payload[2] as u32) << 8 |
+                              payload[3] as u32;
+                self.irq()?.wait(timeout as isize).map(|a| vec![a as u8])
+            }
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown op 0x{:02x}", op))),
+        }
+    }
+
+    fn shutdown(&mut self) -> io::Result<&mut Box<dyn ShutdownLine>> {
+        self.shutdown.as_mut().ok_or_else(|| io::Error::other("No shutdown line"))
+    }
+
+    fn irq(&mut self) -> io::Result<&mut Box<dyn IrqLine>> {
+        self.irq.as_mut().ok_or_else(|| io::Error::other("No IRQ line"))
+    }
+}
+
+#[test]
+fn remote_transmit() {
+    use std::thread;
+
+    use regrw::EmuRegs;
+    use rfm::Rfm22;
+    use rfmconfig::Rfm22Config;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let mut server = RegServer::new(Box::new(EmuRegs::new()), None, None);
+        server.serve(listener.accept().unwrap().0).unwrap();
+    });
+
+    let mut regs = RemoteRegs::connect(addr).unwrap();
+    regs.write(0x10, 0xaa).unwrap();
+    regs.burst_write(0x11, &[0xbb, 0xcc]).unwrap();
+    let mut buf = [0; 3];
+    regs.burst_read(0x10, &mut buf).unwrap();
+    assert_eq!([0xaa, 0xbb, 0xcc], buf);
+    let (irq, shutdown) = regs.lines().unwrap();
+    assert!(irq.is_none() && shutdown.is_none());
+
+    {
+        // Goes through software reset, configuration and a FIFO transmit
+        let mut rf = Rfm22::from_regrw(Box::new(regs), irq, shutdown);
+        rf.configure(Rfm22Config::default()).unwrap();
+        rf.transmit_bitstream(vec![true, false, true]).unwrap();
+    }
+    server.join().unwrap();
+}
@@ -3,15 +3,33 @@ use std::io;
 use std::thread;
 use std::time::{Duration, Instant};
 
-use spidev::Spidev;
-use sysfs_gpio::{Direction, Edge, Pin, PinPoller};
 
-use regrw::{FakeRegs, RegRw, RfmReg, RfmRegs, RegLogger};
+use gpio::{IrqLine, ShutdownLine};
+use regrw::{RegRw, RfmReg, RegLogger};
+use rfmconfig::Rfm22Config;
 
 const FIFO_SIZE: usize = 64;
+/// Upper bound on power-on reset. The datasheet specifies 16.8ms from
+/// shutdown to TX, but boards have been seen to need closer to 30.
+const RESET_TIMEOUT_MS: u64 = 100;
+/// How often the configured registers are read back to catch a reset that
+/// didn't latch IPOR
+const VERIFY_INTERVAL_S: u64 = 60;
+/// A conversion takes about 350us
+const ADC_TIMEOUT_MS: u64 = 10;
+/// ADCSTART when written, ADC done when read
+const ADCSTART: u8 = 0x80;
+/// ADC input select of the temperature sensor, against the bandgap reference
+const ADCSEL_TEMPERATURE: u8 = 0x00;
+/// Temperature sensor range of -64 to 64°C in 0.5°C steps, with its offset on
+const TSRANGE_64: u8 = 0x20;
+/// Supply voltage at a battery voltage level or low battery threshold of 0.
+/// Each step is 50mV.
+const LBD_MIN_VOLTS: f64 = 1.7;
+const LBD_MAX: u8 = 0x1f;
 
 #[repr(u8)]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Rfm22RegVal {
     InterruptStatus1 = 0x3,
     InterruptStatus2 = 0x4,
@@ -19,6 +37,15 @@ pub enum Rfm22RegVal {
     InterruptEnable2 = 0x6,
     OperatingFunctionControl1 = 0x7,
     OperatingFunctionControl2 = 0x8,
+    Gpio0Configuration = 0xb,
+    Gpio1Configuration = 0xc,
+    Gpio2Configuration = 0xd,
+    AdcConfiguration = 0xf,
+    AdcValue = 0x11,
+    TemperatureSensorControl = 0x12,
+    TemperatureValueOffset = 0x13,
+    LowBatteryDetectorThreshold = 0x1a,
+    BatteryVoltageLevel = 0x1b,
     DataAccessControl = 0x30,
     HeaderControl2 = 0x33,
     TxPower = 0x6d,
@@ -173,6 +200,42 @@ rfreg! {
         ANTDIV2 = 7
     }
 }
+rfreg! {
+    Gpio0Configuration {
+        GPIO0CFG0 = 0,
+        GPIO0CFG1 = 1,
+        GPIO0CFG2 = 2,
+        GPIO0CFG3 = 3,
+        GPIO0CFG4 = 4,
+        PUP0 = 5,
+        GPIO0DRV0 = 6,
+        GPIO0DRV1 = 7
+    }
+}
+rfreg! {
+    Gpio1Configuration {
+        GPIO1CFG0 = 0,
+        GPIO1CFG1 = 1,
+        GPIO1CFG2 = 2,
+        GPIO1CFG3 = 3,
+        GPIO1CFG4 = 4,
+        PUP1 = 5,
+        GPIO1DRV0 = 6,
+        GPIO1DRV1 = 7
+    }
+}
+rfreg! {
+    Gpio2Configuration {
+        GPIO2CFG0 = 0,
+        GPIO2CFG1 = 1,
+        GPIO2CFG2 = 2,
+        GPIO2CFG3 = 3,
+        GPIO2CFG4 = 4,
+        PUP2 = 5,
+        GPIO2DRV0 = 6,
+        GPIO2DRV1 = 7
+    }
+}
 rfreg! {
     DataAccessControl {
         CRC0 = 0,
@@ -211,7 +274,7 @@ rfreg! {
 }
 
 impl TxPower {
-    fn set_tx_power(&mut self, power: u8) {
+    pub fn set_tx_power(&mut self, power: u8) {
         assert!(power <= 0x7);
         self.remove(TXPOW2 | TXPOW1 | TXPOW0);
         self.insert(Self::from_bits(power).unwrap());
@@ -232,7 +295,7 @@ rfreg! {
 }
 
 impl TxDataRate1 {
-    fn from_txdr(val: u16) -> Self {
+    pub fn from_txdr(val: u16) -> Self {
         Self::from_bits((val >> 8) as u8).unwrap()
     }
 }
@@ -251,7 +314,7 @@ rfreg! {
 }
 
 impl TxDataRate0 {
-    fn from_txdr(val: u16) -> Self {
+    pub fn from_txdr(val: u16) -> Self {
         Self::from_bits(val as u8).unwrap()
     }
 }
@@ -280,6 +343,8 @@ rfreg! {
 }
 
 #[allow(unused)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum ModulationType {
     Unmodulated,
     OOK,
@@ -288,6 +353,8 @@ pub enum ModulationType {
 }
 
 #[allow(unused)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum DataSource {
     DirectGPIO,
     DirectSDI,
@@ -315,6 +382,24 @@ impl ModulationModeControl2 {
             DataSource::PN9 => DTMOD1 | DTMOD0,
         });
     }
+
+    pub fn modtype(&self) -> ModulationType {
+        match (self.contains(MODTYP1), self.contains(MODTYP0)) {
+            (false, false) => ModulationType::Unmodulated,
+            (false, true) => ModulationType::OOK,
+            (true, false) => ModulationType::FSK,
+            (true, true) => ModulationType::GFSK,
+        }
+    }
+
+    pub fn data_source(&self) -> DataSource {
+        match (self.contains(DTMOD1), self.contains(DTMOD0)) {
+            (false, false) => DataSource::DirectGPIO,
+            (false, true) => DataSource::DirectSDI,
+            (true, false) => DataSource::FIFO,
+            (true, true) => DataSource::PN9,
+        }
+    }
 }
 
 rfreg! {
@@ -331,7 +416,7 @@ rfreg! {
 }
 
 impl FrequencyOffset1 {
-    fn from_frequency_offset(val: u16) -> Self {
+    pub fn from_frequency_offset(val: u16) -> Self {
         Self::from_bits(val as u8).unwrap()
     }
 }
@@ -344,7 +429,7 @@ rfreg! {
 }
 
 impl FrequencyOffset2 {
-    fn from_frequency_offset(val: u16) -> Self {
+    pub fn from_frequency_offset(val: u16) -> Self {
         Self::from_bits((val >> 8) as u8).unwrap()
     }
 }
@@ -362,7 +447,7 @@ rfreg! {
 }
 
 impl FrequencyBandSelect {
-    fn from_band(band: u8) -> Self {
+    pub fn from_band(band: u8) -> Self {
         Self::from_bits(band as u8).unwrap()
     }
 }
@@ -381,7 +466,7 @@ rfreg! {
 }
 
 impl CarrierFrequency1 {
-    fn from_carrier(val: u16) -> Self {
+    pub fn from_carrier(val: u16) -> Self {
         Self::from_bits((val >> 8) as u8).unwrap()
     }
 }
@@ -400,30 +485,136 @@ rfreg! {
 }
 
 impl CarrierFrequency0 {
-    fn from_carrier(val: u16) -> Self {
+    pub fn from_carrier(val: u16) -> Self {
         Self::from_bits(val as u8).unwrap()
     }
 }
 
+/// Registers the chip changes on its own. These are never served from the
+/// register cache.
+fn is_volatile(reg: u8) -> bool {
+    match reg {
+        // Device status and interrupt status
+        0x02..=0x04 => true,
+        // TXON/RXON clear when a packet completes, SWRES self-clears
+        0x07 => true,
+        // ADC start bit, ADC value
+        0x0f | 0x11 => true,
+        // Battery voltage, RSSI, antenna diversity RSSI
+        0x1b | 0x26 | 0x28 | 0x29 => true,
+        // AFC and OOK counter readouts, EZMAC status
+        0x2b | 0x2c | 0x31 => true,
+        // Received headers and packet length
+        0x47..=0x4b => true,
+        // FIFO
+        0x7f => true,
+        _ => false,
+    }
+}
+
+/// When register writes are read back to check them
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VerifyPolicy {
+    Always,
+    /// Only writes that change the register from its cached value. Without
+    /// the cache every write is a change.
+    OnChange,
+    Never,
+}
+
 pub struct Rfm22Regs {
-    regs: RegLogger<Box<RegRw>>,
+    regs: RegLogger<Box<dyn RegRw>>,
+    /// Last known value of each non-volatile register, if caching is enabled
+    cache: Option<Box<[Option<u8>; 0x80]>>,
+    verify: VerifyPolicy,
 }
 
 impl Rfm22Regs {
-    pub fn new(spi: Spidev) -> Self {
-        Rfm22Regs { regs: RegLogger(Box::new(RfmRegs::new(spi))) }
+    pub fn from_regrw(regs: Box<dyn RegRw>) -> Self {
+        Rfm22Regs {
+            regs: RegLogger(regs),
+            cache: None,
+            verify: VerifyPolicy::Always,
+        }
     }
 
-    pub fn dummy() -> Self {
-        Rfm22Regs { regs: RegLogger(Box::new(FakeRegs::new())) }
+    /// Serve reads of non-volatile registers from the values last read or
+    /// written instead of the bus
+    pub fn set_cache(&mut self, enable: bool) {
+        if enable {
+            if self.cache.is_none() {
+                self.cache = Some(Box::new([None; 0x80]));
+            }
+        } else {
+            self.cache = None;
+        }
+    }
+
+    pub fn set_verify_policy(&mut self, verify: VerifyPolicy) {
+        self.verify = verify;
+    }
+
+    /// Forget cached values. Must be called whenever the chip may have reset.
+    pub fn invalidate(&mut self) {
+        if let Some(ref mut cache) = self.cache {
+            **cache = [None; 0x80];
+        }
+    }
+
+    fn cached(&self, reg: u8) -> Option<u8> {
+        self.cache.as_ref().and_then(|cache| cache[reg as usize])
+    }
+
+    fn update_cache(&mut self, reg: u8, val: u8) {
+        if let Some(ref mut cache) = self.cache {
+            if !is_volatile(reg) {
+                cache[reg as usize] = Some(val);
+            }
+        }
+    }
+
+    fn read_reg(&mut self, reg: u8) -> io::Result<u8> {
+        if let Some(val) = self.cached(reg) {
+            return Ok(val);
+        }
+        let val = self.regs.read(reg)?;
+        self.update_cache(reg, val);
+        Ok(val)
+    }
+
+    /// Writes a register and returns whether it is due to be verified
+    fn write_reg(&mut self, reg: u8, val: u8) -> io::Result<bool> {
+        let changed = self.cached(reg) != Some(val);
+        self.regs.write(reg, val)?;
+        self.update_cache(reg, val);
+        Ok(match self.verify {
+            VerifyPolicy::Always => true,
+            VerifyPolicy::OnChange => changed,
+            VerifyPolicy::Never => false,
+        })
     }
 
     pub fn read<R: Rfm22Reg>(&mut self) -> io::Result<R> {
-        self.regs.read(R::regval()).map(|val| R::from_bits(val).unwrap())
+        self.read_reg(R::regval()).map(|val| R::from_bits(val).unwrap())
+    }
+
+    pub fn read_raw(&mut self, reg: Rfm22RegVal) -> io::Result<u8> {
+        self.read_reg(reg as u8)
+    }
+
+    /// Reads from the chip even if the register is cached
+    pub fn read_uncached(&mut self, reg: Rfm22RegVal) -> io::Result<u8> {
+        let val = self.regs.read(reg as u8)?;
+        self.update_cache(reg as u8, val);
+        Ok(val)
     }
 
     pub fn write<R: Rfm22Reg>(&mut self, val: R) -> io::Result<()> {
-        self.regs.write(R::regval(), val.bits())
+        self.write_reg(R::regval(), val.bits()).map(|_| ())
+    }
+
+    pub fn write_raw(&mut self, reg: Rfm22RegVal, val: u8) -> io::Result<()> {
+        self.write_reg(reg as u8, val).map(|_| ())
     }
 
     pub fn modify<R: Rfm22Reg, F>(&mut self, f: F) -> io::Result<()>
@@ -434,6 +625,7 @@ impl Rfm22Regs {
         self.write(val)
     }
 
+    #[allow(unused)]
     pub fn modify_verify<R: Rfm22Reg, F>(&mut self, f: F) -> io::Result<()>
         where F: FnOnce(&mut R)
     {
@@ -443,35 +635,113 @@ impl Rfm22Regs {
     }
 
     pub fn write_validate<R: Rfm22Reg>(&mut self, val: R) -> io::Result<()> {
-        self.write(val)?;
-        assert_eq!(val, self.read().unwrap());
+        if self.write_reg(R::regval(), val.bits())? {
+            let read = self.regs.read(R::regval())?;
+            assert_eq!(val, R::from_bits(read).unwrap());
+        }
         Ok(())
     }
 
     pub fn burst_write(&mut self, reg: Rfm22RegVal, buf: &[u8]) -> io::Result<()> {
-        self.regs.burst_write(reg as u8, buf)
+        self.regs.burst_write(reg as u8, buf)?;
+        // Mirror the chip's address auto-increment
+        let mut reg = reg as u8;
+        for val in buf {
+            self.update_cache(reg, *val);
+            if reg < 0x7f {
+                reg += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Collects the writes made by `f` and submits them to the bus together.
+    /// Writes to consecutive registers are merged into bursts. Verification
+    /// follows the verify policy and checks the final value of each register.
+    pub fn transaction<F>(&mut self, f: F) -> io::Result<()>
+        where F: FnOnce(&mut Rfm22Txn)
+    {
+        let mut txn = Rfm22Txn(Vec::new());
+        f(&mut txn);
+        if txn.0.is_empty() {
+            return Ok(());
+        }
+
+        let mut verify: Vec<(u8, u8)> = Vec::new();
+        for &(reg, val) in &txn.0 {
+            let changed = self.cached(reg) != Some(val);
+            let check = match self.verify {
+                VerifyPolicy::Always => true,
+                VerifyPolicy::OnChange => changed,
+                VerifyPolicy::Never => false,
+            };
+            verify.retain(|v| v.0 != reg);
+            if check {
+                verify.push((reg, val));
+            }
+            self.update_cache(reg, val);
+        }
+
+        let bursts = coalesce_writes(&txn.0);
+        let batch: Vec<(u8, &[u8])> = bursts.iter().map(|b| (b.0, &b.1[..])).collect();
+        self.regs.write_batch(&batch)?;
+
+        for (reg, val) in verify {
+            assert_eq!(val, self.regs.read(reg)?, "Register 0x{:02x} verify failed", reg);
+        }
+        Ok(())
+    }
+}
+
+/// Register writes queued by `Rfm22Regs::transaction`
+pub struct Rfm22Txn(Vec<(u8, u8)>);
+
+impl Rfm22Txn {
+    pub fn write<R: Rfm22Reg>(&mut self, val: R) {
+        self.0.push((R::regval(), val.bits()))
+    }
+
+    pub fn write_raw(&mut self, reg: Rfm22RegVal, val: u8) {
+        self.0.push((reg as u8, val))
     }
 }
 
+/// Merges runs of writes to ascending consecutive registers into bursts,
+/// keeping the original order. The FIFO doesn't auto-increment so it is never
+/// merged.
+fn coalesce_writes(writes: &[(u8, u8)]) -> Vec<(u8, Vec<u8>)> {
+    let mut bursts: Vec<(u8, Vec<u8>)> = Vec::new();
+    for &(reg, val) in writes {
+        if let Some(last) = bursts.last_mut() {
+            let next = last.0 as usize + last.1.len();
+            if reg != 0x7f && reg as usize == next {
+                last.1.push(val);
+                continue;
+            }
+        }
+        bursts.push((reg, vec![val]));
+    }
+    bursts
+}
+
 struct Rfm22IRQs {
     pending: InterruptStatus1,
     enabled: InterruptEnable1,
-    gpio_poller: Option<(Pin, PinPoller)>,
+    /// Status 2 events seen but not yet handled
+    pending2: InterruptStatus2,
+    enabled2: InterruptEnable2,
+    line: Option<Box<dyn IrqLine>>,
     dummy: bool,
 }
 
 impl Rfm22IRQs {
-    fn new(mut gpio: Option<Pin>) -> Self {
-        if let Some(ref mut pin) = gpio {
-            pin.set_edge(Edge::FallingEdge).unwrap();
-        }
+    fn new(line: Option<Box<dyn IrqLine>>) -> Self {
         Rfm22IRQs {
             pending: InterruptStatus1::empty(),
             enabled: InterruptEnable1::empty(),
-            gpio_poller: gpio.map(|pin| {
-                let poller = pin.get_poller().unwrap();
-                (pin, poller)
-            }),
+            pending2: InterruptStatus2::empty(),
+            enabled2: InterruptEnable2::empty(),
+            line,
             dummy: false,
         }
     }
@@ -480,7 +750,9 @@ impl Rfm22IRQs {
         Rfm22IRQs {
             pending: InterruptStatus1::empty(),
             enabled: InterruptEnable1::empty(),
-            gpio_poller: None,
+            pending2: InterruptStatus2::empty(),
+            enabled2: InterruptEnable2::empty(),
+            line: None,
             dummy: true,
         }
     }
@@ -499,13 +771,14 @@ impl Rfm22IRQs {
         }
     }
 
-    fn _wait_for_change(&mut self) {
-        if let Some((ref mut pin, ref mut poller)) = self.gpio_poller {
-            if pin.get_value().unwrap() > 0 {
+    fn _wait_for_change(&mut self, timeout_ms: isize) {
+        if let Some(ref mut line) = self.line {
+            if !line.asserted().unwrap() {
                 debug!("Poll started");
-                match poller.poll(1000).unwrap() {
-                    Some(_) => debug!("Poll finished"),
-                    None => debug!("Timed out: {}", pin.get_value().unwrap()),
+                if line.wait(timeout_ms).unwrap() {
+                    debug!("Poll finished");
+                } else {
+                    debug!("Timed out: {}", line.asserted().unwrap());
                 }
             }
         } else {
@@ -527,17 +800,56 @@ impl Rfm22IRQs {
                 error!("Timed out");
                 return Err(io::Error::new(io::ErrorKind::TimedOut, "IRQ polling timed out"));
             }
-            self._wait_for_change();
+            self._wait_for_change(1000);
             pnd = self.poll(regs)?;
             debug!("pending {:?}", pnd);
         }
         Ok(irqs)
     }
 
+    /// Waits for ICHIPRDY after a reset. Status 2 is read directly rather than
+    /// through the pending set since reset discards the enable state anyway.
+    fn wait_ready(&mut self, regs: &mut Rfm22Regs) -> io::Result<InterruptStatus2> {
+        if self.dummy {
+            return Ok(IPOR | ICHIPRDY);
+        }
+        let start = Instant::now();
+        let timeout = Duration::from_millis(RESET_TIMEOUT_MS);
+        let mut seen = InterruptStatus2::empty();
+        loop {
+            let status: InterruptStatus2 = regs.read()?;
+            // MISO floats high until the chip is out of POR. All-ones is not a
+            // plausible status, so don't mistake it for ready.
+            if status != InterruptStatus2::all() {
+                seen.insert(status);
+            }
+            if seen.contains(ICHIPRDY) {
+                debug!("Ready status {:?}", seen);
+                return Ok(seen);
+            }
+            if start.elapsed() > timeout {
+                error!("Timed out waiting for chip ready");
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "Chip ready timed out"));
+            }
+            self._wait_for_change(RESET_TIMEOUT_MS as isize);
+        }
+    }
+
     fn handled(&mut self, irqs: InterruptStatus1) {
         self.pending.remove(irqs)
     }
 
+    /// Returns the status 2 events seen since they were last handled.
+    /// Reading the register clears it, so events are kept here until then.
+    fn poll2(&mut self, regs: &mut Rfm22Regs) -> io::Result<InterruptStatus2> {
+        self.pending2.insert(regs.read()?);
+        Ok(self.pending2)
+    }
+
+    fn handled2(&mut self, irqs: InterruptStatus2) {
+        self.pending2.remove(irqs)
+    }
+
     /// Clears all enabled IRQs in hardware and clears all considered pending
     fn clear(&mut self, regs: &mut Rfm22Regs) -> io::Result<()> {
         self.poll(regs).map(|pnd| self.handled(pnd))
@@ -550,129 +862,170 @@ impl Rfm22IRQs {
         toclear.remove(irqs.into());
         self.pending.remove(toclear);
 
-        regs.write_validate(irqs)?;
-        regs.write_validate(InterruptEnable2::empty())
+        let irqs2 = self.enabled2;
+        regs.transaction(|txn| {
+            txn.write(irqs);
+            txn.write(irqs2);
+        })
+    }
+
+    fn set_enable2(&mut self, regs: &mut Rfm22Regs, irqs: InterruptEnable2) -> io::Result<()> {
+        self.enabled2 = irqs;
+        regs.write(irqs)
     }
 }
 
 pub struct Rfm22 {
     pub regs: Rfm22Regs,
     irq: Rfm22IRQs,
-    shutdown: Option<Pin>,
+    shutdown: Option<Box<dyn ShutdownLine>>,
+    config: Option<Rfm22Config>,
+    last_verify: Instant,
+    recoveries: u32,
+    /// Low battery detector threshold, kept across reconfiguration
+    low_battery: Option<u8>,
 }
 
 impl Rfm22 {
-    pub fn new(spi: Spidev, mut irq: Option<Pin>, mut shutdown: Option<Pin>) -> Self {
-        if let Some(ref mut sdn) = shutdown {
-            sdn.export().unwrap();
+    /// Drives the chip over any register transport. The IRQ and shutdown
+    /// lines are optional.
+    pub fn from_regrw(regs: Box<dyn RegRw>,
+                      irq: Option<Box<dyn IrqLine>>,
+                      shutdown: Option<Box<dyn ShutdownLine>>)
+                      -> Self {
+        let mut rf = Rfm22 {
+            regs: Rfm22Regs::from_regrw(regs),
+            irq: Rfm22IRQs::new(irq),
+            shutdown,
+            config: None,
+            last_verify: Instant::now(),
+            recoveries: 0,
+            low_battery: None,
+        };
+        let duration = rf.reset().unwrap();
+        info!("Reset complete in {:?}", duration);
+        rf
+    }
+
+    /// Resets the chip and waits for it to report ready. Toggles the shutdown
+    /// line if one is wired, otherwise issues a software reset. Returns how
+    /// long the chip took to become ready after leaving reset.
+    pub fn reset(&mut self) -> io::Result<Duration> {
+        let start = if let Some(ref mut sdn) = self.shutdown {
             // Put in reset if not already
-            let in_reset = match sdn.get_direction().unwrap() {
-                Direction::High => true,
-                Direction::Out => sdn.get_value().unwrap() > 0,
-                _ => false,
-            };
-            if !in_reset {
+            if !sdn.in_shutdown()? {
                 debug!("Resetting");
-                sdn.set_direction(Direction::High).unwrap();
+                sdn.set_shutdown(true)?;
                 thread::sleep(Duration::from_millis(1));
             } else {
                 debug!("Already in reset");
             }
-            // Bring out of reset
-            sdn.set_direction(Direction::Low).unwrap();
-            // 16.8ms specified from shutdown to TX
-            // 20 does not work
-            // 30 works
-            // Using 40 for margin
-            // Should wait on IRQ
-            thread::sleep(Duration::from_millis(40));
-            info!("Reset complete");
-        }
-        if let Some(ref mut irq) = irq {
-            irq.export().unwrap();
-        }
-        Rfm22 {
-            regs: Rfm22Regs::new(spi),
-            irq: Rfm22IRQs::new(irq),
-            shutdown: shutdown,
-        }
+            // Bring out of reset. ENPOR and ENCHIPRDY are enabled by default
+            // after POR, so there is nothing to set up before waiting.
+            sdn.set_shutdown(false)?;
+            Instant::now()
+        } else {
+            debug!("Software reset");
+            self.regs.write(ENPOR | ENCHIPRDY)?;
+            // Discard stale status so only the new ready event is seen
+            self.regs.read::<InterruptStatus2>()?;
+            self.irq.handled2(InterruptStatus2::all());
+            // SWRES self-clears, so this can't be validated
+            self.regs.write(SWRES)?;
+            Instant::now()
+        };
+        self.regs.invalidate();
+        self.irq.wait_ready(&mut self.regs)?;
+        Ok(start.elapsed())
     }
 
+    #[cfg(test)]
     pub fn dummy() -> Self {
+        Self::dummy_regrw(Box::new(::regrw::FakeRegs::new()))
+    }
+
+    /// Uses a register transport with no chip behind it. No reset is done and
+    /// IRQs always read as pending.
+    pub fn dummy_regrw(regs: Box<dyn RegRw>) -> Self {
         Rfm22 {
-            regs: Rfm22Regs::dummy(),
+            regs: Rfm22Regs::from_regrw(regs),
             irq: Rfm22IRQs::dummy(),
             shutdown: None,
+            config: None,
+            last_verify: Instant::now(),
+            recoveries: 0,
+            low_battery: None,
         }
     }
 
-    pub fn set_modulation_type_and_source(&mut self,
-                                          ty: ModulationType,
-                                          source: DataSource)
-                                          -> io::Result<()> {
-        self.regs.modify_verify(|reg: &mut ModulationModeControl2| {
-            reg.set_modtype(ty);
-            reg.set_data_source(source);
-        })
+    /// Applies a full radio configuration and keeps it so it can be restored
+    /// if the chip resets behind our back. Only registers that differ from
+    /// the requested configuration are written.
+    pub fn configure(&mut self, config: Rfm22Config) -> io::Result<()> {
+        config.validate()?;
+        self.init()?;
+        config.apply(self)?;
+        // init turned the detector off
+        if self.low_battery.is_some() {
+            self.apply_low_battery()?;
+        }
+        // Clear any POR from before the config was applied
+        self.irq.poll2(&mut self.regs)?;
+        self.irq.handled2(IPOR);
+        self.config = Some(config);
+        self.last_verify = Instant::now();
+        Ok(())
     }
 
-    pub fn set_tx_power(&mut self, power: u8) -> io::Result<()> {
-        self.regs.modify_verify(|reg: &mut TxPower| reg.set_tx_power(power))
+    /// Checks whether the chip has lost its configuration since it was
+    /// applied and re-applies it if so. IPOR is checked on every call. The
+    /// registers themselves are compared at most every VERIFY_INTERVAL_S.
+    /// Returns true if the configuration had to be restored. If restoring
+    /// fails, the next call tries again.
+    pub fn check_config(&mut self) -> io::Result<bool> {
+        if self.config.is_none() {
+            return Ok(false);
+        }
+        // IPOR stays pending until configure succeeds
+        let status = self.irq.poll2(&mut self.regs)?;
+        if status.contains(IPOR) {
+            warn!("Power-on reset detected");
+            self.regs.invalidate();
+        } else if self.last_verify.elapsed() < Duration::from_secs(VERIFY_INTERVAL_S) {
+            return Ok(false);
+        } else if self.verify_config()? {
+            self.last_verify = Instant::now();
+            return Ok(false);
+        } else {
+            self.regs.invalidate();
+            debug!("Chip config {:?}", Rfm22Config::read_from(self));
+        }
+        self.recoveries += 1;
+        warn!("Re-applying radio configuration (recovery {})", self.recoveries);
+        let config = self.config.clone().unwrap();
+        self.configure(config)?;
+        Ok(true)
     }
 
-    pub fn set_freq_mhz(&mut self, freq: f64) -> io::Result<()> {
-        let band = (freq as u32 - 240) / 10;
-        assert!(band <= 0x1f);
+    /// How many times the configuration has been re-applied, including
+    /// attempts that failed
+    pub fn recoveries(&self) -> u32 {
+        self.recoveries
+    }
 
-        let mut bandsel = FrequencyBandSelect::from_band(band as u8);
-        if freq >= 480.0 {
-            bandsel |= HBSEL;
+    /// Compares the chip registers against the stored configuration
+    fn verify_config(&mut self) -> io::Result<bool> {
+        match self.config {
+            Some(ref config) => config.verify(&mut self.regs),
+            None => Ok(true),
         }
-
-        let foffset = 0;
-
-        let mut fcarrier = freq;
-        if bandsel.contains(HBSEL) {
-            fcarrier /= 20.0;
-        } else {
-            fcarrier /= 10.0;
-        }
-        fcarrier -= (band + 24) as f64;
-        fcarrier *= 64000.0;
-        let fcarrier = fcarrier as u64;
-        debug!("Fcarrier {}", fcarrier);
-        assert!(fcarrier <= 0xffff);
-
-        self.regs.write_validate(bandsel)?;
-        self.regs.write_validate(FrequencyOffset1::from_frequency_offset(foffset))?;
-        self.regs.write_validate(FrequencyOffset2::from_frequency_offset(foffset))?;
-        self.regs.write_validate(CarrierFrequency1::from_carrier(fcarrier as u16))?;
-        self.regs.write_validate(CarrierFrequency0::from_carrier(fcarrier as u16))
-    }
-
-    pub fn set_data_rate_hz(&mut self, rate: f64) -> io::Result<()> {
-        let scale = rate < 30000.0;
-        self.regs
-            .modify_verify(|mc1: &mut ModulationModeControl1| {
-                if scale {
-                    *mc1 |= TXDRTSCALE;
-                }
-            })?;
-        let exp = if scale { 16 + 5 } else { 16 };
-        let txdr = rate * (1 << exp) as f64;
-        let txdr = (txdr / 1000000.0) as u64;
-        assert!(txdr <= 0xffff);
-        self.regs.write_validate(TxDataRate1::from_txdr(txdr as u16))?;
-        self.regs.write_validate(TxDataRate0::from_txdr(txdr as u16))
     }
 
     fn clear_tx_fifo(&mut self) -> io::Result<()> {
-        self.regs
-            .modify_verify(|reg: &mut OperatingFunctionControl2| {
-                reg.insert(FFCLRTX);
-            })?;
-        self.regs.modify_verify(|reg: &mut OperatingFunctionControl2| {
-            reg.remove(FFCLRTX);
+        let reg: OperatingFunctionControl2 = self.regs.read()?;
+        self.regs.transaction(|txn| {
+            txn.write(reg | FFCLRTX);
+            txn.write(reg - FFCLRTX);
         })
     }
 
@@ -721,37 +1074,117 @@ impl Rfm22 {
     pub fn transmit_bitstream<'a, I: IntoIterator<Item = bool>>(&mut self,
                                                                 iter: I)
                                                                 -> io::Result<()> {
-        struct BitsToBytes<I: Iterator<Item = bool>>(I);
+        self.check_config()?;
+        self.transmit_large(BitsToBytes(iter.into_iter()))
+    }
 
-        impl<I: Iterator<Item = bool>> Iterator for BitsToBytes<I> {
-            type Item = u8;
+    pub fn init(&mut self) -> io::Result<()> {
+        self.regs.write_validate(XTON | PLLON)
+    }
 
-            fn next(&mut self) -> Option<Self::Item> {
-                let mut val = 0;
-                if let Some(bit) = self.0.next() {
-                    if bit {
-                        val |= 1 << 7;
-                    }
-                } else {
-                    return None;
-                }
-                // Finish the byte if there was at least 1 bit
-                for idx in (0..7).into_iter().rev() {
-                    if let Some(bit) = self.0.next() {
-                        if bit {
-                            val |= 1 << idx;
-                        }
-                    }
-                }
-                Some(val)
+    /// Reads the on-chip temperature sensor in °C. It isn't calibrated, so
+    /// expect it to be a few degrees out.
+    pub fn read_temperature(&mut self) -> io::Result<f64> {
+        self.regs.write_raw(Rfm22RegVal::TemperatureSensorControl, TSRANGE_64)?;
+        self.regs.write_raw(Rfm22RegVal::TemperatureValueOffset, 0)?;
+        let val = self.read_adc(ADCSEL_TEMPERATURE)?;
+        Ok(val as f64 * 0.5 - 64.0)
+    }
+
+    /// Runs one conversion of the ADC on `input` and returns its value
+    fn read_adc(&mut self, input: u8) -> io::Result<u8> {
+        self.regs.write_raw(Rfm22RegVal::AdcConfiguration, input | ADCSTART)?;
+        let start = Instant::now();
+        while self.regs.read_uncached(Rfm22RegVal::AdcConfiguration)? & ADCSTART == 0 {
+            if start.elapsed() > Duration::from_millis(ADC_TIMEOUT_MS) {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "ADC conversion timed out"));
             }
         }
+        self.regs.read_uncached(Rfm22RegVal::AdcValue)
+    }
 
-        self.transmit_large(BitsToBytes(iter.into_iter()))
+    /// Reads the supply voltage, from 1.7 to 3.25V in 50mV steps. The low
+    /// battery detector does the measuring, so it's turned on for the
+    /// reading if it isn't already.
+    pub fn read_supply_voltage(&mut self) -> io::Result<f64> {
+        let reg: OperatingFunctionControl1 = self.regs.read()?;
+        if !reg.contains(ENLBD) {
+            self.regs.write(reg | ENLBD)?;
+            // A measurement takes about 250us
+            thread::sleep(Duration::from_millis(1));
+        }
+        let level = self.regs.read_uncached(Rfm22RegVal::BatteryVoltageLevel)? & LBD_MAX;
+        if !reg.contains(ENLBD) {
+            self.regs.write(reg)?;
+        }
+        Ok(LBD_MIN_VOLTS + level as f64 * 0.05)
     }
 
-    pub fn init(&mut self) {
-        self.regs.write_validate(XTON | PLLON).unwrap();
+    /// Raises ILBDET when the supply drops below `threshold` volts, or turns
+    /// the low battery detector off for `None`. The threshold is rounded to
+    /// the nearest 50mV from 1.7 to 3.25V.
+    pub fn set_low_battery(&mut self, threshold: Option<f64>) -> io::Result<()> {
+        self.low_battery = threshold.map(|volts| {
+            ((volts - LBD_MIN_VOLTS) / 0.05).round().clamp(0.0, LBD_MAX as f64) as u8
+        });
+        self.apply_low_battery()
+    }
+
+    /// The low battery threshold in volts, if the detector is on
+    pub fn low_battery_threshold(&self) -> Option<f64> {
+        self.low_battery.map(|level| LBD_MIN_VOLTS + level as f64 * 0.05)
+    }
+
+    /// Whether the supply has dropped below the low battery threshold since
+    /// the last call. Always false with the detector off.
+    pub fn low_battery(&mut self) -> io::Result<bool> {
+        let status = self.irq.poll2(&mut self.regs)?;
+        self.irq.handled2(ILBDET);
+        Ok(self.low_battery.is_some() && status.contains(ILBDET))
+    }
+
+    fn apply_low_battery(&mut self) -> io::Result<()> {
+        let mut irqs = self.irq.enabled2;
+        match self.low_battery {
+            Some(level) => {
+                self.regs.write_raw(Rfm22RegVal::LowBatteryDetectorThreshold, level)?;
+                self.regs.modify(|reg: &mut OperatingFunctionControl1| reg.insert(ENLBD))?;
+                irqs.insert(ENLBDET);
+            }
+            None => {
+                self.regs.modify(|reg: &mut OperatingFunctionControl1| reg.remove(ENLBD))?;
+                irqs.remove(ENLBDET);
+            }
+        }
+        self.irq.set_enable2(&mut self.regs, irqs)
+    }
+}
+
+/// Packs symbols into FIFO bytes, MSB first. The last byte is padded with 0
+/// symbols.
+pub struct BitsToBytes<I: Iterator<Item = bool>>(pub I);
+
+impl<I: Iterator<Item = bool>> Iterator for BitsToBytes<I> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut val = 0;
+        if let Some(bit) = self.0.next() {
+            if bit {
+                val |= 1 << 7;
+            }
+        } else {
+            return None;
+        }
+        // Finish the byte if there was at least 1 bit
+        for idx in (0..7).into_iter().rev() {
+            if let Some(bit) = self.0.next() {
+                if bit {
+                    val |= 1 << idx;
+                }
+            }
+        }
+        Some(val)
     }
 }
 
@@ -759,7 +1192,171 @@ impl Drop for Rfm22 {
     fn drop(&mut self) {
         // Put in reset when no longer in use
         if let Some(ref mut sdn) = self.shutdown {
-            sdn.set_value(1).unwrap();
+            if let Err(e) = sdn.set_shutdown(true) {
+                error!("Unable to enter shutdown: {}", e);
+            }
+        }
+    }
+}
+
+#[test]
+fn config_restored_after_reset() {
+    let mut rf = Rfm22::dummy();
+    rf.configure(Rfm22Config::default()).unwrap();
+    let carrier: CarrierFrequency1 = rf.regs.read().unwrap();
+    assert!(!rf.check_config().unwrap());
+    assert!(rf.verify_config().unwrap());
+
+    // Chip comes back from a brown-out with its default carrier
+    rf.regs.write(CarrierFrequency1::from_bits(0xbb).unwrap()).unwrap();
+    assert!(!rf.verify_config().unwrap());
+    rf.regs.write(IPOR).unwrap();
+    assert!(rf.check_config().unwrap());
+    assert_eq!(carrier, rf.regs.read().unwrap());
+    assert!(rf.verify_config().unwrap());
+    assert!(!rf.check_config().unwrap());
+}
+
+#[test]
+fn config_restore_retried() {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use regrw::FakeRegs;
+
+    /// Fails every write while the flag is set, like SPI during a brown-out
+    struct FlakyRegs(FakeRegs, Rc<Cell<bool>>);
+
+    impl FlakyRegs {
+        fn check(&self) -> io::Result<()> {
+            if self.1.get() {
+                Err(io::Error::other("SPI write failed"))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    impl RegRw for FlakyRegs {
+        fn read(&mut self, reg: u8) -> io::Result<u8> {
+            self.0.read(reg)
+        }
+
+        fn write(&mut self, reg: u8, val: u8) -> io::Result<()> {
+            self.check()?;
+            self.0.write(reg, val)
+        }
+
+        fn burst_write(&mut self, reg: u8, val: &[u8]) -> io::Result<()> {
+            self.check()?;
+            self.0.burst_write(reg, val)
         }
     }
+
+    let fail = Rc::new(Cell::new(false));
+    let mut rf = Rfm22::dummy_regrw(Box::new(FlakyRegs(FakeRegs::new(), fail.clone())));
+    rf.configure(Rfm22Config::default()).unwrap();
+    let carrier: CarrierFrequency1 = rf.regs.read().unwrap();
+
+    rf.regs.write(CarrierFrequency1::from_bits(0xbb).unwrap()).unwrap();
+    rf.regs.write(IPOR).unwrap();
+    fail.set(true);
+    assert!(rf.check_config().is_err());
+    assert_eq!(1, rf.recoveries());
+
+    // The chip cleared IPOR when it was read, but the restore is still due
+    fail.set(false);
+    rf.regs.write(InterruptStatus2::empty()).unwrap();
+    assert!(rf.check_config().unwrap());
+    assert_eq!(carrier, rf.regs.read().unwrap());
+    assert_eq!(2, rf.recoveries());
+    assert!(!rf.check_config().unwrap());
+}
+
+#[test]
+fn register_cache() {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use regrw::FakeRegs;
+
+    struct CountingRegs(FakeRegs, Rc<Cell<usize>>);
+
+    impl RegRw for CountingRegs {
+        fn read(&mut self, reg: u8) -> io::Result<u8> {
+            self.1.set(self.1.get() + 1);
+            self.0.read(reg)
+        }
+        fn write(&mut self, reg: u8, val: u8) -> io::Result<()> {
+            self.1.set(self.1.get() + 1);
+            self.0.write(reg, val)
+        }
+        fn burst_write(&mut self, reg: u8, val: &[u8]) -> io::Result<()> {
+            self.1.set(self.1.get() + 1);
+            self.0.burst_write(reg, val)
+        }
+    }
+
+    let count = Rc::new(Cell::new(0));
+    let mut regs = Rfm22Regs::from_regrw(Box::new(CountingRegs(FakeRegs::new(), count.clone())));
+    regs.set_cache(true);
+    regs.set_verify_policy(VerifyPolicy::OnChange);
+
+    // Read, write and verify, then the same value again served from cache
+    // and not verified
+    regs.modify_verify(|reg: &mut TxPower| reg.set_tx_power(3)).unwrap();
+    assert_eq!(3, count.get());
+    regs.modify_verify(|reg: &mut TxPower| reg.set_tx_power(3)).unwrap();
+    assert_eq!(4, count.get());
+    // Volatile registers always go to the chip
+    regs.read::<InterruptStatus1>().unwrap();
+    regs.read::<InterruptStatus1>().unwrap();
+    assert_eq!(6, count.get());
+    // Uncached reads are refreshed after an invalidate
+    regs.invalidate();
+    assert_eq!(TXPOW0 | TXPOW1, regs.read().unwrap());
+    assert_eq!(7, count.get());
+
+    regs.set_verify_policy(VerifyPolicy::Never);
+    regs.write_validate(TXPOW2).unwrap();
+    assert_eq!(8, count.get());
+}
+
+#[test]
+fn coalesce() {
+    let writes = [(0x73, 1), (0x74, 2), (0x75, 3), (0x30, 4), (0x7f, 5), (0x7f, 6), (0x31, 7)];
+    assert_eq!(vec![(0x73, vec![1, 2, 3]), (0x30, vec![4]), (0x7f, vec![5]), (0x7f, vec![6]),
+                    (0x31, vec![7])],
+               coalesce_writes(&writes));
+}
+
+#[test]
+fn chip_temperature() {
+    let mut rf = Rfm22::dummy_regrw(Box::new(::regrw::EmuRegs::new()));
+    assert_eq!(25.0, rf.read_temperature().unwrap());
+}
+
+#[test]
+fn low_battery() {
+    let mut rf = Rfm22::dummy_regrw(Box::new(::regrw::EmuRegs::new()));
+    rf.configure(Rfm22Config::default()).unwrap();
+    assert_eq!(3.0, rf.read_supply_voltage().unwrap());
+    let reg: OperatingFunctionControl1 = rf.regs.read().unwrap();
+    assert!(!reg.contains(ENLBD));
+
+    rf.set_low_battery(Some(2.7)).unwrap();
+    assert_eq!(Some(2.7), rf.low_battery_threshold());
+    assert!(!rf.low_battery().unwrap());
+    // Survives a reconfiguration, and a transmit's IRQ setup
+    rf.configure(Rfm22Config::default()).unwrap();
+    rf.transmit_bitstream(vec![true; 8]).unwrap();
+    let reg: InterruptEnable2 = rf.regs.read().unwrap();
+    assert_eq!(ENLBDET, reg);
+
+    rf.set_low_battery(Some(3.1)).unwrap();
+    assert!(rf.low_battery().unwrap());
+    assert!(!rf.low_battery().unwrap());
+    rf.set_low_battery(None).unwrap();
+    let reg: OperatingFunctionControl1 = rf.regs.read().unwrap();
+    assert!(!reg.contains(ENLBD));
 }
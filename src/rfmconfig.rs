@@ -0,0 +1,452 @@
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use serde_json;
+
+use rfm::*;
+
+/// Functions that can be routed to the GPIO pins. Reserved and test settings
+/// are left out.
+#[allow(unused)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GpioFunction {
+    PowerOnReset = 0x00,
+    WakeUpTimer = 0x01,
+    LowBattery = 0x02,
+    DirectInput = 0x03,
+    ExtIrqFalling = 0x04,
+    ExtIrqRising = 0x05,
+    ExtIrqChange = 0x06,
+    AdcInput = 0x07,
+    DirectOutput = 0x0a,
+    ReferenceVoltage = 0x0e,
+    DataClock = 0x0f,
+    TxDataInput = 0x10,
+    RetransmitRequest = 0x11,
+    TxState = 0x12,
+    TxFifoAlmostFull = 0x13,
+    RxData = 0x14,
+    RxState = 0x15,
+    RxFifoAlmostFull = 0x16,
+    Antenna1 = 0x17,
+    Antenna2 = 0x18,
+    ValidPreamble = 0x19,
+    InvalidPreamble = 0x1a,
+    SyncDetected = 0x1b,
+    ClearChannel = 0x1c,
+    Vdd = 0x1d,
+    Gnd = 0x1e,
+}
+
+const GPIO_FUNCTIONS: &[GpioFunction] = &[GpioFunction::PowerOnReset,
+                                          GpioFunction::WakeUpTimer,
+                                          GpioFunction::LowBattery,
+                                          GpioFunction::DirectInput,
+                                          GpioFunction::ExtIrqFalling,
+                                          GpioFunction::ExtIrqRising,
+                                          GpioFunction::ExtIrqChange,
+                                          GpioFunction::AdcInput,
+                                          GpioFunction::DirectOutput,
+                                          GpioFunction::ReferenceVoltage,
+                                          GpioFunction::DataClock,
+                                          GpioFunction::TxDataInput,
+                                          GpioFunction::RetransmitRequest,
+                                          GpioFunction::TxState,
+                                          GpioFunction::TxFifoAlmostFull,
+                                          GpioFunction::RxData,
+                                          GpioFunction::RxState,
+                                          GpioFunction::RxFifoAlmostFull,
+                                          GpioFunction::Antenna1,
+                                          GpioFunction::Antenna2,
+                                          GpioFunction::ValidPreamble,
+                                          GpioFunction::InvalidPreamble,
+                                          GpioFunction::SyncDetected,
+                                          GpioFunction::ClearChannel,
+                                          GpioFunction::Vdd,
+                                          GpioFunction::Gnd];
+
+impl GpioFunction {
+    fn from_bits(bits: u8) -> Option<Self> {
+        GPIO_FUNCTIONS.iter().find(|f| **f as u8 == bits).cloned()
+    }
+}
+
+/// Bits of the GPIOx configuration registers that select the pin function
+const GPIO_FUNCTION_MASK: u8 = 0x1f;
+
+/// Packet handler settings. Bits of DataAccessControl and HeaderControl2 not
+/// covered here are written as zero.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PacketConfig {
+    pub tx_handler: bool,
+    pub rx_handler: bool,
+    pub crc: bool,
+    pub lsb_first: bool,
+    pub skip_sync: bool,
+}
+
+impl PacketConfig {
+    fn data_access(&self) -> DataAccessControl {
+        let mut reg = DataAccessControl::empty();
+        if self.tx_handler {
+            reg |= ENPACTX;
+        }
+        if self.rx_handler {
+            reg |= ENPACRX;
+        }
+        if self.crc {
+            reg |= ENCRC;
+        }
+        if self.lsb_first {
+            reg |= LSBFIRST;
+        }
+        reg
+    }
+
+    fn header_control(&self) -> HeaderControl2 {
+        if self.skip_sync {
+            SKIPSYN
+        } else {
+            HeaderControl2::empty()
+        }
+    }
+}
+
+/// Complete radio setup, kept by `Rfm22` so it can be restored after a reset.
+/// The defaults are the settings used for the fan remotes.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Rfm22Config {
+    pub modulation: ModulationType,
+    pub data_source: DataSource,
+    pub freq_mhz: f64,
+    pub freq_offset_hz: f64,
+    pub data_rate_hz: f64,
+    pub tx_power: u8,
+    pub packet: PacketConfig,
+    /// Function for GPIO0-2. None leaves the pin as the chip has it.
+    pub gpio: [Option<GpioFunction>; 3],
+}
+
+impl Default for Rfm22Config {
+    fn default() -> Self {
+        Rfm22Config {
+            modulation: ModulationType::OOK,
+            data_source: DataSource::FIFO,
+            freq_mhz: 303.8,
+            freq_offset_hz: 0.0,
+            data_rate_hz: 3000.0,
+            tx_power: 3,
+            packet: PacketConfig { skip_sync: true, ..PacketConfig::default() },
+            gpio: [None; 3],
+        }
+    }
+}
+
+/// Splits a carrier frequency into band select and fractional carrier
+fn freq_regs(freq_mhz: f64) -> (FrequencyBandSelect, u16) {
+    let high = freq_mhz >= 480.0;
+    let step = if high { 20.0 } else { 10.0 };
+    let band = (freq_mhz / step) as u32 - 24;
+    let mut bandsel = FrequencyBandSelect::from_band(band as u8);
+    if high {
+        bandsel |= HBSEL;
+    }
+    let mut fcarrier = freq_mhz;
+    fcarrier /= step;
+    fcarrier -= (band + 24) as f64;
+    fcarrier *= 64000.0;
+    (bandsel, fcarrier as u16)
+}
+
+/// Frequency offset resolution in Hz
+fn offset_step_hz(bandsel: FrequencyBandSelect) -> f64 {
+    if bandsel.contains(HBSEL) { 312.5 } else { 156.25 }
+}
+
+/// Returns TXDRTSCALE and the TXDR register value
+fn data_rate_regs(rate_hz: f64) -> (bool, u16) {
+    let scale = rate_hz < 30000.0;
+    let exp = if scale { 16 + 5 } else { 16 };
+    let txdr = rate_hz * (1 << exp) as f64;
+    (scale, (txdr / 1000000.0) as u16)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+impl Rfm22Config {
+    /// Loads a JSON configuration. Missing fields take their default.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let config: Rfm22Config = serde_json::from_reader(File::open(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> io::Result<()> {
+        if !(240.0..960.0).contains(&self.freq_mhz) {
+            return Err(invalid("Frequency out of range 240-960 MHz"));
+        }
+        let (bandsel, _) = freq_regs(self.freq_mhz);
+        let offset = self.freq_offset_hz / offset_step_hz(bandsel);
+        if !(-512.0..=511.0).contains(&offset) {
+            return Err(invalid("Frequency offset out of range"));
+        }
+        if !(123.0..=256000.0).contains(&self.data_rate_hz) {
+            return Err(invalid("Data rate out of range 123-256000 bps"));
+        }
+        if self.tx_power > 7 {
+            return Err(invalid("TX power out of range 0-7"));
+        }
+        Ok(())
+    }
+
+    /// Register contents for this configuration as (register, mask, value).
+    /// Bits outside the mask are not owned by the config and are left alone.
+    /// Ordered by address so consecutive registers can be written as a burst.
+    pub fn registers(&self) -> Vec<(Rfm22RegVal, u8, u8)> {
+        let (bandsel, carrier) = freq_regs(self.freq_mhz);
+        let offset = (self.freq_offset_hz / offset_step_hz(bandsel)).round() as i16 as u16 & 0x3ff;
+        let (scale, txdr) = data_rate_regs(self.data_rate_hz);
+        let mc1 = if scale {
+            TXDRTSCALE
+        } else {
+            ModulationModeControl1::empty()
+        };
+        let mut mc2 = ModulationModeControl2::empty();
+        mc2.set_modtype(self.modulation);
+        mc2.set_data_source(self.data_source);
+        let mut power = TxPower::empty();
+        power.set_tx_power(self.tx_power);
+
+        let mut regs = Vec::new();
+        let gpio_regs = [Rfm22RegVal::Gpio0Configuration,
+                         Rfm22RegVal::Gpio1Configuration,
+                         Rfm22RegVal::Gpio2Configuration];
+        for (reg, function) in gpio_regs.iter().zip(self.gpio.iter()) {
+            if let Some(function) = *function {
+                regs.push((*reg, GPIO_FUNCTION_MASK, function as u8));
+            }
+        }
+        regs.extend_from_slice(&[(Rfm22RegVal::DataAccessControl,
+                                  DataAccessControl::all().bits(),
+                                  self.packet.data_access().bits()),
+                                 (Rfm22RegVal::HeaderControl2,
+                                  HeaderControl2::all().bits(),
+                                  self.packet.header_control().bits()),
+                                 (Rfm22RegVal::TxPower,
+                                  (TXPOW0 | TXPOW1 | TXPOW2).bits(),
+                                  power.bits()),
+                                 (Rfm22RegVal::TxDataRate1,
+                                  TxDataRate1::all().bits(),
+                                  TxDataRate1::from_txdr(txdr).bits()),
+                                 (Rfm22RegVal::TxDataRate0,
+                                  TxDataRate0::all().bits(),
+                                  TxDataRate0::from_txdr(txdr).bits()),
+                                 (Rfm22RegVal::ModulationModeControl1,
+                                  TXDRTSCALE.bits(),
+                                  mc1.bits()),
+                                 (Rfm22RegVal::ModulationModeControl2,
+                                  (MODTYP0 | MODTYP1 | DTMOD0 | DTMOD1).bits(),
+                                  mc2.bits()),
+                                 (Rfm22RegVal::FrequencyOffset1,
+                                  FrequencyOffset1::all().bits(),
+                                  FrequencyOffset1::from_frequency_offset(offset).bits()),
+                                 (Rfm22RegVal::FrequencyOffset2,
+                                  FrequencyOffset2::all().bits(),
+                                  FrequencyOffset2::from_frequency_offset(offset).bits()),
+                                 (Rfm22RegVal::FrequencyBandSelect,
+                                  FrequencyBandSelect::all().bits(),
+                                  bandsel.bits()),
+                                 (Rfm22RegVal::CarrierFrequency1,
+                                  CarrierFrequency1::all().bits(),
+                                  CarrierFrequency1::from_carrier(carrier).bits()),
+                                 (Rfm22RegVal::CarrierFrequency0,
+                                  CarrierFrequency0::all().bits(),
+                                  CarrierFrequency0::from_carrier(carrier).bits())]);
+        regs
+    }
+
+    /// Writes the registers that differ from this configuration in a single
+    /// transaction
+    pub fn apply(&self, rf: &mut Rfm22) -> io::Result<()> {
+        let mut updates = Vec::new();
+        for (reg, mask, val) in self.registers() {
+            let cur = rf.regs.read_raw(reg)?;
+            let new = (cur & !mask) | val;
+            if new != cur {
+                updates.push((reg, new));
+            }
+        }
+        rf.regs.transaction(|txn| for (reg, val) in updates {
+            txn.write_raw(reg, val);
+        })
+    }
+
+    /// Returns true if every register on the chip matches this configuration.
+    /// Always reads the chip, bypassing the register cache.
+    pub fn verify(&self, regs: &mut Rfm22Regs) -> io::Result<bool> {
+        for (reg, mask, val) in self.registers() {
+            let cur = regs.read_uncached(reg)?;
+            if cur & mask != val {
+                warn!("Register 0x{:02x} is 0x{:02x}, expected 0x{:02x}/0x{:02x}",
+                      reg as u8,
+                      cur,
+                      val,
+                      mask);
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Decodes the configuration currently programmed into the chip
+    pub fn read_from(rf: &mut Rfm22) -> io::Result<Self> {
+        let regs = &mut rf.regs;
+        let mc2: ModulationModeControl2 = regs.read()?;
+        let data_access: DataAccessControl = regs.read()?;
+        let header_control: HeaderControl2 = regs.read()?;
+
+        let bandsel: FrequencyBandSelect = regs.read()?;
+        let carrier = (regs.read::<CarrierFrequency1>()?.bits() as u16) << 8 |
+                      regs.read::<CarrierFrequency0>()?.bits() as u16;
+        let step = if bandsel.contains(HBSEL) { 20.0 } else { 10.0 };
+        let band = (bandsel.bits() & (FB0 | FB1 | FB2 | FB3 | FB4).bits()) as f64;
+        let freq_mhz = step * (band + 24.0 + carrier as f64 / 64000.0);
+        let offset = (regs.read::<FrequencyOffset2>()?.bits() as u16) << 8 |
+                     regs.read::<FrequencyOffset1>()?.bits() as u16;
+        // Sign extend the 10-bit offset
+        let offset = ((offset << 6) as i16) >> 6;
+
+        let scale = regs.read::<ModulationModeControl1>()?.contains(TXDRTSCALE);
+        let txdr = (regs.read::<TxDataRate1>()?.bits() as u16) << 8 |
+                   regs.read::<TxDataRate0>()?.bits() as u16;
+        let exp = if scale { 16 + 5 } else { 16 };
+        let data_rate_hz = txdr as f64 * 1000000.0 / (1 << exp) as f64;
+
+        let power = regs.read::<TxPower>()? & (TXPOW0 | TXPOW1 | TXPOW2);
+
+        let gpio0: Gpio0Configuration = regs.read()?;
+        let gpio1: Gpio1Configuration = regs.read()?;
+        let gpio2: Gpio2Configuration = regs.read()?;
+        let gpio = [GpioFunction::from_bits(gpio0.bits() & GPIO_FUNCTION_MASK),
+                    GpioFunction::from_bits(gpio1.bits() & GPIO_FUNCTION_MASK),
+                    GpioFunction::from_bits(gpio2.bits() & GPIO_FUNCTION_MASK)];
+
+        Ok(Rfm22Config {
+            modulation: mc2.modtype(),
+            data_source: mc2.data_source(),
+            freq_mhz,
+            freq_offset_hz: offset as f64 * offset_step_hz(bandsel),
+            data_rate_hz,
+            tx_power: power.bits(),
+            packet: PacketConfig {
+                tx_handler: data_access.contains(ENPACTX),
+                rx_handler: data_access.contains(ENPACRX),
+                crc: data_access.contains(ENCRC),
+                lsb_first: data_access.contains(LSBFIRST),
+                skip_sync: header_control.contains(SKIPSYN),
+            },
+            gpio,
+        })
+    }
+}
+
+/// Changes settings of a base config, checking them all when built
+pub struct Rfm22ConfigBuilder {
+    config: Rfm22Config,
+    /// A GPIO given that the chip doesn't have
+    bad_pin: Option<usize>,
+}
+
+impl Rfm22ConfigBuilder {
+    pub fn new(base: Rfm22Config) -> Self {
+        Rfm22ConfigBuilder {
+            config: base,
+            bad_pin: None,
+        }
+    }
+
+    pub fn freq_mhz(mut self, freq: f64) -> Self {
+        self.config.freq_mhz = freq;
+        self
+    }
+
+    pub fn data_rate_hz(mut self, rate: f64) -> Self {
+        self.config.data_rate_hz = rate;
+        self
+    }
+
+    pub fn tx_power(mut self, power: u8) -> Self {
+        self.config.tx_power = power;
+        self
+    }
+
+    /// Sets what GPIO `pin` does. Pins other than 0 to 2 fail the build.
+    pub fn gpio(mut self, pin: usize, function: GpioFunction) -> Self {
+        match self.config.gpio.get_mut(pin) {
+            Some(gpio) => *gpio = Some(function),
+            None => self.bad_pin = self.bad_pin.or(Some(pin)),
+        }
+        self
+    }
+
+    pub fn build(self) -> io::Result<Rfm22Config> {
+        if let Some(pin) = self.bad_pin {
+            return Err(invalid(&format!("No GPIO{}, only GPIO0-2", pin)));
+        }
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+#[test]
+fn config_registers() {
+    let regs = Rfm22Config::default().registers();
+    let val = |reg: Rfm22RegVal| regs.iter().find(|r| r.0 as u8 == reg as u8).unwrap().2;
+    assert_eq!(0x06, val(Rfm22RegVal::FrequencyBandSelect));
+    assert_eq!(0x5f, val(Rfm22RegVal::CarrierFrequency1));
+    assert_eq!(0x00, val(Rfm22RegVal::CarrierFrequency0));
+    assert_eq!(0x18, val(Rfm22RegVal::TxDataRate1));
+    assert_eq!(0x93, val(Rfm22RegVal::TxDataRate0));
+    assert_eq!(TXDRTSCALE.bits(), val(Rfm22RegVal::ModulationModeControl1));
+    assert_eq!((MODTYP0 | DTMOD1).bits(), val(Rfm22RegVal::ModulationModeControl2));
+}
+
+#[test]
+fn config_read_back() {
+    let base = Rfm22Config { freq_offset_hz: -1250.0, ..Rfm22Config::default() };
+    let config = Rfm22ConfigBuilder::new(base)
+        .freq_mhz(868.3)
+        .data_rate_hz(40000.0)
+        .tx_power(7)
+        .gpio(0, GpioFunction::TxState)
+        .gpio(1, GpioFunction::RxState)
+        .gpio(2, GpioFunction::Vdd)
+        .build()
+        .unwrap();
+    let mut rf = Rfm22::dummy();
+    config.apply(&mut rf).unwrap();
+    assert!(config.verify(&mut rf.regs).unwrap());
+    let read = Rfm22Config::read_from(&mut rf).unwrap();
+    assert_eq!(config.registers(), read.registers());
+    assert_eq!(-1250.0, read.freq_offset_hz);
+    assert_eq!(Some(GpioFunction::RxState), read.gpio[1]);
+}
+
+#[test]
+fn config_json() {
+    let config: Rfm22Config = serde_json::from_str(r#"{"freq_mhz": 433.92, "tx_power": 5}"#)
+        .unwrap();
+    assert_eq!(433.92, config.freq_mhz);
+    assert_eq!(ModulationType::OOK, config.modulation);
+    let json = serde_json::to_string(&config).unwrap();
+    assert_eq!(config, serde_json::from_str(&json).unwrap());
+    assert!(Rfm22ConfigBuilder::new(config.clone()).tx_power(8).build().is_err());
+    assert!(Rfm22ConfigBuilder::new(config).gpio(3, GpioFunction::TxState).build().is_err());
+}
@@ -0,0 +1,260 @@
+//! rtl_433 interop: OOK pulse files for checking our encoding with its flex
+//! decoder, and ingest of its JSON events.
+//!
+//! With the flex decoder from `flex_spec`, rtl_433 reports each button press
+//! as one event with a row per frame. Short pulses are 0 bits and long pulses
+//! 1 bits, and frames are split into rows on the inter-frame gap. Each row is
+//! the start bit followed by the packet bits. `fanrf ingest --print-spec`
+//! prints the spec for the current timing, which for the fans is:
+//!
+//! ```text
+//! rtl_433 -f 303.8M -X 'n=fanrf,m=OOK_PWM,s=333,l=667,g=5500,r=22000' -F json | fanrf ingest
+//! ```
+
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+use serde_json::{self, Value};
+
+use config::Config;
+use fan::{FanPkt, FanPkt12, FanPkt21};
+use pwm::PwmSymbols;
+use state::StateStore;
+
+/// Writes durations (see `encode::timings`) as an rtl_433 pulse file, loadable
+/// with `rtl_433 -r file.ook`. Leading silence is dropped since every line is
+/// a pulse followed by a gap.
+pub fn write_ook<W: Write>(w: &mut W, freq_hz: f64, timings: &[i64]) -> io::Result<()> {
+    let mut pulses = Vec::new();
+    let mut iter = timings.iter().skip_while(|&&t| t <= 0).peekable();
+    while let Some(&pulse) = iter.next() {
+        let gap = match iter.peek() {
+            Some(&&gap) if gap < 0 => {
+                iter.next();
+                -gap
+            }
+            _ => 0,
+        };
+        pulses.push((pulse, gap));
+    }
+
+    writeln!(w, ";pulse data")?;
+    writeln!(w, ";version 1")?;
+    writeln!(w, ";timescale 1us")?;
+    writeln!(w, ";ook {} pulses", pulses.len())?;
+    writeln!(w, ";freq1 {:.0}", freq_hz)?;
+    writeln!(w, ";centerfreq {:.0} Hz", freq_hz)?;
+    writeln!(w, ";samplerate 1000000 Hz")?;
+    for (pulse, gap) in pulses {
+        writeln!(w, "{} {}", pulse, gap)?;
+    }
+    writeln!(w, ";end")
+}
+
+/// Reads the pulses of an rtl_433 pulse file back as durations. Files with
+/// several packages are joined in order.
+pub fn read_ook<R: BufRead>(reader: R) -> io::Result<Vec<i64>> {
+    let mut timings = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(';') {
+            continue;
+        }
+        let mut durs = line.split_whitespace().map(|d| d.parse::<i64>());
+        match (durs.next(), durs.next()) {
+            (Some(Ok(pulse)), Some(Ok(gap))) => {
+                timings.push(pulse);
+                if gap > 0 {
+                    timings.push(-gap);
+                }
+            }
+            _ => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("Invalid pulse line '{}'", line)))
+            }
+        }
+    }
+    Ok(timings)
+}
+
+/// Name of the flex decoder, which rtl_433 gives as the `model` of its events
+const MODEL: &str = "fanrf";
+
+/// rtl_433 flex decoder spec for commands sent with `pwm` at `data_rate_hz`.
+/// Rows end halfway through the gap between frames, which is longer than any
+/// gap between bits, and the event ends after two gaps of silence.
+pub fn flex_spec(pwm: &PwmSymbols, data_rate_hz: f64) -> String {
+    let us = |symbols: usize| (symbols as f64 * 1000000.0 / data_rate_hz).round();
+    format!("n={},m=OOK_PWM,s={},l={},g={},r={}",
+            MODEL,
+            us(pwm.short),
+            us(pwm.long),
+            us(pwm.gap) / 2.0,
+            us(pwm.gap) * 2.0)
+}
+
+/// Bits of a flex decoder row. `data` is hex, MSB first, padded to a nibble.
+fn row_bits(len: usize, data: &str) -> Option<Vec<bool>> {
+    let mut bits = Vec::with_capacity(data.len() * 4);
+    for c in data.chars() {
+        let nibble = c.to_digit(16)?;
+        bits.extend((0..4).rev().map(|i| nibble & (1 << i) != 0));
+    }
+    if bits.len() < len {
+        return None;
+    }
+    bits.truncate(len);
+    Some(bits)
+}
+
+/// Decodes a frame's bits, with or without the start bit
+pub fn decode_row(bits: &[bool]) -> Option<FanPkt> {
+    // Rows include the 0 start bit unless the decoder was set up to skip it
+    let bits = match bits.len() {
+        13 | 22 if !bits[0] => &bits[1..],
+        _ => bits,
+    };
+    match bits.len() {
+        12 => FanPkt12::from_bits(bits).map(FanPkt::Dumb),
+        21 => FanPkt21::from_bits(bits).map(FanPkt::Smart),
+        _ => None,
+    }
+}
+
+/// Fan commands in one rtl_433 JSON event. Rows repeat the same command, so
+/// each distinct command is returned once. Events from other decoders are
+/// skipped, even if their rows happen to decode.
+pub fn decode_event(event: &Value) -> Vec<FanPkt> {
+    if event.get("model").and_then(Value::as_str) != Some(MODEL) {
+        return Vec::new();
+    }
+    let mut rows = Vec::new();
+    if let Some(list) = event.get("rows").and_then(Value::as_array) {
+        for row in list {
+            let len = row.get("len").and_then(Value::as_u64);
+            let data = row.get("data").and_then(Value::as_str);
+            if let (Some(len), Some(data)) = (len, data) {
+                rows.push((len as usize, data.to_string()));
+            }
+        }
+    } else if let Some(list) = event.get("codes").and_then(Value::as_array) {
+        // "{13}5408"
+        for code in list.iter().filter_map(Value::as_str) {
+            let mut parts = code.trim_start_matches('{').splitn(2, '}');
+            let len = parts.next().and_then(|len| len.parse::<usize>().ok());
+            if let (Some(len), Some(data)) = (len, parts.next()) {
+                rows.push((len, data.to_string()));
+            }
+        }
+    }
+
+    let mut pkts: Vec<FanPkt> = Vec::new();
+    for (len, data) in rows {
+        if let Some(pkt) = row_bits(len, &data).and_then(|bits| decode_row(&bits)) {
+            if !pkts.contains(&pkt) {
+                pkts.push(pkt);
+            }
+        }
+    }
+    pkts
+}
+
+/// Applies fan commands from rtl_433 JSON lines to the state store at
+/// `state`. The store is opened for each event, so other processes can
+/// update it between them. Returns the number of commands recorded.
+pub fn ingest<R: BufRead>(reader: R, state: &Path, config: &Config) -> io::Result<usize> {
+    let mut count = 0;
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let event: Value = match serde_json::from_str(&line) {
+            Ok(event) => event,
+            Err(e) => {
+                warn!("Skipping invalid rtl_433 event: {}", e);
+                continue;
+            }
+        };
+        let pkts = decode_event(&event);
+        if pkts.is_empty() {
+            continue;
+        }
+        let mut store = StateStore::open(state)?;
+        for pkt in pkts.iter() {
+            info!("Heard {}", pkt);
+            store.record(pkt, &config.brightness(pkt.addr()), "rtl_433");
+            count += 1;
+        }
+        store.save()?;
+    }
+    Ok(count)
+}
+
+#[test]
+fn ook_export() {
+    use encode::timings;
+    use fan::{FanCmd12, FanPkt12};
+    use pwm::PwmSymbols;
+
+    let pkt = FanPkt::Dumb(FanPkt12::new(5, FanCmd12::Light));
+    let mut out = Vec::new();
+    let symbol_us = 1000000.0 / 3000.0;
+    write_ook(&mut out, 303.8e6, &timings(&pkt.frame(&PwmSymbols::FAN), symbol_us)).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    let lines = out.lines().collect::<Vec<_>>();
+    assert_eq!(";ook 13 pulses", lines[3]);
+    assert_eq!(";freq1 303800000", lines[4]);
+    // Start bit, then the first packet bit which is always 1
+    assert_eq!("334 333", lines[7]);
+    assert_eq!("667 666", lines[8]);
+    // Last bit and the inter-frame gap
+    assert_eq!("667 11333", lines[19]);
+    assert_eq!(";end", lines[20]);
+
+    let expected = timings(&pkt.frame(&PwmSymbols::FAN), symbol_us);
+    assert_eq!(&expected[1..], &read_ook(out.as_bytes()).unwrap()[..]);
+}
+
+#[test]
+fn rtl433_flex_spec() {
+    use pwm::PwmTiming;
+
+    assert_eq!("n=fanrf,m=OOK_PWM,s=333,l=667,g=5500,r=22000",
+               flex_spec(&PwmSymbols::FAN, 3000.0));
+    let (pwm, rate) = PwmTiming::EV1527.oversample().unwrap();
+    assert_eq!("n=fanrf,m=OOK_PWM,s=400,l=1200,g=5600,r=22400", flex_spec(&pwm, rate));
+}
+
+#[test]
+fn rtl433_ingest() {
+    use std::env;
+    use std::fs;
+
+    use fan::FanState21;
+
+    // Start bit + dumb address 5 light, and smart address 5 low 50%. The
+    // doorbell's row would be address 3 off.
+    let events = r#"{"time":"2026-10-18 10:00:00","model":"fanrf","count":20,"num_rows":2,"rows":[{"len":13,"data":"5408"},{"len":13,"data":"5408"}]}
+not json
+{"time":"2026-10-18 10:00:05","model":"Acurite-Tower","id":1234,"temperature_C":21.5}
+{"time":"2026-10-18 10:00:07","model":"doorbell","count":1,"num_rows":1,"rows":[{"len":13,"data":"4c10"}]}
+{"time":"2026-10-18 10:00:09","model":"fanrf","count":1,"num_rows":1,"codes":["{22}7ad044"]}
+"#;
+    let path = env::temp_dir().join(format!("fanrf-ingest-{}.json", ::std::process::id()));
+    assert_eq!(2, ingest(events.as_bytes(), &path, &Config::default()).unwrap());
+    let store = StateStore::open(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    let status = &store.fans[&5];
+    assert_eq!(Some(FanState21::Low), status.fan);
+    assert_eq!(Some(true), status.light);
+    assert_eq!("rtl_433", status.source);
+    assert!(!store.fans.contains_key(&3));
+
+    let doorbell: Value = serde_json::from_str(events.lines().nth(3).unwrap()).unwrap();
+    assert!(decode_event(&doorbell).is_empty());
+    let mut renamed = doorbell.clone();
+    renamed["model"] = Value::from(MODEL);
+    assert_eq!(vec![FanPkt::Dumb(FanPkt12::from_code(3, 0x02))], decode_event(&renamed));
+}
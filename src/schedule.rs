@@ -0,0 +1,432 @@
+//! Actions run at set times: cron expressions, and sunrise or sunset worked
+//! out from the configured location. Times are local, at a fixed offset from
+//! UTC since we don't read the time zone database.
+
+use std::f64::consts::PI;
+use std::fmt;
+
+use config::Scene;
+use fade::parse_duration;
+
+/// What a schedule entry does to its fan
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// A scene from the config, by name
+    Scene(String),
+    Command(Scene),
+    /// Fade a smart fan's light to a brightness from 0.0 to 1.0
+    Fade { to: f64, over: String },
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Action::Scene(ref name) => write!(f, "scene {}", name),
+            Action::Command(Scene::Dumb(code)) => write!(f, "dumb command 0x{:02x}", code),
+            Action::Command(Scene::Smart { fan, brightness }) => {
+                write!(f, "smart fan {:?} brightness {:.0}%", fan, brightness * 100.0)
+            }
+            Action::Fade { to, ref over } => write!(f, "fade to {:.0}% over {}", to * 100.0, over),
+        }
+    }
+}
+
+/// An entry's action checked against the config, ready to run
+#[derive(Clone, Debug, PartialEq)]
+pub enum Task {
+    Send(Scene),
+    Fade { to: f64, over_ms: f64 },
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    #[serde(default)]
+    pub name: String,
+    pub fan: u8,
+    /// A cron expression, or `sunrise` or `sunset` with an optional offset
+    /// like `sunset-30m`
+    pub when: String,
+    pub action: Action,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Schedule {
+    /// Degrees north, for sunrise and sunset
+    pub latitude: Option<f64>,
+    /// Degrees east
+    pub longitude: Option<f64>,
+    /// Local time zone. Needs changing for daylight saving time.
+    pub utc_offset_min: i32,
+    pub entries: Vec<Entry>,
+}
+
+const SECS_PER_DAY: i64 = 86400;
+
+/// Days since 1970-01-01 of a date
+#[cfg(test)]
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Date of a number of days since 1970-01-01, as (year, month, day)
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let doe = days - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Formats a Unix time as local `YYYY-MM-DD HH:MM`
+pub fn format_local(time: u64, utc_offset_min: i32) -> String {
+    let local = time as i64 + utc_offset_min as i64 * 60;
+    let (year, month, day) = civil_from_days(local.div_euclid(SECS_PER_DAY));
+    let secs = local.rem_euclid(SECS_PER_DAY);
+    format!("{:04}-{:02}-{:02} {:02}:{:02}",
+            year,
+            month,
+            day,
+            secs / 3600,
+            secs % 3600 / 60)
+}
+
+/// A five field cron expression: minute, hour, day of month, month and day of
+/// week, with `*`, lists, ranges and steps. Each field is a bit set.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Day of month and day of week match if either does, unless one
+    /// matches every day
+    any_day: bool,
+    any_weekday: bool,
+}
+
+/// Bits of every value from `min` to `max`
+fn span(min: u32, max: u32) -> u64 {
+    (min..=max).fold(0, |bits, val| bits | 1 << val)
+}
+
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.find('/') {
+            Some(idx) => {
+                let step = part[idx + 1..]
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|&step| step > 0)
+                    .ok_or_else(|| format!("Invalid step in '{}'", part))?;
+                (&part[..idx], step)
+            }
+            None => (part, 1),
+        };
+        let num = |text: &str| {
+            text.parse::<u32>()
+                .ok()
+                .filter(|&val| val >= min && val <= max)
+                .ok_or_else(|| format!("'{}' out of range {}-{}", text, min, max))
+        };
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some(idx) = range.find('-') {
+            let (start, end) = (num(&range[..idx])?, num(&range[idx + 1..])?);
+            if start > end {
+                return Err(format!("Range '{}' runs backwards", range));
+            }
+            (start, end)
+        } else if step > 1 {
+            // `5/15` means from 5 on
+            (num(range)?, max)
+        } else {
+            let val = num(range)?;
+            (val, val)
+        };
+        for val in (start..=end).step_by(step as usize) {
+            bits |= 1 << val;
+        }
+    }
+    Ok(bits)
+}
+
+impl Cron {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let fields = text.split_whitespace().collect::<Vec<_>>();
+        if fields.len() != 5 {
+            return Err(format!("Expected 5 cron fields in '{}'", text));
+        }
+        let mut weekdays = parse_field(fields[4], 0, 7)?;
+        // 7 is also Sunday
+        if weekdays & 1 << 7 != 0 {
+            weekdays |= 1;
+        }
+        let days = parse_field(fields[2], 1, 31)?;
+        Ok(Cron {
+            minutes: parse_field(fields[0], 0, 59)?,
+            hours: parse_field(fields[1], 0, 23)?,
+            days,
+            months: parse_field(fields[3], 1, 12)?,
+            weekdays,
+            any_day: days == span(1, 31),
+            any_weekday: weekdays & span(0, 6) == span(0, 6),
+        })
+    }
+
+    fn matches_day(&self, days: i64) -> bool {
+        let (_, month, day) = civil_from_days(days);
+        // 1970-01-01 was a Thursday
+        let weekday = (days + 4).rem_euclid(7);
+        let by_day = self.days & 1 << day != 0;
+        let by_weekday = self.weekdays & 1 << weekday != 0;
+        let day_ok = match (self.any_day, self.any_weekday) {
+            (false, false) => by_day || by_weekday,
+            _ => by_day && by_weekday,
+        };
+        self.months & 1 << month != 0 && day_ok
+    }
+
+    /// First matching minute after `time`, in local Unix time
+    fn next_local(&self, time: i64) -> Option<i64> {
+        let start = time.div_euclid(60) + 1;
+        let first_day = (start * 60).div_euclid(SECS_PER_DAY);
+        // Leap days can be 4 years apart, and 2100 isn't one
+        for days in first_day..first_day + 366 * 8 + 1 {
+            if !self.matches_day(days) {
+                continue;
+            }
+            for hour in (0..24).filter(|h| self.hours & 1 << h != 0) {
+                for minute in (0..60).filter(|m| self.minutes & 1 << m != 0) {
+                    let at = days * SECS_PER_DAY + hour * 3600 + minute * 60;
+                    if at >= start * 60 {
+                        return Some(at);
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SunEvent {
+    Sunrise,
+    Sunset,
+}
+
+/// Sunrise or sunset on the UTC date `days` after 1970-01-01, as Unix time.
+/// `None` during polar day or night. Good to a minute or two away from the
+/// poles.
+pub fn sun_time(event: SunEvent, days: i64, latitude: f64, longitude: f64) -> Option<f64> {
+    let rad = PI / 180.0;
+    // Days from the J2000 epoch, 2000-01-01 12:00 UTC, to local mean noon
+    let noon = (days - 10957) as f64 - longitude / 360.0;
+    let anomaly = (357.5291 + 0.98560028 * noon).rem_euclid(360.0);
+    let center = 1.9148 * (anomaly * rad).sin() + 0.02 * (2.0 * anomaly * rad).sin() +
+                 0.0003 * (3.0 * anomaly * rad).sin();
+    let ecliptic = (anomaly + center + 180.0 + 102.9372).rem_euclid(360.0);
+    let transit = noon + 0.0053 * (anomaly * rad).sin() - 0.0069 * (2.0 * ecliptic * rad).sin();
+    let declination = ((ecliptic * rad).sin() * (23.4397 * rad).sin()).asin();
+    // The sun's center is 0.833 degrees below the horizon at sunrise, for
+    // refraction and the size of its disc
+    let cos_hour = ((-0.833 * rad).sin() - (latitude * rad).sin() * declination.sin()) /
+                   ((latitude * rad).cos() * declination.cos());
+    if !(-1.0..=1.0).contains(&cos_hour) {
+        return None;
+    }
+    let hour_angle = cos_hour.acos() / rad / 360.0;
+    let day = match event {
+        SunEvent::Sunrise => transit - hour_angle,
+        SunEvent::Sunset => transit + hour_angle,
+    };
+    Some(946728000.0 + day * SECS_PER_DAY as f64)
+}
+
+/// When an entry runs
+#[derive(Clone, Debug, PartialEq)]
+pub enum Trigger {
+    Cron(Cron),
+    Sun { event: SunEvent, offset_secs: i64 },
+}
+
+impl Trigger {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let text = text.trim();
+        let (event, rest) = if let Some(rest) = text.strip_prefix("sunrise") {
+            (SunEvent::Sunrise, rest)
+        } else if let Some(rest) = text.strip_prefix("sunset") {
+            (SunEvent::Sunset, rest)
+        } else {
+            return Cron::parse(text).map(Trigger::Cron);
+        };
+        let offset_secs = if rest.is_empty() {
+            0
+        } else {
+            let (sign, dur) = match rest.chars().next() {
+                Some('+') => (1.0, &rest[1..]),
+                Some('-') => (-1.0, &rest[1..]),
+                _ => return Err(format!("Expected + or - offset in '{}'", text)),
+            };
+            let ms = parse_duration(dur).ok_or_else(|| format!("Invalid offset in '{}'", text))?;
+            (sign * ms / 1000.0).round() as i64
+        };
+        Ok(Trigger::Sun { event, offset_secs })
+    }
+
+    /// First time after `time` the trigger fires, as Unix time
+    pub fn next_after(&self, time: u64, schedule: &Schedule) -> Option<u64> {
+        let offset = schedule.utc_offset_min as i64 * 60;
+        let time = time as i64;
+        match *self {
+            Trigger::Cron(ref cron) => cron.next_local(time + offset).map(|t| (t - offset) as u64),
+            Trigger::Sun { event, offset_secs } => {
+                let (latitude, longitude) = (schedule.latitude?, schedule.longitude?);
+                // A day early in case the offset moves an event across midnight
+                let first_day = time.div_euclid(SECS_PER_DAY) - 1;
+                (first_day..first_day + 367)
+                    .filter_map(|days| sun_time(event, days, latitude, longitude))
+                    .map(|t| t.round() as i64 + offset_secs)
+                    .find(|&t| t > time)
+                    .map(|t| t as u64)
+            }
+        }
+    }
+}
+
+impl Schedule {
+    /// Checks every entry's trigger, and that the location is set if any
+    /// needs it
+    pub fn triggers(&self) -> Result<Vec<Trigger>, String> {
+        self.entries
+            .iter()
+            .map(|entry| {
+                let trigger = Trigger::parse(&entry.when)
+                    .map_err(|e| format!("Schedule entry '{}': {}", entry.name, e))?;
+                if let Trigger::Sun { .. } = trigger {
+                    if self.latitude.is_none() || self.longitude.is_none() {
+                        return Err(format!("Schedule entry '{}' needs latitude and longitude",
+                                           entry.name));
+                    }
+                }
+                Ok(trigger)
+            })
+            .collect()
+    }
+
+    /// The next `count` times entries run after `time`, soonest first, as
+    /// (time, entry index)
+    pub fn upcoming(&self, time: u64, count: usize) -> Result<Vec<(u64, usize)>, String> {
+        let triggers = self.triggers()?;
+        let mut next = triggers.iter()
+            .map(|trigger| trigger.next_after(time, self))
+            .collect::<Vec<_>>();
+        let mut out = Vec::new();
+        while out.len() < count {
+            let soonest = next.iter()
+                .enumerate()
+                .filter_map(|(idx, t)| t.map(|t| (t, idx)))
+                .min();
+            match soonest {
+                Some((at, idx)) => {
+                    out.push((at, idx));
+                    next[idx] = triggers[idx].next_after(at, self);
+                }
+                None => break,
+            }
+        }
+        Ok(out)
+    }
+}
+
+#[test]
+fn schedule_dates() {
+    assert_eq!(0, days_from_civil(1970, 1, 1));
+    assert_eq!(10957, days_from_civil(2000, 1, 1));
+    assert_eq!((2024, 2, 29), civil_from_days(days_from_civil(2024, 2, 29)));
+    assert_eq!((1969, 12, 31), civil_from_days(-1));
+    assert_eq!("2026-10-18 15:05", format_local(1792328700, 120));
+    assert_eq!("2026-10-18 12:35", format_local(1792328700, -30));
+}
+
+#[test]
+fn schedule_cron() {
+    let schedule = Schedule::default();
+    let at = |y, mo, d, h: i64, mi: i64| {
+        (days_from_civil(y, mo, d) * SECS_PER_DAY + h * 3600 + mi * 60) as u64
+    };
+    // Thursday
+    let now = at(2026, 10, 15, 22, 30);
+    let next = |text| Trigger::parse(text).unwrap().next_after(now, &schedule);
+    assert_eq!(Some(at(2026, 10, 15, 22, 45)), next("*/15 * * * *"));
+    assert_eq!(Some(at(2026, 10, 16, 7, 0)), next("0 7 * * 1-5"));
+    assert_eq!(Some(at(2026, 10, 18, 9, 30)), next("30 9 * * 7"));
+    // Either the 1st or a Monday
+    assert_eq!(Some(at(2026, 10, 19, 0, 0)), next("0 0 1 * 1"));
+    assert_eq!(Some(at(2026, 11, 1, 0, 0)), next("0 0 1 * *"));
+    assert_eq!(Some(at(2028, 2, 29, 12, 0)), next("0 12 29 2 *"));
+    // However every day is written
+    assert_eq!(next("0 0 1 * *"), next("0 0 1 * */1"));
+    assert_eq!(next("0 0 1 * *"), next("0 0 1 * 0-6"));
+    assert_eq!(next("0 0 * * 1"), next("0 0 */1 * 1"));
+    assert!(Cron::parse("60 * * * *").is_err());
+    assert!(Cron::parse("* * * *").is_err());
+    // Would never run
+    assert!(Cron::parse("0 5-1 * * *").is_err());
+
+    // Local time an hour ahead of UTC
+    let schedule = Schedule { utc_offset_min: 60, ..schedule };
+    assert_eq!(Some(at(2026, 10, 16, 6, 0)),
+               Trigger::parse("0 7 * * *").unwrap().next_after(now, &schedule));
+}
+
+#[test]
+fn schedule_sun() {
+    // London at midsummer: sunrise 03:43, sunset 20:21 UTC
+    let days = days_from_civil(2024, 6, 21);
+    let rise = sun_time(SunEvent::Sunrise, days, 51.5074, -0.1278).unwrap();
+    let set = sun_time(SunEvent::Sunset, days, 51.5074, -0.1278).unwrap();
+    let midnight = (days * SECS_PER_DAY) as f64;
+    assert!((rise - midnight - (3.0 * 3600.0 + 43.0 * 60.0)).abs() < 120.0);
+    assert!((set - midnight - (20.0 * 3600.0 + 21.0 * 60.0)).abs() < 120.0);
+    // No sunset in Tromsø at midsummer
+    assert_eq!(None, sun_time(SunEvent::Sunset, days, 69.65, 18.96));
+
+    let schedule = Schedule {
+        latitude: Some(51.5074),
+        longitude: Some(-0.1278),
+        utc_offset_min: 60,
+        entries: vec![Entry {
+                          name: "dusk".to_string(),
+                          fan: 5,
+                          when: "sunset-30m".to_string(),
+                          action: Action::Scene("night".to_string()),
+                      },
+                      Entry {
+                          name: "hourly".to_string(),
+                          fan: 3,
+                          when: "0 * * * *".to_string(),
+                          action: Action::Command(Scene::Dumb(2)),
+                      }],
+    };
+    let upcoming = schedule.upcoming(midnight as u64 + 19 * 3600 + 10 * 60, 3).unwrap();
+    // 19:51 UTC, then 21:00 and 22:00 local
+    assert_eq!(vec![0, 1, 1], upcoming.iter().map(|u| u.1).collect::<Vec<_>>());
+    assert!((upcoming[0].0 as f64 - (set - 1800.0)).abs() < 1.0);
+    assert_eq!(midnight as u64 + 20 * 3600, upcoming[1].0);
+    assert!(Schedule { latitude: None, ..schedule }.triggers().is_err());
+}
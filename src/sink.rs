@@ -0,0 +1,212 @@
+//! Register backend that stands in for the chip and renders what it would
+//! transmit to a waveform file instead of the air
+
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use regrw::{EmuRegs, RegRw};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SinkFormat {
+    /// Interleaved unsigned 8-bit IQ, as recorded by rtl_sdr
+    Cu8,
+    /// Interleaved little endian float IQ, as used by GNU Radio
+    Cf32,
+    /// 16-bit mono PCM envelope
+    Wav,
+}
+
+impl SinkFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "cu8" => Some(SinkFormat::Cu8),
+            "cf32" => Some(SinkFormat::Cf32),
+            "wav" => Some(SinkFormat::Wav),
+            _ => None,
+        }
+    }
+
+    /// Guesses the format from the file extension
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Self> {
+        path.as_ref().extension().and_then(|ext| ext.to_str()).and_then(Self::from_name)
+    }
+
+    pub fn default_sample_rate(&self) -> u32 {
+        match *self {
+            SinkFormat::Cu8 | SinkFormat::Cf32 => 250000,
+            SinkFormat::Wav => 48000,
+        }
+    }
+}
+
+const WAV_HEADER_LEN: u32 = 44;
+
+fn write_wav_header<W: Write>(w: &mut W, sample_rate: u32, data_len: u32) -> io::Result<()> {
+    let mut header = Vec::with_capacity(WAV_HEADER_LEN as usize);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&(WAV_HEADER_LEN - 8 + data_len).to_le_bytes());
+    header.extend_from_slice(b"WAVEfmt ");
+    header.extend_from_slice(&16u32.to_le_bytes());
+    header.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    header.extend_from_slice(&1u16.to_le_bytes()); // Mono
+    header.extend_from_slice(&sample_rate.to_le_bytes());
+    header.extend_from_slice(&(sample_rate * 2).to_le_bytes()); // Byte rate
+    header.extend_from_slice(&2u16.to_le_bytes()); // Block align
+    header.extend_from_slice(&16u16.to_le_bytes()); // Bits per sample
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&data_len.to_le_bytes());
+    w.write_all(&header)
+}
+
+/// Emulates the chip with `EmuRegs` and renders every byte written to the TX
+/// FIFO at the data rate programmed in the TX data rate registers. The carrier
+/// is at 0 Hz in the IQ formats.
+pub struct WaveformSink<W: Write + Seek> {
+    regs: EmuRegs,
+    out: Option<W>,
+    format: SinkFormat,
+    sample_rate: u32,
+    /// End of the last rendered symbol in seconds
+    time_s: f64,
+    samples: u64,
+}
+
+impl<W: Write + Seek> WaveformSink<W> {
+    pub fn new(mut out: W, format: SinkFormat, sample_rate: u32) -> io::Result<Self> {
+        if format == SinkFormat::Wav {
+            // Lengths are filled in by finish()
+            write_wav_header(&mut out, sample_rate, 0)?;
+        }
+        Ok(WaveformSink {
+            regs: EmuRegs::new(),
+            out: Some(out),
+            format,
+            sample_rate,
+            time_s: 0.0,
+            samples: 0,
+        })
+    }
+
+    /// Symbol rate from the TX data rate registers
+    fn data_rate_hz(&mut self) -> io::Result<f64> {
+        let txdr = (self.regs.read(0x6e)? as u32) << 8 | self.regs.read(0x6f)? as u32;
+        // txdtrtscale in Modulation Mode Control 1
+        let exp = if self.regs.read(0x70)? & 0x20 != 0 { 16 + 5 } else { 16 };
+        if txdr == 0 {
+            return Err(io::Error::other("TX data rate is not set"));
+        }
+        Ok(txdr as f64 * 1000000.0 / (1u64 << exp) as f64)
+    }
+
+    fn render(&mut self, fifo: &[u8]) -> io::Result<()> {
+        let symbol_s = 1.0 / self.data_rate_hz()?;
+        let mut buf = Vec::new();
+        for byte in fifo {
+            for bit in (0..8).rev() {
+                let on = byte & (1 << bit) != 0;
+                self.time_s += symbol_s;
+                let end = (self.time_s * self.sample_rate as f64).round() as u64;
+                for _ in self.samples..end {
+                    match self.format {
+                        SinkFormat::Cu8 => buf.extend_from_slice(&[if on { 255 } else { 128 }, 128]),
+                        SinkFormat::Cf32 => {
+                            let i: f32 = if on { 1.0 } else { 0.0 };
+                            buf.extend_from_slice(&i.to_le_bytes());
+                            buf.extend_from_slice(&0f32.to_le_bytes());
+                        }
+                        SinkFormat::Wav => {
+                            let level: i16 = if on { i16::MAX } else { 0 };
+                            buf.extend_from_slice(&level.to_le_bytes());
+                        }
+                    }
+                }
+                self.samples = self.samples.max(end);
+            }
+        }
+        match self.out {
+            Some(ref mut out) => out.write_all(&buf),
+            None => Err(io::Error::other("Sink already finished")),
+        }
+    }
+
+    /// Flushes the file and fixes up the WAV header. Called on drop.
+    pub fn finish(&mut self) -> io::Result<()> {
+        let mut out = match self.out.take() {
+            Some(out) => out,
+            None => return Ok(()),
+        };
+        if self.format == SinkFormat::Wav {
+            out.seek(SeekFrom::Start(0))?;
+            write_wav_header(&mut out, self.sample_rate, (self.samples * 2) as u32)?;
+            out.seek(SeekFrom::End(0))?;
+        }
+        out.flush()?;
+        info!("Rendered {} samples ({:.3} s)", self.samples, self.time_s);
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> RegRw for WaveformSink<W> {
+    fn read(&mut self, reg: u8) -> io::Result<u8> {
+        self.regs.read(reg)
+    }
+
+    fn write(&mut self, reg: u8, val: u8) -> io::Result<()> {
+        self.regs.write(reg, val)
+    }
+
+    fn burst_write(&mut self, reg: u8, val: &[u8]) -> io::Result<()> {
+        if reg == 0x7f {
+            self.render(val)?;
+        }
+        self.regs.burst_write(reg, val)
+    }
+}
+
+impl<W: Write + Seek> Drop for WaveformSink<W> {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            error!("Unable to finish waveform: {}", e);
+        }
+    }
+}
+
+#[test]
+fn waveform_sink() {
+    use std::env;
+    use std::fs;
+
+    use fan::{FanCmd12, FanPkt, FanPkt12};
+    use pwm::PwmSymbols;
+    use rfm::Rfm22;
+    use rfmconfig::Rfm22Config;
+
+    let path = env::temp_dir().join(format!("fanrf-sink-{}.wav", ::std::process::id()));
+    let pkt = FanPkt::Dumb(FanPkt12::new(5, FanCmd12::Light));
+    {
+        let sink = WaveformSink::new(fs::File::create(&path).unwrap(), SinkFormat::Wav, 48000)
+            .unwrap();
+        let mut rf = Rfm22::from_regrw(Box::new(sink), None, None);
+        rf.configure(Rfm22Config::default()).unwrap();
+        pkt.transmit(&mut rf, &PwmSymbols::FAN, pkt.repeats()).unwrap();
+    }
+    let wav = fs::read(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(b"RIFF", &wav[0..4]);
+    let data_len = u32::from_le_bytes([wav[40], wav[41], wav[42], wav[43]]) as usize;
+    assert_eq!(wav.len() - WAV_HEADER_LEN as usize, data_len);
+    let samples = wav[44..]
+        .chunks(2)
+        .map(|s| i16::from_le_bytes([s[0], s[1]]) != 0)
+        .collect::<Vec<_>>();
+    // The programmed rate is 2999.8 bps, 16 samples per symbol. The stream is
+    // padded to whole FIFO bytes.
+    let symbols = pkt.repeated(&PwmSymbols::FAN, pkt.repeats()).len().div_ceil(8) * 8;
+    assert_eq!((symbols as f64 * 48000.0 / 2999.78).round() as usize, samples.len());
+    // Start bit (0, 1, 0) then the first packet bit (1, 1, 0)
+    let expected = [false, true, false, true, true, false];
+    for (idx, &on) in expected.iter().enumerate() {
+        assert_eq!(on, samples[idx * 16 + 8]);
+    }
+}
@@ -0,0 +1,440 @@
+//! Last known state of each fan, kept in a JSON file. Updated by commands we
+//! send and by commands other remotes were heard sending.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json;
+
+use config::Scene;
+use fan::{BrightnessCurve, FanCmd12, FanPkt, FanPkt21, FanState21};
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FanStatus {
+    pub fan: Option<FanState21>,
+    pub light: Option<bool>,
+    /// 0.0 to 1.0. Known for fans with smart remotes, and estimated for dumb
+    /// ones after dimming.
+    pub brightness: Option<f64>,
+    /// Whether the last hold of a dumb fan's Light button brightened the
+    /// light. Holds alternate, so the next goes the other way.
+    pub dim_up: Option<bool>,
+    /// Unix time of the last command
+    pub updated: u64,
+    /// Who sent the last command, e.g. `fanrf` or `rtl_433`
+    pub source: String,
+    /// The fade in progress, if any
+    pub fade: Option<FadeStatus>,
+    /// Scene to send at a later time, e.g. off for a sleep timer
+    pub timer: Option<Timer>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Timer {
+    /// Unix time it expires at
+    pub at: u64,
+    pub scene: Scene,
+}
+
+/// A fade run by a `fanrf fade` process
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FadeStatus {
+    /// Process ID of the fade
+    pub id: u32,
+    /// Brightness it ends at
+    pub to: f64,
+    /// Unix time it ends at
+    pub until: u64,
+}
+
+/// A hold of a dumb fan's Light button
+#[derive(Clone, Debug, PartialEq)]
+pub struct DimHold {
+    pub duration_ms: f64,
+    /// Brightness the light ends at, if it can be estimated
+    pub level: Option<f64>,
+}
+
+/// Plans a hold taking the light from `from` to `to` brightness, where
+/// sweeping the whole range takes `sweep_ms`. `up` is the way the receiver
+/// will go, if known. A target the other way can't be reached. If the way is
+/// unknown the hold is sized for `to`, but where it ends isn't known.
+pub fn dim_hold(from: f64, to: f64, sweep_ms: f64, up: Option<bool>) -> Result<DimHold, String> {
+    let to = to.clamp(0.0, 1.0);
+    if to == from {
+        return Err(format!("The light is already at {:.0}%", to * 100.0));
+    }
+    match up {
+        Some(up) if up != (to > from) => {
+            Err(format!("The next hold {} the light, so can't reach {:.0}% from {:.0}%. Hold \
+                         it with --for first.",
+                        if up { "brightens" } else { "dims" },
+                        to * 100.0,
+                        from * 100.0))
+        }
+        _ => {
+            Ok(DimHold {
+                duration_ms: (from - to).abs() * sweep_ms,
+                level: up.map(|_| to),
+            })
+        }
+    }
+}
+
+impl FanStatus {
+    /// Which way the next hold of the Light button goes, if known
+    pub fn next_dim_up(&self) -> Option<bool> {
+        self.dim_up.map(|up| !up)
+    }
+
+    /// Updates the state with what the fan does on receiving `pkt`. Smart
+    /// levels are read as brightness on the fan's `curve`.
+    pub fn apply(&mut self, pkt: &FanPkt, curve: &BrightnessCurve) {
+        match *pkt {
+            FanPkt::Dumb(ref pkt) => {
+                match pkt.cmd() {
+                    // The fan toggles the light, so it's only known if it was before
+                    Some(FanCmd12::Light) => self.light = self.light.map(|on| !on),
+                    Some(FanCmd12::FanOff) => self.fan = Some(FanState21::Off),
+                    Some(FanCmd12::FanLow) => self.fan = Some(FanState21::Low),
+                    Some(FanCmd12::FanMed) => self.fan = Some(FanState21::Med),
+                    Some(FanCmd12::FanHigh) => self.fan = Some(FanState21::High),
+                    None => (),
+                }
+            }
+            FanPkt::Smart(ref pkt) => self.set_smart(pkt.fan(), curve.brightness(pkt.level())),
+        }
+    }
+
+    fn set_smart(&mut self, fan: FanState21, brightness: f64) {
+        self.fan = Some(fan);
+        self.brightness = Some(brightness);
+        self.light = Some(brightness > 0.0);
+    }
+}
+
+/// Unix time in seconds
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn create_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => fs::create_dir_all(dir),
+        _ => Ok(()),
+    }
+}
+
+pub struct StateStore {
+    path: PathBuf,
+    pub fans: BTreeMap<u8, FanStatus>,
+    /// Held until the store is dropped, so another process can't load the
+    /// file and save over what we save
+    _lock: File,
+}
+
+impl StateStore {
+    /// `$XDG_STATE_HOME/fanrf/state.json`, falling back to `~/.local/state`
+    pub fn default_path() -> PathBuf {
+        env::var_os("XDG_STATE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".local/state")))
+            .unwrap_or_else(|| PathBuf::from("."))
+            .join("fanrf/state.json")
+    }
+
+    /// Loads the store. A missing file is an empty store. Waits for any
+    /// other process with the store open to drop it, so keep it only as
+    /// long as it takes to update.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        create_parent(&path)?;
+        let lock = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path.with_extension("json.lock"))?;
+        lock.lock()?;
+        let fans = match File::open(&path) {
+            Ok(file) => {
+                serde_json::from_reader(file)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(StateStore {
+            path,
+            fans,
+            _lock: lock,
+        })
+    }
+
+    /// Writes the store atomically by renaming a temporary file over it
+    pub fn save(&self) -> io::Result<()> {
+        let tmp = self.path.with_extension("json.tmp");
+        serde_json::to_writer_pretty(File::create(&tmp)?, &self.fans)
+            .map_err(io::Error::other)?;
+        fs::rename(&tmp, &self.path)
+    }
+
+    /// Applies a command to the fan it's addressed to, whose brightness
+    /// follows `curve`. Cancels any fade, since the command overrides it.
+    pub fn record(&mut self,
+                  pkt: &FanPkt,
+                  curve: &BrightnessCurve,
+                  source: &str)
+                  -> &FanStatus {
+        self.update(pkt.addr(), source, |status| {
+            status.apply(pkt, curve);
+            status.fade = None;
+        })
+    }
+
+    /// Notes a fade starting, replacing any other
+    pub fn start_fade(&mut self, addr: u8, fade: FadeStatus) {
+        self.fans.entry(addr).or_default().fade = Some(fade);
+    }
+
+    /// Whether the fade with `id` is still running on the fan at `addr`
+    pub fn fading(&self, addr: u8, id: u32) -> bool {
+        self.fans.get(&addr).and_then(|s| s.fade.as_ref()).is_some_and(|f| f.id == id)
+    }
+
+    /// Applies a fade step, at `brightness` on the fan's curve. Returns false
+    /// without applying it if the fade has been cancelled.
+    pub fn record_fade(&mut self,
+                       id: u32,
+                       pkt: &FanPkt21,
+                       brightness: f64,
+                       source: &str)
+                       -> bool {
+        if !self.fading(pkt.addr(), id) {
+            return false;
+        }
+        self.update(pkt.addr(), source, |status| {
+            status.set_smart(pkt.fan(), brightness);
+        });
+        true
+    }
+
+    /// Ends the fade on the fan at `addr`, or only the one with `id`
+    pub fn end_fade(&mut self, addr: u8, id: Option<u32>) -> Option<FadeStatus> {
+        let status = self.fans.get_mut(&addr)?;
+        if id.is_some() && status.fade.as_ref().map(|f| f.id) != id {
+            return None;
+        }
+        status.fade.take()
+    }
+
+    /// Notes that the light of a dumb fan was dimmed by holding the Light
+    /// button, to `level` if it could be estimated. `up` is the way it went,
+    /// if known.
+    pub fn record_dim(&mut self,
+                      addr: u8,
+                      level: Option<f64>,
+                      up: Option<bool>,
+                      source: &str)
+                      -> &FanStatus {
+        self.update(addr, source, |status| {
+            status.light = Some(true);
+            status.brightness = level;
+            status.dim_up = up;
+        })
+    }
+
+    /// Sets the timer of the fan at `addr`, replacing any other
+    pub fn set_timer(&mut self, addr: u8, timer: Timer) {
+        self.fans.entry(addr).or_default().timer = Some(timer);
+    }
+
+    pub fn cancel_timer(&mut self, addr: u8) -> Option<Timer> {
+        self.fans.get_mut(&addr).and_then(|status| status.timer.take())
+    }
+
+    /// Timers of all fans, soonest first
+    pub fn timers(&self) -> Vec<(u8, &Timer)> {
+        let mut timers = self.fans
+            .iter()
+            .filter_map(|(&addr, status)| status.timer.as_ref().map(|timer| (addr, timer)))
+            .collect::<Vec<_>>();
+        timers.sort_by_key(|&(addr, timer)| (timer.at, addr));
+        timers
+    }
+
+    /// Removes and returns the timers that expired by `time`
+    pub fn take_due(&mut self, time: u64) -> Vec<(u8, Timer)> {
+        let due = self.timers()
+            .into_iter()
+            .filter(|&(_, timer)| timer.at <= time)
+            .map(|(addr, _)| addr)
+            .collect::<Vec<_>>();
+        due.into_iter().filter_map(|addr| self.cancel_timer(addr).map(|t| (addr, t))).collect()
+    }
+
+    fn update<F: FnOnce(&mut FanStatus)>(&mut self, addr: u8, source: &str, f: F) -> &FanStatus {
+        let status = self.fans.entry(addr).or_default();
+        f(status);
+        status.updated = now();
+        status.source = source.to_string();
+        status
+    }
+}
+
+#[test]
+fn state_store() {
+    use fan::FanPkt12;
+
+    let path = env::temp_dir().join(format!("fanrf-state-{}.json", ::std::process::id()));
+    let mut store = StateStore::open(&path).unwrap();
+    assert!(store.fans.is_empty());
+    let curve = BrightnessCurve::DEFAULT;
+    store.record(&FanPkt::Smart(FanPkt21::new(5, 0.0, FanState21::High)), &curve, "test");
+    store.record(&FanPkt::Dumb(FanPkt12::new(5, FanCmd12::Light)), &curve, "test");
+    store.record(&FanPkt::Dumb(FanPkt12::new(2, FanCmd12::Light)), &curve, "test");
+    store.record_dim(3, Some(0.25), Some(false), "test");
+    store.save().unwrap();
+    drop(store);
+
+    let store = StateStore::open(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(Some(FanState21::High), store.fans[&5].fan);
+    assert_eq!(Some(true), store.fans[&5].light);
+    assert_eq!(None, store.fans[&2].light);
+    assert_eq!((Some(true), Some(0.25)), (store.fans[&3].light, store.fans[&3].brightness));
+    assert_eq!(Some(true), store.fans[&3].next_dim_up());
+}
+
+#[test]
+fn state_curve() {
+    let path = env::temp_dir().join(format!("fanrf-curve-{}.json", ::std::process::id()));
+    let mut store = StateStore::open(&path).unwrap();
+    let curve = BrightnessCurve {
+        min: 8,
+        max: 62,
+        gamma: 2.2,
+    };
+    // What's tracked sends the same level again, e.g. when only the speed changes
+    for level in (8..63).chain(Some(63)) {
+        let pkt = FanPkt::Smart(FanPkt21::from_level(5, level, FanState21::Low));
+        let brightness = store.record(&pkt, &curve, "test").brightness.unwrap();
+        assert_eq!(level, curve.level(brightness));
+    }
+    for &brightness in &[0.0, 0.1, 0.3, 0.5, 1.0] {
+        let level = curve.level(brightness);
+        let pkt = FanPkt::Smart(FanPkt21::from_level(5, level, FanState21::Low));
+        let tracked = store.record(&pkt, &curve, "test").brightness.unwrap();
+        assert_eq!(level, curve.level(tracked));
+    }
+}
+
+#[test]
+fn state_dim_hold() {
+    assert_eq!(Ok(DimHold {
+                   duration_ms: 2500.0,
+                   level: Some(0.25),
+               }),
+               dim_hold(0.75, 0.25, 5000.0, Some(false)));
+    assert_eq!(Ok(DimHold {
+                   duration_ms: 5000.0,
+                   level: Some(1.0),
+               }),
+               dim_hold(0.0, 1.5, 5000.0, Some(true)));
+    // The receiver would go the wrong way
+    assert!(dim_hold(0.8, 0.2, 5000.0, Some(true)).is_err());
+    assert!(dim_hold(0.5, 0.5, 5000.0, None).is_err());
+    // Sized for the target, but it may have gone the other way
+    assert_eq!(Ok(DimHold {
+                   duration_ms: 2500.0,
+                   level: None,
+               }),
+               dim_hold(1.0, 0.5, 5000.0, None));
+}
+
+#[test]
+fn state_fade() {
+    let path = env::temp_dir().join(format!("fanrf-fade-{}.json", ::std::process::id()));
+    let mut store = StateStore::open(&path).unwrap();
+    let fade = FadeStatus {
+        id: 7,
+        to: 0.8,
+        until: 100,
+    };
+    let step = FanPkt21::from_level(5, 30, FanState21::Low);
+    assert!(!store.record_fade(7, &step, 0.3, "test"));
+    store.start_fade(5, fade.clone());
+    assert!(store.record_fade(7, &step, 0.3, "test"));
+    assert!(!store.record_fade(8, &step, 0.3, "test"));
+    assert_eq!((Some(0.3), Some(fade.clone())),
+               (store.fans[&5].brightness, store.fans[&5].fade.clone()));
+    store.save().unwrap();
+    drop(store);
+
+    // Another command cancels it
+    let mut store = StateStore::open(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    let pkt = FanPkt::Smart(FanPkt21::new(5, 1.0, FanState21::Low));
+    store.record(&pkt, &BrightnessCurve::DEFAULT, "test");
+    assert!(!store.fading(5, 7));
+    store.start_fade(5, fade.clone());
+    assert_eq!(None, store.end_fade(5, Some(8)));
+    assert_eq!(Some(fade), store.end_fade(5, None));
+}
+
+#[test]
+fn state_timers() {
+    use std::thread;
+    use std::time::Duration;
+
+    let path = env::temp_dir().join(format!("fanrf-timers-{}.json", ::std::process::id()));
+    let mut store = StateStore::open(&path).unwrap();
+    let off = Timer {
+        at: 200,
+        scene: Scene::Dumb(FanCmd12::FanOff as u8),
+    };
+    let dim = Timer {
+        at: 100,
+        scene: Scene::Smart {
+            fan: FanState21::Off,
+            brightness: 0.1,
+        },
+    };
+    store.set_timer(5, off.clone());
+    store.set_timer(3, dim.clone());
+    store.save().unwrap();
+    drop(store);
+
+    // Still there after a restart
+    let mut store = StateStore::open(&path).unwrap();
+    assert_eq!(vec![(3, &dim), (5, &off)], store.timers());
+    assert_eq!(vec![(3, dim)], store.take_due(150));
+    assert!(store.take_due(150).is_empty());
+    assert_eq!(Some(off.clone()), store.cancel_timer(5));
+    assert!(store.timers().is_empty());
+    store.save().unwrap();
+
+    // Another process saving while we have the store open waits for us,
+    // then sees the timer we set
+    let other = {
+        let path = path.clone();
+        thread::spawn(move || {
+            let mut store = StateStore::open(&path).unwrap();
+            store.record_dim(2, None, None, "test");
+            store.save().unwrap();
+        })
+    };
+    thread::sleep(Duration::from_millis(50));
+    store.set_timer(5, off.clone());
+    store.save().unwrap();
+    drop(store);
+    other.join().unwrap();
+    let store = StateStore::open(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(vec![(5, &off)], store.timers());
+    assert!(store.fans.contains_key(&2));
+}
@@ -0,0 +1,256 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::time::Instant;
+
+use serde_json;
+
+use regrw::RegRw;
+
+/// A single register bus operation
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum TraceOp {
+    Read { reg: u8, val: u8 },
+    Write { reg: u8, val: u8 },
+    Burst { reg: u8, data: Vec<u8> },
+    BurstRead { reg: u8, data: Vec<u8> },
+}
+
+/// One line of a trace file
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TraceEntry {
+    /// Microseconds since the recorder was created
+    pub t_us: u64,
+    #[serde(flatten)]
+    pub op: TraceOp,
+}
+
+/// Reads a JSON lines trace, ignoring blank lines
+pub fn read_trace<R: BufRead>(reader: R) -> io::Result<Vec<TraceEntry>> {
+    let mut entries = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        entries.push(serde_json::from_str(&line)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?);
+    }
+    Ok(entries)
+}
+
+/// Passes operations through to another transport and writes each one to
+/// `out` as a line of JSON
+pub struct TraceRecorder<R: RegRw, W: Write> {
+    regs: R,
+    out: W,
+    start: Instant,
+}
+
+impl<R: RegRw, W: Write> TraceRecorder<R, W> {
+    pub fn new(regs: R, out: W) -> Self {
+        TraceRecorder {
+            regs,
+            out,
+            start: Instant::now(),
+        }
+    }
+
+    fn record(&mut self, op: TraceOp) -> io::Result<()> {
+        let elapsed = self.start.elapsed();
+        let entry = TraceEntry {
+            t_us: elapsed.as_secs() * 1000000 + elapsed.subsec_micros() as u64,
+            op,
+        };
+        serde_json::to_writer(&mut self.out, &entry)
+            .map_err(io::Error::other)?;
+        self.out.write_all(b"\n")
+    }
+}
+
+impl<R: RegRw, W: Write> RegRw for TraceRecorder<R, W> {
+    fn read(&mut self, reg: u8) -> io::Result<u8> {
+        let val = self.regs.read(reg)?;
+        self.record(TraceOp::Read { reg, val })?;
+        Ok(val)
+    }
+
+    fn write(&mut self, reg: u8, val: u8) -> io::Result<()> {
+        self.regs.write(reg, val)?;
+        self.record(TraceOp::Write { reg, val })
+    }
+
+    fn burst_write(&mut self, reg: u8, val: &[u8]) -> io::Result<()> {
+        self.regs.burst_write(reg, val)?;
+        self.record(TraceOp::Burst {
+            reg,
+            data: val.to_vec(),
+        })
+    }
+
+    fn burst_read(&mut self, reg: u8, buf: &mut [u8]) -> io::Result<()> {
+        self.regs.burst_read(reg, buf)?;
+        self.record(TraceOp::BurstRead {
+            reg,
+            data: buf.to_vec(),
+        })
+    }
+
+    fn write_batch(&mut self, writes: &[(u8, &[u8])]) -> io::Result<()> {
+        self.regs.write_batch(writes)?;
+        for &(reg, val) in writes {
+            self.record(TraceOp::Burst {
+                reg,
+                data: val.to_vec(),
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// Serves reads from a recorded trace and fails as soon as the driver does
+/// something the trace doesn't. Batched writes are matched as bursts, the
+/// same way `TraceRecorder` logs them.
+pub struct TraceReplay {
+    ops: Vec<TraceOp>,
+    pos: usize,
+}
+
+impl TraceReplay {
+    pub fn new(ops: Vec<TraceOp>) -> Self {
+        TraceReplay { ops, pos: 0 }
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let entries = read_trace(BufReader::new(File::open(path)?))?;
+        Ok(Self::new(entries.into_iter().map(|e| e.op).collect()))
+    }
+
+    fn expect(&mut self, op: TraceOp) -> io::Result<TraceOp> {
+        let expected = self.ops.get(self.pos).cloned();
+        let matches = match (&expected, &op) {
+            (Some(TraceOp::Read { reg: a, .. }), TraceOp::Read { reg: b, .. }) => a == b,
+            (Some(TraceOp::BurstRead { reg: a, data: ref x }),
+             TraceOp::BurstRead { reg: b, data: ref y }) => a == b && x.len() == y.len(),
+            (Some(a), b) => a == b,
+            (None, _) => false,
+        };
+        if !matches {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("Trace diverged at entry {}: expected {:?}, got {:?}",
+                                              self.pos + 1,
+                                              expected,
+                                              op)));
+        }
+        self.pos += 1;
+        Ok(expected.unwrap())
+    }
+}
+
+impl RegRw for TraceReplay {
+    fn read(&mut self, reg: u8) -> io::Result<u8> {
+        match self.expect(TraceOp::Read { reg, val: 0 })? {
+            TraceOp::Read { val, .. } => Ok(val),
+            _ => unreachable!(),
+        }
+    }
+
+    fn write(&mut self, reg: u8, val: u8) -> io::Result<()> {
+        self.expect(TraceOp::Write { reg, val }).map(|_| ())
+    }
+
+    fn burst_write(&mut self, reg: u8, val: &[u8]) -> io::Result<()> {
+        self.expect(TraceOp::Burst {
+                reg,
+                data: val.to_vec(),
+            })
+            .map(|_| ())
+    }
+
+    fn burst_read(&mut self, reg: u8, buf: &mut [u8]) -> io::Result<()> {
+        match self.expect(TraceOp::BurstRead {
+                reg,
+                data: vec![0; buf.len()],
+            })? {
+            TraceOp::BurstRead { data, .. } => {
+                buf.copy_from_slice(&data);
+                Ok(())
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn write_batch(&mut self, writes: &[(u8, &[u8])]) -> io::Result<()> {
+        for &(reg, val) in writes {
+            self.burst_write(reg, val)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for TraceReplay {
+    fn drop(&mut self) {
+        if self.pos < self.ops.len() {
+            warn!("Replay finished with {} trace entries unused",
+                  self.ops.len() - self.pos);
+        }
+    }
+}
+
+#[cfg(test)]
+fn record_golden(pkt: ::fan::FanPkt) -> Vec<TraceOp> {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use pwm::PwmSymbols;
+    use regrw::FakeRegs;
+    use rfm::Rfm22;
+    use rfmconfig::Rfm22Config;
+
+    struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let buf = Rc::new(RefCell::new(Vec::new()));
+    {
+        let recorder = TraceRecorder::new(FakeRegs::new(), SharedBuf(buf.clone()));
+        let mut rf = Rfm22::dummy_regrw(Box::new(recorder));
+        rf.configure(Rfm22Config::default()).unwrap();
+        pkt.transmit(&mut rf, &PwmSymbols::FAN, pkt.repeats()).unwrap();
+    }
+    let buf = buf.borrow();
+    read_trace(&buf[..]).unwrap().into_iter().map(|e| e.op).collect()
+}
+
+/// The golden traces are recorded with the dummy backend, e.g.
+/// `fanrf --trace testdata/dumb_light.jsonl -a 5 dumb light`
+#[test]
+fn golden_traces() {
+    use fan::{FanCmd12, FanPkt, FanPkt12, FanPkt21, FanState21};
+
+    let golden = |trace: &str| -> Vec<TraceOp> {
+        read_trace(trace.as_bytes()).unwrap().into_iter().map(|e| e.op).collect()
+    };
+    assert_eq!(golden(include_str!("../testdata/dumb_light.jsonl")),
+               record_golden(FanPkt::Dumb(FanPkt12::new(5, FanCmd12::Light))));
+    assert_eq!(golden(include_str!("../testdata/smart_low_50.jsonl")),
+               record_golden(FanPkt::Smart(FanPkt21::new(5, 0.5, FanState21::Low))));
+}
+
+#[test]
+fn replay_divergence() {
+    let mut replay = TraceReplay::new(vec![TraceOp::Read { reg: 0x6d, val: 0x03 },
+                                           TraceOp::Write { reg: 0x6d, val: 0x07 }]);
+    assert_eq!(0x03, replay.read(0x6d).unwrap());
+    assert!(replay.write(0x6d, 0x06).is_err());
+    replay.write(0x6d, 0x07).unwrap();
+    assert!(replay.read(0x6d).is_err());
+}